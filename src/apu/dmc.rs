@@ -0,0 +1,196 @@
+/**
+* Delta modulation channel ($4010-$4013)
+*
+*   $4010 IL-- RRRR: IRQ enable, loop, rate index
+*   $4011 -DDD DDDD: output level, loaded directly
+*   $4012 AAAA AAAA: sample address, $C000 + A * 64
+*   $4013 LLLL LLLL: sample length, L * 16 + 1 bytes
+* A 1-bit delta stream read from $8000-$FFFF moves the 7-bit output level up or down by 2 at
+* one of 16 rates. The reader fetches the next byte whenever the one-byte buffer is empty; the
+* APU asks for it with fetch_address and hands it over with fill. Reads past $FFFF wrap to
+* $8000. The CPU cycles the fetches steal aren't emulated.
+*/
+use crate::region::Region;
+
+// Timer periods in CPU cycles
+const NTSC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const PAL_RATES: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+#[derive(Clone, Copy)]
+pub struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    rate_index: u8,
+    timer: u16,
+    level: u8,
+
+    sample_address: u16,
+    sample_length: u16,
+    address: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+
+    pub irq: bool,
+}
+
+impl Dmc {
+    pub fn new() -> Dmc {
+        Dmc {
+            irq_enabled: false,
+            looping: false,
+            rate_index: 0,
+            timer: 0,
+            level: 0,
+
+            sample_address: 0xC000,
+            sample_length: 1,
+            address: 0xC000,
+            bytes_remaining: 0,
+            buffer: None,
+
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+
+            irq: false,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register & 0x03 {
+            0 => {
+                self.irq_enabled = value & 0x80 != 0;
+                self.looping = value & 0x40 != 0;
+                self.rate_index = value & 0x0F;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            1 => self.level = value & 0x7F,
+            2 => self.sample_address = 0xC000 | (value as u16) << 6,
+            _ => self.sample_length = (value as u16) << 4 | 1,
+        }
+    }
+
+    // $4015 bit 4: starts the sample if it isn't already playing, or stops it
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    // Where the next sample byte should be read from, if the buffer needs one
+    pub fn fetch_address(&self) -> Option<u16> {
+        if self.buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.address)
+        } else {
+            None
+        }
+    }
+
+    pub fn fill(&mut self, value: u8) {
+        self.buffer = Some(value);
+        self.address = self.address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    pub fn clock_timer(&mut self, region: Region) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        let rates = match region {
+            Region::Pal => &PAL_RATES,
+            _ => &NTSC_RATES,
+        };
+        self.timer = rates[self.rate_index as usize] - 1;
+
+        if !self.silence {
+            if self.shift & 0x01 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(value) => {
+                    self.shift = value;
+                    self.silence = false;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // 0-127
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plays_a_sample_and_raises_its_irq() {
+        let mut dmc = Dmc::new();
+        dmc.write(0, 0x8F); // IRQ, fastest rate
+        dmc.write(1, 0x40);
+        dmc.write(2, 0xFF); // $FFC0
+        dmc.write(3, 0x04); // 65 bytes, past $FFFF
+        dmc.set_enabled(true);
+
+        let mut addresses = vec![];
+        while let Some(address) = dmc.fetch_address() {
+            addresses.push(address);
+            dmc.fill(0xFF);
+            // A byte lasts 8 output steps
+            for _ in 0..8 * 54 {
+                dmc.clock_timer(Region::Ntsc);
+            }
+        }
+        assert_eq!(addresses.len(), 65);
+        assert_eq!(addresses[63..], [0xFFFF, 0x8000]);
+        assert!(dmc.irq);
+        assert!(!dmc.active());
+        // Only ever going up, it tops out at 127 or 126
+        assert!(dmc.output() >= 126);
+
+        dmc.set_enabled(false);
+        assert!(!dmc.irq);
+    }
+}
@@ -0,0 +1,332 @@
+/**
+* NES Audio Processing Unit (APU) module
+*
+* CPU-facing registers:
+*   $4000-$4003 pulse 1, $4004-$4007 pulse 2, $4008-$400B triangle, $400C-$400F noise,
+*   $4010-$4013 DMC
+*   $4015 write: channel enables (---D NT21); read: length counters active, DMC active, frame
+*         IRQ (cleared by the read) and DMC IRQ (IF-D NT21)
+*   $4017 write: frame counter mode (M, 0 = 4 steps, 1 = 5 steps) and IRQ inhibit (I)
+* The APU runs on the CPU clock. The frame counter clocks the envelopes and the triangle's
+* linear counter every quarter frame, the length counters and sweeps every half frame, and in
+* 4-step mode raises an IRQ at the end of each sequence. The channels are mixed with the usual
+* approximation of the nonlinear DAC, the cartridge's expansion audio is added on top, and the
+* result is averaged down to SAMPLE_RATE with the DC offset filtered out for the frontend.
+*/
+mod dmc;
+mod noise;
+mod pulse;
+mod triangle;
+mod units;
+
+use crate::region::Region;
+use dmc::Dmc;
+use noise::Noise;
+pub use pulse::{Pulse, Sweep};
+use triangle::Triangle;
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+// Frame counter steps in CPU cycles; 4-step mode ends after the fourth, 5-step mode after the fifth
+const NTSC_FRAME_STEPS: [u32; 5] = [7457, 14913, 22371, 29829, 37281];
+const PAL_FRAME_STEPS: [u32; 5] = [8313, 16627, 24939, 33253, 41565];
+
+// Named like CPU and PPU
+#[allow(clippy::upper_case_acronyms)]
+pub struct APU {
+    pub region: Region,
+
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+
+    // Frame counter ($4017)
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: u32,
+
+    // Output, averaged over the CPU cycles in each sample
+    sample_sum: f32,
+    sample_cycles: u32,
+    sample_clock: f64,
+    last_input: f32,
+    last_output: f32,
+    samples: Vec<f32>,
+}
+
+impl APU {
+    pub fn new() -> APU {
+        APU {
+            region: Region::Ntsc,
+
+            pulse1: Pulse::new(Sweep::OnesComplement),
+            pulse2: Pulse::new(Sweep::TwosComplement),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+
+            sample_sum: 0.0,
+            sample_cycles: 0,
+            sample_clock: 0.0,
+            last_input: 0.0,
+            last_output: 0.0,
+            samples: Vec::with_capacity(SAMPLE_RATE as usize / 10),
+        }
+    }
+
+    pub fn read_register(&mut self, address: u16) -> u8 {
+        if address != 0x4015 {
+            return 0;
+        }
+        let status = self.pulse1.active() as u8
+            | (self.pulse2.active() as u8) << 1
+            | (self.triangle.active() as u8) << 2
+            | (self.noise.active() as u8) << 3
+            | (self.dmc.active() as u8) << 4
+            | (self.frame_irq as u8) << 6
+            | (self.dmc.irq as u8) << 7;
+        self.frame_irq = false;
+        status
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x4000..=0x4003 => self.pulse1.write(address, value),
+            0x4004..=0x4007 => self.pulse2.write(address, value),
+            0x4008..=0x400B => self.triangle.write(address, value),
+            0x400C..=0x400F => self.noise.write(address, value),
+            0x4010..=0x4013 => self.dmc.write(address, value),
+            0x4015 => {
+                self.pulse1.set_enabled(value & 0x01 != 0);
+                self.pulse2.set_enabled(value & 0x02 != 0);
+                self.triangle.set_enabled(value & 0x04 != 0);
+                self.noise.set_enabled(value & 0x08 != 0);
+                self.dmc.set_enabled(value & 0x10 != 0);
+            }
+            0x4017 => {
+                self.five_step = value & 0x80 != 0;
+                self.irq_inhibit = value & 0x40 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                // Switching to 5-step mode clocks everything straight away
+                self.frame_cycle = 0;
+                if self.five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // One CPU cycle. `expansion` is the cartridge's audio output at that cycle.
+    pub fn tick(&mut self, expansion: f32) {
+        self.pulse1.clock_timer();
+        self.pulse2.clock_timer();
+        self.triangle.clock_timer();
+        self.noise.clock_timer(self.region);
+        self.dmc.clock_timer(self.region);
+        self.clock_frame_counter();
+
+        self.sample_sum += self.mix() + expansion;
+        self.sample_cycles += 1;
+        self.sample_clock += SAMPLE_RATE as f64;
+        if self.sample_clock >= self.region.cpu_hz() {
+            self.sample_clock -= self.region.cpu_hz();
+            let input = self.sample_sum / self.sample_cycles as f32;
+            self.sample_sum = 0.0;
+            self.sample_cycles = 0;
+
+            // High-pass at about 40 Hz so silence sits at 0
+            let output = input - self.last_input + 0.994 * self.last_output;
+            self.last_input = input;
+            self.last_output = output;
+            // Nobody is draining them: keep a second's worth at most
+            if self.samples.len() < SAMPLE_RATE as usize {
+                self.samples.push(output);
+            }
+        }
+    }
+
+    fn clock_frame_counter(&mut self) {
+        let steps = match self.region {
+            Region::Pal => &PAL_FRAME_STEPS,
+            _ => &NTSC_FRAME_STEPS,
+        };
+        self.frame_cycle += 1;
+        let step = match steps.iter().position(|&cycle| cycle == self.frame_cycle) {
+            Some(step) => step,
+            None => return,
+        };
+
+        match (self.five_step, step) {
+            (_, 0) | (_, 2) => self.clock_quarter_frame(),
+            (_, 1) | (true, 4) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            (false, 3) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                if !self.irq_inhibit {
+                    self.frame_irq = true;
+                }
+            }
+            _ => {}
+        }
+        if step == 4 || (step == 3 && !self.five_step) {
+            self.frame_cycle = 0;
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.clock_quarter_frame();
+        self.pulse2.clock_quarter_frame();
+        self.triangle.clock_quarter_frame();
+        self.noise.clock_quarter_frame();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse1.clock_half_frame();
+        self.pulse2.clock_half_frame();
+        self.triangle.clock_half_frame();
+        self.noise.clock_half_frame();
+    }
+
+    fn mix(&self) -> f32 {
+        let pulse_out = pulse_level(self.pulse1.output() + self.pulse2.output());
+        let tnd = self.triangle.output() as f32 / 8227.0
+            + self.noise.output() as f32 / 12241.0
+            + self.dmc.output() as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+        pulse_out + tnd_out
+    }
+
+    // The DMC's next sample byte is read through the CPU bus by whoever runs the APU
+    pub fn dmc_fetch_address(&self) -> Option<u16> {
+        self.dmc.fetch_address()
+    }
+
+    pub fn dmc_fill(&mut self, value: u8) {
+        self.dmc.fill(value);
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    // Samples made since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+}
+
+// Output of the pulse DAC for the sum of two pulse levels (0-30), 0 to about 0.26
+pub fn pulse_level(sum: u8) -> f32 {
+    if sum == 0 {
+        return 0.0;
+    }
+    95.88 / (8128.0 / sum as f32 + 100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(apu: &mut APU, cycles: u32) {
+        for _ in 0..cycles {
+            apu.tick(0.0);
+        }
+    }
+
+    #[test]
+    fn status_reports_length_counters() {
+        let mut apu = APU::new();
+        apu.write_register(0x4003, 0x08);
+        assert_eq!(apu.read_register(0x4015), 0x00);
+
+        apu.write_register(0x4015, 0x0F);
+        for address in [0x4003, 0x4007, 0x400B, 0x400F] {
+            apu.write_register(address, 0x18); // 2 half frames
+        }
+        assert_eq!(apu.read_register(0x4015), 0x0F);
+
+        // Half frames land on the second and fourth steps
+        run(&mut apu, NTSC_FRAME_STEPS[3]);
+        assert_eq!(apu.read_register(0x4015) & 0x0F, 0x00);
+
+        apu.write_register(0x4015, 0x10);
+        assert_eq!(apu.read_register(0x4015) & 0x10, 0x10);
+    }
+
+    #[test]
+    fn frame_irq_in_4_step_mode_only() {
+        let mut apu = APU::new();
+        run(&mut apu, NTSC_FRAME_STEPS[3] - 1);
+        assert!(!apu.irq_pending());
+        run(&mut apu, 1);
+        assert!(apu.irq_pending());
+        // Reading $4015 acknowledges it
+        assert_eq!(apu.read_register(0x4015) & 0x40, 0x40);
+        assert!(!apu.irq_pending());
+
+        apu.write_register(0x4017, 0x40);
+        run(&mut apu, NTSC_FRAME_STEPS[4] * 2);
+        assert!(!apu.irq_pending());
+
+        apu.write_register(0x4017, 0x80);
+        run(&mut apu, NTSC_FRAME_STEPS[4] * 2);
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn dmc_irq_after_its_last_byte() {
+        let mut apu = APU::new();
+        apu.write_register(0x4010, 0x80);
+        apu.write_register(0x4012, 0x00);
+        apu.write_register(0x4013, 0x00);
+        apu.write_register(0x4015, 0x10);
+        assert_eq!(apu.dmc_fetch_address(), Some(0xC000));
+        apu.dmc_fill(0x00);
+        assert_eq!(apu.dmc_fetch_address(), None);
+        assert!(apu.irq_pending());
+        assert_eq!(apu.read_register(0x4015) & 0x90, 0x80);
+    }
+
+    #[test]
+    fn samples_at_the_output_rate() {
+        let mut apu = APU::new();
+        apu.write_register(0x4015, 0x01);
+        apu.write_register(0x4000, 0xBF);
+        apu.write_register(0x4002, 0xFD); // about 440 Hz
+        apu.write_register(0x4003, 0x00);
+        run(&mut apu, Region::Ntsc.cpu_hz() as u32 / 10);
+
+        let samples = apu.take_samples();
+        assert!((samples.len() as i32 - SAMPLE_RATE as i32 / 10).abs() <= 1);
+        assert!(samples.iter().any(|&sample| sample > 0.05));
+        assert!(samples.iter().any(|&sample| sample < -0.05));
+        assert!(apu.take_samples().is_empty());
+    }
+
+    #[test]
+    fn mixer_levels() {
+        assert_eq!(pulse_level(0), 0.0);
+        assert!((pulse_level(30) - 0.2584).abs() < 0.001);
+        // The triangle holds its first level, 15, from power on
+        assert!((APU::new().mix() - 159.79 / (8227.0 / 15.0 + 100.0)).abs() < 0.0001);
+    }
+}
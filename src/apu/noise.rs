@@ -0,0 +1,126 @@
+/**
+* Noise channel ($400C-$400F)
+*
+*   $400C --LC VVVV: length counter halt/envelope loop, constant volume, volume
+*   $400E M--- PPPP: mode, period index
+*   $400F LLLL L---: length counter load; restarts the envelope
+* A 15-bit linear feedback shift register is clocked at one of 16 rates. Feedback comes from
+* bits 0 and 1, or bits 0 and 6 in mode 1, which makes a short metallic loop. The channel is
+* silent while bit 0 is set.
+*/
+use super::units::{Envelope, LengthCounter};
+use crate::region::Region;
+
+// Timer periods in CPU cycles
+const NTSC_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const PAL_PERIODS: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+#[derive(Clone, Copy)]
+pub struct Noise {
+    mode: bool,
+    period_index: u8,
+    timer: u16,
+    shift: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+}
+
+impl Noise {
+    pub fn new() -> Noise {
+        Noise {
+            mode: false,
+            period_index: 0,
+            timer: 0,
+            shift: 1,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+        }
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register & 0x03 {
+            0 => {
+                self.envelope.write(value);
+                self.length.set_halt(value & 0x20 != 0);
+            }
+            2 => {
+                self.mode = value & 0x80 != 0;
+                self.period_index = value & 0x0F;
+            }
+            3 => {
+                self.length.load(value);
+                self.envelope.restart();
+            }
+            _ => {}
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    pub fn active(&self) -> bool {
+        self.length.active()
+    }
+
+    pub fn clock_timer(&mut self, region: Region) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        let periods = match region {
+            Region::Pal => &PAL_PERIODS,
+            _ => &NTSC_PERIODS,
+        };
+        self.timer = periods[self.period_index as usize] - 1;
+
+        let tap = if self.mode { 6 } else { 1 };
+        let feedback = (self.shift ^ (self.shift >> tap)) & 0x01;
+        self.shift = (self.shift >> 1) | feedback << 14;
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.active() || self.shift & 0x01 != 0 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shifts(mode: u8) -> usize {
+        let mut noise = Noise::new();
+        noise.write(2, mode);
+        let start = noise.shift;
+        // Period index 0 clocks the register every 4 CPU cycles
+        (1..=0x8000)
+            .find(|_| {
+                for _ in 0..4 {
+                    noise.clock_timer(Region::Ntsc);
+                }
+                noise.shift == start
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn sequence_lengths() {
+        assert_eq!(shifts(0x00), 32767);
+        assert_eq!(shifts(0x80), 93);
+    }
+}
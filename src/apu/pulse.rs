@@ -0,0 +1,223 @@
+/**
+* Pulse channel ($4000-$4003 and $4004-$4007)
+*
+*   $4000 DDLC VVVV: duty, length counter halt/envelope loop, constant volume, volume
+*   $4001 EPPP NSSS: sweep enable, period, negate, shift
+*   $4002 LLLL LLLL: timer low
+*   $4003 LLLL LHHH: length counter load, timer high; restarts the envelope and the duty cycle
+* The 11-bit timer runs at half the CPU clock and steps through an 8-step duty sequence. The
+* sweep unit bends the period every few half frames and mutes the channel when the period is
+* below 8 or the target would overflow 11 bits, even when the sweep is disabled. The two pulses
+* differ only in how they negate: pulse 1 subtracts one more (ones' complement).
+* The MMC5 has two of these without the sweep unit.
+*/
+use super::units::{Envelope, LengthCounter};
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Sweep {
+    // Pulse 1
+    OnesComplement,
+    // Pulse 2
+    TwosComplement,
+    // MMC5
+    None,
+}
+
+#[derive(Clone, Copy)]
+pub struct Pulse {
+    sweep: Sweep,
+    duty: u8,
+    step: u8,
+    timer_period: u16,
+    timer: u16,
+    odd_cycle: bool,
+    envelope: Envelope,
+    length: LengthCounter,
+
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    pub fn new(sweep: Sweep) -> Pulse {
+        Pulse {
+            sweep,
+            duty: 0,
+            step: 0,
+            timer_period: 0,
+            timer: 0,
+            odd_cycle: false,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+        }
+    }
+
+    // `register` is the address's low two bits
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register & 0x03 {
+            0 => {
+                self.duty = value >> 6;
+                self.envelope.write(value);
+                self.length.set_halt(value & 0x20 != 0);
+            }
+            1 => {
+                self.sweep_enabled = value & 0x80 != 0;
+                self.sweep_period = (value >> 4) & 0x07;
+                self.sweep_negate = value & 0x08 != 0;
+                self.sweep_shift = value & 0x07;
+                self.sweep_reload = true;
+            }
+            2 => self.timer_period = (self.timer_period & 0x700) | value as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0xFF) | (value as u16 & 0x07) << 8;
+                self.length.load(value);
+                self.step = 0;
+                self.envelope.restart();
+            }
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    pub fn active(&self) -> bool {
+        self.length.active()
+    }
+
+    // Called every CPU cycle
+    pub fn clock_timer(&mut self) {
+        self.odd_cycle = !self.odd_cycle;
+        if !self.odd_cycle {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.step = (self.step + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+        if self.sweep == Sweep::None {
+            return;
+        }
+
+        if self.sweep_divider == 0
+            && self.sweep_enabled
+            && self.sweep_shift > 0
+            && !self.sweep_muted()
+        {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if !self.sweep_negate {
+            return self.timer_period + change;
+        }
+        match self.sweep {
+            Sweep::OnesComplement => self.timer_period.saturating_sub(change + 1),
+            _ => self.timer_period.saturating_sub(change),
+        }
+    }
+
+    fn sweep_muted(&self) -> bool {
+        self.sweep != Sweep::None && (self.timer_period < 8 || self.sweep_target() > 0x7FF)
+    }
+
+    // 0-15
+    pub fn output(&self) -> u8 {
+        if !self.length.active()
+            || self.sweep_muted()
+            || DUTY_TABLE[self.duty as usize][self.step as usize] == 0
+        {
+            return 0;
+        }
+        self.envelope.output()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playing(sweep: Sweep, period: u16) -> Pulse {
+        let mut pulse = Pulse::new(sweep);
+        pulse.set_enabled(true);
+        pulse.write(0, 0xFF); // 75% duty, halted, constant volume 15
+        pulse.write(2, period as u8);
+        pulse.write(3, (period >> 8) as u8);
+        pulse
+    }
+
+    #[test]
+    fn steps_through_the_duty_cycle() {
+        let mut pulse = playing(Sweep::TwosComplement, 8);
+        let mut levels = vec![];
+        for _ in 0..8 {
+            levels.push(pulse.output());
+            // A step every period + 1 APU cycles, two CPU cycles each
+            for _ in 0..18 {
+                pulse.clock_timer();
+            }
+        }
+        assert_eq!(levels, [15, 0, 0, 15, 15, 15, 15, 15]);
+    }
+
+    #[test]
+    fn short_periods_are_muted_except_on_the_mmc5() {
+        let pulse = playing(Sweep::TwosComplement, 7);
+        assert_eq!(pulse.output(), 0);
+        let pulse = playing(Sweep::None, 7);
+        assert_eq!(pulse.output(), 15);
+    }
+
+    #[test]
+    fn sweep_negates_differently_per_channel() {
+        for (sweep, period) in [
+            (Sweep::OnesComplement, 0xFF),
+            (Sweep::TwosComplement, 0x100),
+        ] {
+            let mut pulse = playing(sweep, 0x200);
+            pulse.write(1, 0x89); // enabled, period 0, negate, shift 1
+            pulse.clock_half_frame();
+            assert_eq!(pulse.timer_period, period);
+        }
+
+        let mut pulse = playing(Sweep::TwosComplement, 0x600);
+        pulse.write(1, 0x81); // adding half again overflows 11 bits
+        assert_eq!(pulse.output(), 0);
+    }
+}
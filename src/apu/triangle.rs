@@ -0,0 +1,129 @@
+/**
+* Triangle channel ($4008-$400B)
+*
+*   $4008 CRRR RRRR: length counter halt/linear counter control, linear counter reload value
+*   $400A LLLL LLLL: timer low
+*   $400B LLLL LHHH: length counter load, timer high; sets the linear counter reload flag
+* The timer runs at the CPU clock and steps through a 32-step sequence (15 down to 0 and back
+* up) while both the linear and the length counter are non-zero; otherwise the output holds its
+* last level. Periods below 2 would sound above 20 kHz and are held too, to avoid popping.
+*/
+use super::units::LengthCounter;
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+#[derive(Clone, Copy)]
+pub struct Triangle {
+    control: bool,
+    linear_reload_value: u8,
+    linear_reload: bool,
+    linear_counter: u8,
+    timer_period: u16,
+    timer: u16,
+    step: u8,
+    length: LengthCounter,
+}
+
+impl Triangle {
+    pub fn new() -> Triangle {
+        Triangle {
+            control: false,
+            linear_reload_value: 0,
+            linear_reload: false,
+            linear_counter: 0,
+            timer_period: 0,
+            timer: 0,
+            step: 0,
+            length: LengthCounter::new(),
+        }
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register & 0x03 {
+            0 => {
+                self.control = value & 0x80 != 0;
+                self.linear_reload_value = value & 0x7F;
+                self.length.set_halt(self.control);
+            }
+            2 => self.timer_period = (self.timer_period & 0x700) | value as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0xFF) | (value as u16 & 0x07) << 8;
+                self.length.load(value);
+                self.linear_reload = true;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    pub fn active(&self) -> bool {
+        self.length.active()
+    }
+
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.linear_counter > 0 && self.length.active() && self.timer_period >= 2 {
+                self.step = (self.step + 1) & 0x1F;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.step as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_only_while_both_counters_run() {
+        let mut triangle = Triangle::new();
+        triangle.set_enabled(true);
+        triangle.write(0, 0x01); // linear counter of 1
+        triangle.write(2, 0x02);
+        triangle.write(3, 0x08);
+        for _ in 0..3 {
+            triangle.clock_timer();
+        }
+        assert_eq!(triangle.output(), 15);
+
+        triangle.clock_quarter_frame();
+        for _ in 0..3 {
+            triangle.clock_timer();
+        }
+        assert_eq!(triangle.output(), 14);
+
+        // The linear counter runs out and the level holds
+        triangle.clock_quarter_frame();
+        for _ in 0..9 {
+            triangle.clock_timer();
+        }
+        assert_eq!(triangle.output(), 14);
+    }
+}
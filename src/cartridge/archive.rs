@@ -0,0 +1,319 @@
+/**
+* Zip and gzip archive support
+*
+* Zip: the central directory at the end of the file lists every entry with its compression
+* method, CRC-32, sizes and the offset of its local header, after which the data starts.
+* Stored and deflated entries are supported. Without a name, the first entry with a ROM
+* extension is picked.
+* Gzip: a single deflated file; the decoder checks its CRC-32.
+*/
+use std::io::Read;
+use std::path::Path;
+
+use flate2::read::{DeflateDecoder, MultiGzDecoder};
+
+use super::crc32::crc32;
+use super::RomError;
+
+const ROM_EXTENSIONS: &[&str] = &["nes", "unf", "unif", "fds", "nsf"];
+
+const LOCAL_HEADER: u32 = 0x04034B50;
+const CENTRAL_HEADER: u32 = 0x02014B50;
+const END_OF_DIRECTORY: u32 = 0x06054B50;
+
+pub fn is_zip(file: &[u8]) -> bool {
+    file.starts_with(b"PK\x03\x04") || file.starts_with(b"PK\x05\x06")
+}
+
+pub fn is_gzip(file: &[u8]) -> bool {
+    file.starts_with(&[0x1F, 0x8B])
+}
+
+// The ROM inside `file` if it's an archive, or None if it isn't one
+pub fn unpack(file: &[u8], entry: Option<&str>) -> Result<Option<Vec<u8>>, RomError> {
+    if is_zip(file) {
+        return unzip(file, entry).map(Some);
+    }
+    if is_gzip(file) {
+        let mut rom = vec![];
+        MultiGzDecoder::new(file)
+            .read_to_end(&mut rom)
+            .map_err(|e| RomError::BadArchive(e.to_string()))?;
+        return Ok(Some(rom));
+    }
+    Ok(None)
+}
+
+struct ZipEntry {
+    name: String,
+    method: u16,
+    flags: u16,
+    crc: u32,
+    compressed_size: usize,
+    size: usize,
+    offset: usize,
+}
+
+fn unzip(file: &[u8], wanted: Option<&str>) -> Result<Vec<u8>, RomError> {
+    let entries = central_directory(file)?;
+    let entry = match wanted {
+        Some(wanted) => entries
+            .iter()
+            .find(|entry| entry.name == wanted || file_name(&entry.name) == wanted)
+            .ok_or_else(|| RomError::MissingArchiveEntry(wanted.to_string()))?,
+        None => entries
+            .iter()
+            .find(|entry| is_rom_name(&entry.name))
+            .ok_or(RomError::NoRomInArchive)?,
+    };
+
+    if entry.flags & 0x0001 != 0 {
+        return Err(RomError::BadArchive(format!("{} is encrypted", entry.name)));
+    }
+    if u32_at(file, entry.offset) != Some(LOCAL_HEADER) {
+        return Err(RomError::BadArchive(format!(
+            "bad local header for {}",
+            entry.name
+        )));
+    }
+    let name_length = u16_at(file, entry.offset + 26).unwrap_or(0) as usize;
+    let extra_length = u16_at(file, entry.offset + 28).unwrap_or(0) as usize;
+    let start = entry.offset + 30 + name_length + extra_length;
+    let data = file
+        .get(start..start.saturating_add(entry.compressed_size))
+        .ok_or_else(|| RomError::BadArchive(format!("{} is truncated", entry.name)))?;
+
+    let rom = match entry.method {
+        0 => data.to_vec(),
+        8 => {
+            // The recorded size can't be trusted, so inflate at most one byte past it
+            let mut rom = Vec::new();
+            DeflateDecoder::new(data)
+                .take(entry.size as u64 + 1)
+                .read_to_end(&mut rom)
+                .map_err(|e| RomError::BadArchive(format!("{}: {}", entry.name, e)))?;
+            if rom.len() != entry.size {
+                return Err(RomError::BadArchive(format!(
+                    "{} inflates to {} bytes, not {}",
+                    entry.name,
+                    rom.len(),
+                    entry.size
+                )));
+            }
+            rom
+        }
+        method => {
+            return Err(RomError::BadArchive(format!(
+                "{} uses unsupported compression method {}",
+                entry.name, method
+            )))
+        }
+    };
+
+    if rom.len() != entry.size || crc32(&rom) != entry.crc {
+        return Err(RomError::BadArchive(format!("{} is corrupt", entry.name)));
+    }
+    Ok(rom)
+}
+
+fn central_directory(file: &[u8]) -> Result<Vec<ZipEntry>, RomError> {
+    // The end record is 22 bytes plus a comment of up to 64K, so search backwards for it
+    let end = (0..=file.len().saturating_sub(22))
+        .rev()
+        .take(0x10000 + 22)
+        .find(|&position| u32_at(file, position) == Some(END_OF_DIRECTORY))
+        .ok_or_else(|| RomError::BadArchive("no zip directory".to_string()))?;
+    let count = u16_at(file, end + 10).unwrap_or(0) as usize;
+    let mut position = u32_at(file, end + 16).unwrap_or(0) as usize;
+
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        let truncated = || RomError::BadArchive("zip directory is truncated".to_string());
+        if u32_at(file, position) != Some(CENTRAL_HEADER) {
+            return Err(truncated());
+        }
+        let name_length = u16_at(file, position + 28).ok_or_else(truncated)? as usize;
+        let extra_length = u16_at(file, position + 30).ok_or_else(truncated)? as usize;
+        let comment_length = u16_at(file, position + 32).ok_or_else(truncated)? as usize;
+        let name = file
+            .get(position + 46..position + 46 + name_length)
+            .ok_or_else(truncated)?;
+
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(name).to_string(),
+            flags: u16_at(file, position + 8).ok_or_else(truncated)?,
+            method: u16_at(file, position + 10).ok_or_else(truncated)?,
+            crc: u32_at(file, position + 16).ok_or_else(truncated)?,
+            compressed_size: u32_at(file, position + 20).ok_or_else(truncated)? as usize,
+            size: u32_at(file, position + 24).ok_or_else(truncated)? as usize,
+            offset: u32_at(file, position + 42).ok_or_else(truncated)? as usize,
+        });
+        position += 46 + name_length + extra_length + comment_length;
+    }
+    Ok(entries)
+}
+
+fn is_rom_name(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            ROM_EXTENSIONS
+                .iter()
+                .any(|rom| rom.eq_ignore_ascii_case(extension))
+        })
+}
+
+// Entries are stored with '/' separated paths
+fn file_name(name: &str) -> &str {
+    name.rsplit('/').next().unwrap_or(name)
+}
+
+fn u16_at(file: &[u8], position: usize) -> Option<u16> {
+    let bytes = file.get(position..position + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(file: &[u8], position: usize) -> Option<u32> {
+    let bytes = file.get(position..position + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{DeflateEncoder, GzEncoder};
+    use flate2::Compression;
+    use std::io::Write;
+
+    // (name, contents, deflate)
+    fn zip(files: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut archive = vec![];
+        let mut directory = vec![];
+        for &(name, contents, deflate) in files {
+            let data = if deflate {
+                let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+                encoder.write_all(contents).unwrap();
+                encoder.finish().unwrap()
+            } else {
+                contents.to_vec()
+            };
+            let method: u16 = if deflate { 8 } else { 0 };
+            let offset = archive.len() as u32;
+
+            let mut fields = vec![];
+            fields.extend_from_slice(&20u16.to_le_bytes());
+            fields.extend_from_slice(&0u16.to_le_bytes());
+            fields.extend_from_slice(&method.to_le_bytes());
+            fields.extend_from_slice(&[0; 4]);
+            fields.extend_from_slice(&crc32(contents).to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            fields.extend_from_slice(&0u16.to_le_bytes());
+
+            archive.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+            archive.extend_from_slice(&fields);
+            archive.extend_from_slice(name.as_bytes());
+            archive.extend_from_slice(&data);
+
+            directory.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+            directory.extend_from_slice(&20u16.to_le_bytes());
+            directory.extend_from_slice(&fields);
+            // Comment length, disk number and attributes
+            directory.extend_from_slice(&[0; 10]);
+            directory.extend_from_slice(&offset.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+        }
+
+        let directory_offset = archive.len() as u32;
+        archive.extend_from_slice(&directory);
+        archive.extend_from_slice(&END_OF_DIRECTORY.to_le_bytes());
+        archive.extend_from_slice(&[0; 4]);
+        archive.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        archive.extend_from_slice(&directory_offset.to_le_bytes());
+        archive.extend_from_slice(&0u16.to_le_bytes());
+        archive
+    }
+
+    #[test]
+    fn picks_first_rom_entry() {
+        let archive = zip(&[
+            ("readme.txt", b"hello", false),
+            ("roms/Game (U).NES", &[0xEA; 100], true),
+            ("other.nes", b"second", false),
+        ]);
+        assert_eq!(unpack(&archive, None).unwrap(), Some(vec![0xEA; 100]));
+    }
+
+    #[test]
+    fn picks_named_entry() {
+        let archive = zip(&[("a.nes", b"first", true), ("sub/b.nes", b"second", false)]);
+        assert_eq!(
+            unpack(&archive, Some("b.nes")).unwrap(),
+            Some(b"second".to_vec())
+        );
+        assert_eq!(
+            unpack(&archive, Some("sub/b.nes")).unwrap(),
+            Some(b"second".to_vec())
+        );
+        assert!(matches!(
+            unpack(&archive, Some("c.nes")),
+            Err(RomError::MissingArchiveEntry(name)) if name == "c.nes"
+        ));
+    }
+
+    #[test]
+    fn reports_bad_archives() {
+        let archive = zip(&[("readme.txt", b"hello", false)]);
+        assert!(matches!(
+            unpack(&archive, None),
+            Err(RomError::NoRomInArchive)
+        ));
+
+        let mut archive = zip(&[("game.nes", b"contents", false)]);
+        archive[30 + 8] ^= 0xFF;
+        assert!(matches!(
+            unpack(&archive, None),
+            Err(RomError::BadArchive(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_wrong_inflated_size() {
+        let contents = [0xEA; 0x1000];
+        let mut archive = zip(&[("game.nes", &contents, true)]);
+        // Claim 4G in the central directory entry
+        let directory = u32_at(&archive, archive.len() - 6).unwrap() as usize;
+        archive[directory + 24..directory + 28].copy_from_slice(&[0xFF; 4]);
+        assert!(matches!(
+            unpack(&archive, None),
+            Err(RomError::BadArchive(message)) if message.contains("inflates to 4096 bytes")
+        ));
+
+        let mut archive = zip(&[("game.nes", &contents, true)]);
+        archive[directory + 24..directory + 28].copy_from_slice(&16u32.to_le_bytes());
+        assert!(matches!(
+            unpack(&archive, None),
+            Err(RomError::BadArchive(message)) if message.contains("inflates to 17 bytes")
+        ));
+    }
+
+    #[test]
+    fn gunzips() {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(&[0x4E, 0x45, 0x53, 0x1A]).unwrap();
+        let archive = encoder.finish().unwrap();
+        assert_eq!(
+            unpack(&archive, None).unwrap(),
+            Some(vec![0x4E, 0x45, 0x53, 0x1A])
+        );
+    }
+
+    #[test]
+    fn leaves_other_files_alone() {
+        assert_eq!(unpack(b"NES\x1A", None).unwrap(), None);
+    }
+}
@@ -0,0 +1,127 @@
+/**
+* Battery-backed PRG RAM, persisted to a .sav file
+*
+* The save holds the mapper's whole $6000-$7FFF work RAM, or for Famicom Disk System games the
+* disk sides as a .fds image without its header. It's written through a temporary file and a
+* rename so a crash mid-write never leaves a half written save behind, and only when the RAM
+* changed since the last write.
+*/
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub struct Battery {
+    pub path: PathBuf,
+    saved: Vec<u8>,
+}
+
+impl Battery {
+    // `<saves_dir or the ROM's directory>/<ROM name>.sav`
+    pub fn save_path(rom_path: &Path, saves_dir: Option<&Path>) -> PathBuf {
+        let name = rom_path.with_extension("sav");
+        match saves_dir {
+            Some(dir) => dir.join(name.file_name().unwrap_or_default()),
+            None => name,
+        }
+    }
+
+    // Fills `ram` from the save at `path` if there is one
+    pub fn load(path: PathBuf, ram: &mut [u8]) -> io::Result<Battery> {
+        match fs::read(&path) {
+            Ok(data) => {
+                let len = data.len().min(ram.len());
+                ram[..len].copy_from_slice(&data[..len]);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        Ok(Battery {
+            path,
+            saved: ram.to_vec(),
+        })
+    }
+
+    // Writes `ram` out if it changed since the last save
+    pub fn save(&mut self, ram: &[u8]) -> io::Result<()> {
+        if ram == self.saved.as_slice() {
+            return Ok(());
+        }
+
+        if let Some(dir) = self.path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+        let temp = self.path.with_extension("sav.tmp");
+        fs::write(&temp, ram)?;
+        fs::rename(&temp, &self.path)?;
+
+        self.saved = ram.to_vec();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("nust-battery-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn save_path_next_to_rom_or_in_saves_dir() {
+        let rom = Path::new("/games/Zelda (U).nes");
+        assert_eq!(
+            Battery::save_path(rom, None),
+            PathBuf::from("/games/Zelda (U).sav")
+        );
+        assert_eq!(
+            Battery::save_path(rom, Some(Path::new("/saves"))),
+            PathBuf::from("/saves/Zelda (U).sav")
+        );
+    }
+
+    #[test]
+    fn round_trips_through_file() {
+        let dir = temp_dir("round-trip");
+        let path = dir.join("game.sav");
+
+        let mut ram = vec![0; 0x2000];
+        let mut battery = Battery::load(path.clone(), &mut ram).unwrap();
+        assert!(!path.exists());
+
+        // Unchanged RAM isn't written
+        battery.save(&ram).unwrap();
+        assert!(!path.exists());
+
+        ram[0x123] = 0x45;
+        battery.save(&ram).unwrap();
+        assert_eq!(fs::read(&path).unwrap()[0x123], 0x45);
+        assert!(!path.with_extension("sav.tmp").exists());
+
+        let mut loaded = vec![0; 0x2000];
+        Battery::load(path, &mut loaded).unwrap();
+        assert_eq!(loaded, ram);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn short_save_fills_start_of_ram() {
+        let dir = temp_dir("short");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("game.sav");
+        fs::write(&path, [1, 2, 3]).unwrap();
+
+        let mut ram = vec![0xFF; 8];
+        Battery::load(path, &mut ram).unwrap();
+        assert_eq!(ram, [1, 2, 3, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+/**
+* Game database for correcting bad iNES headers
+*
+* Plenty of dumps in circulation carry a wrong mapper number, mirroring bit or battery flag.
+* database.txt lists known games by the CRC-32 or SHA-1 of their PRG and CHR ROM along with the
+* header fields they should have; matching entries override what an iNES 1.0 header says (NES
+* 2.0 headers exist to settle exactly these questions, so they're trusted). A database file
+* in the same format can be loaded on top of the built-in one (--db), e.g. one converted from
+* NesCartDB.
+*/
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use super::crc32::Crc32;
+use super::mappers::Mirroring;
+use super::sha1::{self, Sha1};
+use super::{CartridgeHeader, TIMING_DENDY, TIMING_MULTI_REGION, TIMING_NTSC, TIMING_PAL};
+
+const DATABASE: &str = include_str!("database.txt");
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GameEntry {
+    pub name: String,
+    pub mapper: Option<u16>,
+    pub submapper: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    // (PRG RAM, PRG NVRAM, CHR RAM) in bytes
+    pub ram: Option<(usize, usize, usize)>,
+    pub timing: Option<u8>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Key {
+    Crc32(u32),
+    Sha1([u8; 20]),
+}
+
+pub struct Database {
+    entries: HashMap<Key, GameEntry>,
+}
+
+impl Database {
+    // The database built into the binary
+    pub fn embedded() -> &'static Database {
+        static EMBEDDED: OnceLock<Database> = OnceLock::new();
+        EMBEDDED.get_or_init(|| {
+            Database::parse(DATABASE).unwrap_or_else(|e| panic!("database.txt: {}", e))
+        })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Database, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Database::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> Result<Database, String> {
+        let mut entries = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, entry) =
+                parse_line(line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+            entries.insert(key, entry);
+        }
+        Ok(Database { entries })
+    }
+
+    // The matching entry and the hash it was found by, in hex. SHA-1 entries win over CRC-32 ones.
+    pub fn find(&self, prg_rom: &[u8], chr_rom: &[u8]) -> Option<(String, &GameEntry)> {
+        let mut sha1 = Sha1::new();
+        sha1.update(prg_rom);
+        sha1.update(chr_rom);
+        let sha1 = sha1.finish();
+        if let Some(entry) = self.entries.get(&Key::Sha1(sha1)) {
+            return Some((sha1::to_hex(&sha1), entry));
+        }
+
+        let mut crc = Crc32::new();
+        crc.update(prg_rom);
+        crc.update(chr_rom);
+        let crc = crc.finish();
+        self.entries
+            .get(&Key::Crc32(crc))
+            .map(|entry| (format!("{:08X}", crc), entry))
+    }
+}
+
+impl GameEntry {
+    pub fn apply(&self, header: &mut CartridgeHeader) {
+        if let Some(mapper) = self.mapper {
+            header.mapper = mapper;
+        }
+        if let Some(submapper) = self.submapper {
+            header.submapper = submapper;
+        }
+        if let Some(mirroring) = self.mirroring {
+            header.flags &= !0x0900;
+            header.flags |= match mirroring {
+                Mirroring::FourScreen => 0x0800,
+                Mirroring::Vertical => 0x0100,
+                _ => 0x0000,
+            };
+        }
+        if let Some((prg_ram, prg_nvram, chr_ram)) = self.ram {
+            // Only called on iNES 1.0 headers, which have no field for RAM sizes; upgrade it to
+            // NES 2.0 to hold them
+            header.nes2 = true;
+            header.prg_ram_size = size_to_shift(prg_ram);
+            header.prg_nvram_size = size_to_shift(prg_nvram);
+            header.chr_ram_size = size_to_shift(chr_ram);
+            header.chr_nvram_size = 0;
+        }
+        if let Some(timing) = self.timing {
+            header.cpu_ppu_timing = timing;
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Result<(Key, GameEntry), String> {
+    let mut rest = line;
+    let mut next = |name: &str| column(&mut rest).ok_or(format!("missing {}", name));
+
+    let key = parse_key(next("hash")?)?;
+    let mapper = optional(next("mapper")?, |c| c.parse().ok())?;
+    let submapper = optional(next("sub")?, |c| c.parse().ok())?;
+    let mirroring = optional(next("mirror")?, |c| match c {
+        "H" => Some(Mirroring::Horizontal),
+        "V" => Some(Mirroring::Vertical),
+        "4" => Some(Mirroring::FourScreen),
+        _ => None,
+    })?;
+    let prg_ram = optional(next("prg_ram")?, |c| c.parse::<usize>().ok())?;
+    let prg_nvram = optional(next("prg_nv")?, |c| c.parse::<usize>().ok())?;
+    let chr_ram = optional(next("chr_ram")?, |c| c.parse::<usize>().ok())?;
+    let timing = optional(next("region")?, |c| match c {
+        "NTSC" => Some(TIMING_NTSC),
+        "PAL" => Some(TIMING_PAL),
+        "Multi" => Some(TIMING_MULTI_REGION),
+        "Dendy" => Some(TIMING_DENDY),
+        _ => None,
+    })?;
+    let name = rest.trim().to_string();
+    if name.is_empty() {
+        return Err("missing name".to_string());
+    }
+
+    let ram = match (prg_ram, prg_nvram, chr_ram) {
+        (Some(prg_ram), Some(prg_nvram), Some(chr_ram)) => Some((prg_ram, prg_nvram, chr_ram)),
+        (None, None, None) => None,
+        _ => return Err("RAM sizes must all be given or all be -".to_string()),
+    };
+
+    Ok((
+        key,
+        GameEntry {
+            name,
+            mapper,
+            submapper,
+            mirroring,
+            ram,
+            timing,
+        },
+    ))
+}
+
+// 8 hex digits for a CRC-32, 40 for a SHA-1
+fn parse_key(column: &str) -> Result<Key, String> {
+    if !column.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("hash: bad value {}", column));
+    }
+    match column.len() {
+        8 => Ok(Key::Crc32(u32::from_str_radix(column, 16).unwrap())),
+        40 => {
+            let mut sha1 = [0; 20];
+            for (i, byte) in sha1.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&column[i * 2..i * 2 + 2], 16).unwrap();
+            }
+            Ok(Key::Sha1(sha1))
+        }
+        _ => Err(format!("hash: {} is neither a CRC-32 nor a SHA-1", column)),
+    }
+}
+
+// Splits the next whitespace separated column off the front of `rest`
+fn column<'a>(rest: &mut &'a str) -> Option<&'a str> {
+    let line = rest.trim_start();
+    if line.is_empty() {
+        return None;
+    }
+    let end = line.find(char::is_whitespace).unwrap_or(line.len());
+    let (column, remainder) = line.split_at(end);
+    *rest = remainder;
+    Some(column)
+}
+
+fn optional<T>(column: &str, parse: impl Fn(&str) -> Option<T>) -> Result<Option<T>, String> {
+    if column == "-" {
+        return Ok(None);
+    }
+    parse(column)
+        .map(Some)
+        .ok_or(format!("bad value {}", column))
+}
+
+// Inverse of the NES 2.0 64 << n encoding, rounding up to the next size that fits
+fn size_to_shift(bytes: usize) -> u8 {
+    if bytes == 0 {
+        return 0;
+    }
+    let mut shift = 1;
+    while (64usize << shift) < bytes && shift < 15 {
+        shift += 1;
+    }
+    shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::crc32::crc32;
+
+    fn ines_header() -> CartridgeHeader {
+        let mut file = [0; 16];
+        file[0..4].copy_from_slice(&[0x4E, 0x45, 0x53, 0x1A]);
+        file[6] = 0x01;
+        CartridgeHeader::parse(&file)
+    }
+
+    #[test]
+    fn embedded_database_parses() {
+        Database::embedded();
+    }
+
+    #[test]
+    fn finds_entries_by_prg_and_chr_crc() {
+        let prg = [1, 2, 3];
+        let chr = [4, 5];
+        let text = format!(
+            "# comment\n{:08X}  4  0 H  -  -  -  -     Some Game (U)\n",
+            crc32(&[1, 2, 3, 4, 5])
+        );
+        let database = Database::parse(&text).unwrap();
+
+        let (hash, entry) = database.find(&prg, &chr).unwrap();
+        assert_eq!(hash, format!("{:08X}", crc32(&[1, 2, 3, 4, 5])));
+        assert_eq!(entry.name, "Some Game (U)");
+        assert_eq!(entry.mapper, Some(4));
+        assert!(database.find(&prg, &[]).is_none());
+    }
+
+    #[test]
+    fn finds_entries_by_sha1_first() {
+        // SHA-1 of "abc"
+        let text = "A9993E364706816ABA3E25717850C26C9CD0D89D  4 - - - - - - By SHA-1\n\
+                    352441C2                                  1 - - - - - - By CRC-32\n";
+        let database = Database::parse(text).unwrap();
+        let (hash, entry) = database.find(b"ab", b"c").unwrap();
+        assert_eq!(hash, "A9993E364706816ABA3E25717850C26C9CD0D89D");
+        assert_eq!(entry.mapper, Some(4));
+        assert!(database.find(b"ab", b"").is_none());
+    }
+
+    #[test]
+    fn applies_overrides() {
+        let entry = parse_line("00000000 1 5 H 0 8192 8192 PAL Game").unwrap().1;
+        let mut header = ines_header();
+        entry.apply(&mut header);
+
+        assert_eq!(header.mapper, 1);
+        assert_eq!(header.submapper, 5);
+        assert_eq!(header.mirroring(), Mirroring::Horizontal);
+        assert!(header.nes2);
+        assert_eq!(header.prg_ram_bytes(0), 0x2000);
+        assert!(header.has_battery());
+        assert_eq!(header.chr_ram_bytes(0), 0x2000);
+        assert_eq!(header.cpu_ppu_timing, TIMING_PAL);
+    }
+
+    #[test]
+    fn dashes_keep_header_fields() {
+        let entry = parse_line("00000000 - - - - - - - Game").unwrap().1;
+        let mut header = ines_header();
+        let original = header;
+        entry.apply(&mut header);
+        assert_eq!(header, original);
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        assert!(parse_line("XYZ 1 0 H - - - - Game").is_err());
+        assert!(parse_line("0 1 0 H - - - - Game").is_err());
+        assert!(parse_line("00000000 1 0 Q - - - - Game").is_err());
+        assert!(parse_line("00000000 1 0 H 8192 - - - Game").is_err());
+        assert!(parse_line("00000000 1 0 H").is_err());
+    }
+
+    #[test]
+    fn converts_sizes_to_shifts() {
+        assert_eq!(size_to_shift(0), 0);
+        assert_eq!(size_to_shift(128), 1);
+        assert_eq!(size_to_shift(0x2000), 7);
+        assert_eq!(size_to_shift(0x2001), 8);
+    }
+}
@@ -0,0 +1,104 @@
+/**
+* Errors returned while loading a cartridge image
+*/
+use std::fmt;
+use std::io;
+
+use super::patch::PatchError;
+
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    // The file is shorter than the 16 byte header
+    TruncatedHeader { length: usize },
+    // The file doesn't start with "NES\x1A" or "UNIF"
+    BadMagic,
+    // The header is well formed but describes an impossible cartridge
+    BadHeader(String),
+    TruncatedTrainer { found: usize },
+    TruncatedPrg { expected: usize, found: usize },
+    TruncatedChr { expected: usize, found: usize },
+    UnsupportedMapper { mapper: u16, submapper: u8 },
+    // UNIF chunk running past the end of the file
+    TruncatedChunk(String),
+    // UNIF board name with no mapper behind it
+    UnsupportedBoard(String),
+    // --db file that can't be read or parsed
+    BadDatabase(String),
+    // IPS/UPS/BPS patch that is malformed or made for a different ROM
+    Patch(PatchError),
+    // Zip or gzip file that can't be read
+    BadArchive(String),
+    // Zip file without a .nes/.unf/.fds/.nsf entry
+    NoRomInArchive,
+    MissingArchiveEntry(String),
+    // Disk images need the FDS BIOS, which isn't part of the image
+    MissingFdsBios,
+    BadFdsBios { length: usize },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::Io(e) => write!(f, "Error reading file: {}", e),
+            RomError::TruncatedHeader { length } => {
+                write!(f, "File is too short for an iNES header ({} bytes)", length)
+            }
+            RomError::BadMagic => write!(f, "Not an iNES or UNIF file"),
+            RomError::BadHeader(reason) => write!(f, "Bad iNES header: {}", reason),
+            RomError::TruncatedTrainer { found } => {
+                write!(
+                    f,
+                    "Trainer is truncated: expected 512 bytes, found {}",
+                    found
+                )
+            }
+            RomError::TruncatedPrg { expected, found } => write!(
+                f,
+                "PRG ROM is truncated: expected {} bytes, found {}",
+                expected, found
+            ),
+            RomError::TruncatedChr { expected, found } => write!(
+                f,
+                "CHR ROM is truncated: expected {} bytes, found {}",
+                expected, found
+            ),
+            RomError::UnsupportedMapper { mapper, submapper } => {
+                write!(f, "Unsupported mapper {} (submapper {})", mapper, submapper)
+            }
+            RomError::TruncatedChunk(id) => write!(f, "UNIF chunk {} is truncated", id),
+            RomError::UnsupportedBoard(board) => write!(f, "Unsupported board {}", board),
+            RomError::BadDatabase(reason) => write!(f, "Bad game database {}", reason),
+            RomError::Patch(e) => write!(f, "Could not apply patch: {}", e),
+            RomError::BadArchive(reason) => write!(f, "Bad archive: {}", reason),
+            RomError::NoRomInArchive => write!(f, "No ROM found in archive"),
+            RomError::MissingArchiveEntry(name) => write!(f, "No {} in archive", name),
+            RomError::MissingFdsBios => write!(f, "Disk images need an FDS BIOS (--fds-bios)"),
+            RomError::BadFdsBios { length } => {
+                write!(f, "FDS BIOS should be 8192 bytes, found {}", length)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RomError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RomError::Io(e) => Some(e),
+            RomError::Patch(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RomError {
+    fn from(e: io::Error) -> RomError {
+        RomError::Io(e)
+    }
+}
+
+impl From<PatchError> for RomError {
+    fn from(e: PatchError) -> RomError {
+        RomError::Patch(e)
+    }
+}
@@ -0,0 +1,84 @@
+/**
+* Famicom Disk System images
+*
+* A .fds file is the disk sides back to back, 65500 bytes each, optionally behind a 16 byte
+* fwNES header ("FDS\x1A", then the side count). Every side starts with the disk info block:
+* block type 1 and "*NINTENDO-HVC*".
+*/
+use super::RomError;
+
+pub const SIDE_SIZE: usize = 65500;
+
+const DISK_INFO: &[u8] = b"\x01*NINTENDO-HVC*";
+
+pub fn is_fds(file: &[u8]) -> bool {
+    file.starts_with(b"FDS\x1A") || file.starts_with(DISK_INFO)
+}
+
+pub fn parse(file: &[u8]) -> Result<Vec<Vec<u8>>, RomError> {
+    let data = if file.starts_with(b"FDS\x1A") {
+        if file.len() < 16 {
+            return Err(RomError::TruncatedHeader { length: file.len() });
+        }
+        &file[16..]
+    } else {
+        file
+    };
+
+    // Some dumps trim the unused end of the last side
+    let sides: Vec<Vec<u8>> = data
+        .chunks(SIDE_SIZE)
+        .map(|side| {
+            let mut side = side.to_vec();
+            side.resize(SIDE_SIZE, 0);
+            side
+        })
+        .collect();
+    if sides.is_empty() {
+        return Err(RomError::BadHeader("no disk sides".to_string()));
+    }
+    for (number, side) in sides.iter().enumerate() {
+        if !side.starts_with(DISK_INFO) {
+            return Err(RomError::BadHeader(format!(
+                "disk side {} has no disk info block",
+                number + 1
+            )));
+        }
+    }
+    Ok(sides)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn side() -> Vec<u8> {
+        let mut side = DISK_INFO.to_vec();
+        side.resize(SIDE_SIZE, 0);
+        side
+    }
+
+    #[test]
+    fn reads_sides_with_and_without_header() {
+        let raw = [side(), side()].concat();
+        assert!(is_fds(&raw));
+        assert_eq!(parse(&raw).unwrap().len(), 2);
+
+        let mut headered = b"FDS\x1A\x02".to_vec();
+        headered.resize(16, 0);
+        headered.extend_from_slice(&raw);
+        assert!(is_fds(&headered));
+        assert_eq!(parse(&headered).unwrap(), vec![side(), side()]);
+    }
+
+    #[test]
+    fn rejects_bad_sides() {
+        let mut raw = [side(), side()].concat();
+        raw[SIDE_SIZE] = 0x02;
+        assert!(matches!(parse(&raw), Err(RomError::BadHeader(_))));
+        assert!(matches!(
+            parse(b"FDS\x1A"),
+            Err(RomError::TruncatedHeader { length: 4 })
+        ));
+    }
+}
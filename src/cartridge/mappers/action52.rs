@@ -0,0 +1,169 @@
+/**
+* Active Enterprises Action 52 and Cheetahmen II (mapper 228)
+*
+* Every register lives in the address written to $8000-$FFFF:
+*   A13: mirroring, A11-A12: PRG chip, A6-A10: 16K PRG page, A5: 16K (1) or 32K (0) mode,
+*   A0-A3 + D0-D1: 8K CHR bank
+* The board has three 512K PRG chips in sockets for chips 0, 1 and 3; chip 2 is unpopulated and
+* reads as open bus. $4020-$5FFF holds four 4-bit RAM cells the menu uses.
+*/
+use super::{read_bank, write_bank, Mapper, Mirroring};
+use crate::cartridge::CartridgeHeader;
+
+pub struct Action52 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+
+    prg_banks: [usize; 2],
+    prg_chip_present: bool,
+    chr_bank: usize,
+    mirroring: Mirroring,
+    ram: [u8; 4],
+}
+
+impl Action52 {
+    pub fn new(header: &CartridgeHeader, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Action52 {
+        Action52 {
+            prg_rom,
+            chr: chr_rom,
+            chr_writable: header.chr_rom_bytes() == 0,
+
+            prg_banks: [0, 1],
+            prg_chip_present: true,
+            chr_bank: 0,
+            mirroring: Mirroring::Vertical,
+            ram: [0; 4],
+        }
+    }
+}
+
+impl Mapper for Action52 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x4020..=0x5FFF => self.ram[address as usize & 0x03] & 0x0F,
+            0x8000..=0xFFFF if self.prg_chip_present => {
+                let bank = self.prg_banks[(address as usize - 0x8000) / 0x4000];
+                read_bank(&self.prg_rom, bank, 0x4000, address as usize)
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x4020..=0x5FFF => self.ram[address as usize & 0x03] = value & 0x0F,
+            0x8000..=0xFFFF => {
+                let address = address as usize;
+                let chip = match (address >> 11) & 0x03 {
+                    3 => 2,
+                    chip => chip,
+                };
+                self.prg_chip_present = (address >> 11) & 0x03 != 2;
+                let page = (chip << 5) | ((address >> 6) & 0x1F);
+
+                self.prg_banks = if address & 0x20 != 0 {
+                    [page, page]
+                } else {
+                    [page & !1, page | 1]
+                };
+                self.chr_bank = ((address & 0x0F) << 2) | (value as usize & 0x03);
+                self.mirroring = if address & 0x2000 != 0 {
+                    Mirroring::Horizontal
+                } else {
+                    Mirroring::Vertical
+                };
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        read_bank(&self.chr, self.chr_bank, 0x2000, address as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            write_bank(
+                &mut self.chr,
+                self.chr_bank,
+                0x2000,
+                address as usize,
+                value,
+            );
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::mappers::test_rom;
+
+    fn action52() -> Action52 {
+        // Three 512K chips of 16K pages, 512K of CHR
+        let prg = test_rom::banked(0x180000, 0x4000);
+        let chr = test_rom::banked(0x80000, 0x2000);
+        Action52::new(&test_rom::header(228, 0), prg, chr)
+    }
+
+    #[test]
+    fn selects_32k_and_16k_prg_modes() {
+        let mut mapper = action52();
+
+        // 32K mode, page 5 rounds down to the pair 4/5
+        mapper.cpu_write(0x8000 | (5 << 6), 0);
+        assert_eq!(mapper.cpu_read(0x8000), 4);
+        assert_eq!(mapper.cpu_read(0xC000), 5);
+
+        // 16K mode mirrors the page into both halves
+        mapper.cpu_write(0x8000 | (5 << 6) | 0x20, 0);
+        assert_eq!(mapper.cpu_read(0x8000), 5);
+        assert_eq!(mapper.cpu_read(0xC000), 5);
+    }
+
+    #[test]
+    fn chip_three_maps_to_third_socket() {
+        let mut mapper = action52();
+
+        mapper.cpu_write(0x8000 | (3 << 11) | 0x20, 0);
+        assert_eq!(mapper.cpu_read(0x8000), 64);
+    }
+
+    #[test]
+    fn chip_two_is_open_bus() {
+        let mut mapper = action52();
+
+        mapper.cpu_write(0x8000 | (2 << 11) | (1 << 6) | 0x20, 0);
+        assert_eq!(mapper.cpu_read(0x8000), 0);
+        assert_eq!(mapper.cpu_read(0xFFFF), 0);
+
+        mapper.cpu_write(0x8000 | (1 << 11) | (1 << 6) | 0x20, 0);
+        assert_eq!(mapper.cpu_read(0x8000), 33);
+    }
+
+    #[test]
+    fn chr_bank_combines_address_and_data() {
+        let mut mapper = action52();
+
+        mapper.cpu_write(0x8000 | 0x05, 0x02);
+        assert_eq!(mapper.ppu_read(0x0000), (5 << 2) | 2);
+    }
+
+    #[test]
+    fn mirroring_and_nibble_ram() {
+        let mut mapper = action52();
+
+        mapper.cpu_write(0xA000, 0);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+        mapper.cpu_write(0x8000, 0);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Vertical));
+
+        mapper.cpu_write(0x5FF1, 0xAB);
+        assert_eq!(mapper.cpu_read(0x5FF1), 0x0B);
+    }
+}
@@ -0,0 +1,154 @@
+/**
+* BNROM and AVE NINA-001 (mapper 34)
+*
+* Two unrelated boards share this number. BNROM (submapper 2) switches 32K of PRG with any write
+* to $8000-$FFFF and uses CHR RAM. NINA-001 (submapper 1) has 8K of PRG RAM and its registers at
+* $7FFD-$7FFF: a 32K PRG bank and two 4K CHR banks. Plain iNES dumps are told apart by whether
+* they carry more than 8K of CHR ROM.
+*/
+use super::{read_bank, write_bank, Mapper};
+use crate::cartridge::CartridgeHeader;
+
+pub struct Bnrom {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+    prg_ram: Vec<u8>,
+
+    is_nina001: bool,
+    prg_bank: u8,
+    chr_banks: [u8; 2],
+}
+
+impl Bnrom {
+    pub fn new(header: &CartridgeHeader, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Bnrom {
+        let is_nina001 = match header.submapper {
+            1 => true,
+            2 => false,
+            _ => chr_rom.len() > 0x2000,
+        };
+
+        Bnrom {
+            prg_rom,
+            chr: chr_rom,
+            chr_writable: header.chr_rom_bytes() == 0,
+            prg_ram: vec![0; 0x2000],
+
+            is_nina001,
+            prg_bank: 0,
+            chr_banks: [0, 1],
+        }
+    }
+}
+
+impl Mapper for Bnrom {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF if self.is_nina001 => self.prg_ram[address as usize - 0x6000],
+            0x8000..=0xFFFF => read_bank(
+                &self.prg_rom,
+                self.prg_bank as usize,
+                0x8000,
+                address as usize,
+            ),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if self.is_nina001 {
+            if let 0x6000..=0x7FFF = address {
+                self.prg_ram[address as usize - 0x6000] = value;
+            }
+            match address {
+                0x7FFD => self.prg_bank = value & 0x01,
+                0x7FFE => self.chr_banks[0] = value & 0x0F,
+                0x7FFF => self.chr_banks[1] = value & 0x0F,
+                _ => {}
+            }
+            return;
+        }
+
+        if address >= 0x8000 {
+            self.prg_bank = value;
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        let bank = self.chr_banks[address as usize / 0x1000];
+        read_bank(&self.chr, bank as usize, 0x1000, address as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            let bank = self.chr_banks[address as usize / 0x1000];
+            write_bank(
+                &mut self.chr,
+                bank as usize,
+                0x1000,
+                address as usize,
+                value,
+            );
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::mappers::test_rom;
+
+    #[test]
+    fn bnrom_switches_32k_on_any_rom_write() {
+        let prg = test_rom::banked(0x20000, 0x8000);
+        let mut mapper = Bnrom::new(&test_rom::header(34, 2), prg, vec![]);
+
+        assert_eq!(mapper.cpu_read(0x8000), 0);
+        mapper.cpu_write(0xFFF0, 3);
+        assert_eq!(mapper.cpu_read(0x8000), 3);
+        assert_eq!(mapper.cpu_read(0xFFFF), 3);
+
+        // $7FFD is plain open bus on BNROM
+        mapper.cpu_write(0x7FFD, 1);
+        assert_eq!(mapper.cpu_read(0x8000), 3);
+    }
+
+    #[test]
+    fn nina001_registers_and_prg_ram() {
+        let prg = test_rom::banked(0x10000, 0x8000);
+        let chr = test_rom::banked(0x10000, 0x1000);
+        let mut mapper = Bnrom::new(&test_rom::header(34, 1), prg, chr);
+
+        mapper.cpu_write(0x7FFD, 1);
+        mapper.cpu_write(0x7FFE, 6);
+        mapper.cpu_write(0x7FFF, 9);
+        assert_eq!(mapper.cpu_read(0x8000), 1);
+        assert_eq!(mapper.ppu_read(0x0000), 6);
+        assert_eq!(mapper.ppu_read(0x1FFF), 9);
+
+        mapper.cpu_write(0x6123, 0x42);
+        assert_eq!(mapper.cpu_read(0x6123), 0x42);
+
+        // ROM writes don't touch the registers on NINA-001
+        mapper.cpu_write(0x8000, 0);
+        assert_eq!(mapper.cpu_read(0x8000), 1);
+    }
+
+    #[test]
+    fn detects_nina001_from_chr_size() {
+        let prg = test_rom::banked(0x10000, 0x8000);
+        let chr = test_rom::banked(0x10000, 0x1000);
+        let mut mapper = Bnrom::new(&test_rom::header(34, 0), prg, chr);
+
+        mapper.cpu_write(0x7FFD, 1);
+        assert_eq!(mapper.cpu_read(0x8000), 1);
+    }
+}
@@ -0,0 +1,119 @@
+/**
+* Camerica/Codemasters BF909x (mapper 71)
+*
+* A 16K switchable bank at $8000 with the last bank fixed at $C000. The BF9097 variant used by
+* Fire Hawk (submapper 1) also selects a single-screen nametable through $9000-$9FFF; the plain
+* BF9093 (submapper 2) has hardwired mirroring and ignores those writes.
+*/
+use super::{read_bank, write_bank, Mapper, Mirroring};
+use crate::cartridge::CartridgeHeader;
+
+pub struct Camerica {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+
+    prg_bank: u8,
+    has_mirroring_control: bool,
+    mirroring: Option<Mirroring>,
+}
+
+impl Camerica {
+    pub fn new(header: &CartridgeHeader, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Camerica {
+        Camerica {
+            prg_rom,
+            chr: chr_rom,
+            chr_writable: header.chr_rom_bytes() == 0,
+
+            prg_bank: 0,
+            has_mirroring_control: header.submapper != 2,
+            mirroring: None,
+        }
+    }
+}
+
+impl Mapper for Camerica {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x8000..=0xBFFF => read_bank(
+                &self.prg_rom,
+                self.prg_bank as usize,
+                0x4000,
+                address as usize,
+            ),
+            0xC000..=0xFFFF => {
+                let last = (self.prg_rom.len() / 0x4000).saturating_sub(1);
+                read_bank(&self.prg_rom, last, 0x4000, address as usize)
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x9000..=0x9FFF if self.has_mirroring_control => {
+                self.mirroring = Some(if value & 0x10 == 0 {
+                    Mirroring::SingleScreenA
+                } else {
+                    Mirroring::SingleScreenB
+                });
+            }
+            0xC000..=0xFFFF => self.prg_bank = value,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        read_bank(&self.chr, 0, 0x2000, address as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            write_bank(&mut self.chr, 0, 0x2000, address as usize, value);
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        self.mirroring
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::mappers::test_rom;
+
+    #[test]
+    fn switches_16k_bank_at_8000_and_fixes_last_bank() {
+        let prg = test_rom::banked(0x20000, 0x4000);
+        let mut mapper = Camerica::new(&test_rom::header(71, 0), prg, vec![]);
+
+        assert_eq!(mapper.cpu_read(0x8000), 0);
+        assert_eq!(mapper.cpu_read(0xC000), 7);
+
+        mapper.cpu_write(0xC000, 5);
+        assert_eq!(mapper.cpu_read(0x8000), 5);
+        assert_eq!(mapper.cpu_read(0xBFFF), 5);
+        assert_eq!(mapper.cpu_read(0xFFFF), 7);
+    }
+
+    #[test]
+    fn fire_hawk_selects_single_screen() {
+        let prg = test_rom::banked(0x20000, 0x4000);
+        let mut mapper = Camerica::new(&test_rom::header(71, 1), prg, vec![]);
+
+        mapper.cpu_write(0x9000, 0x10);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::SingleScreenB));
+        mapper.cpu_write(0x9000, 0x00);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::SingleScreenA));
+    }
+
+    #[test]
+    fn bf9093_ignores_mirroring_writes() {
+        let prg = test_rom::banked(0x20000, 0x4000);
+        let mut mapper = Camerica::new(&test_rom::header(71, 2), prg, vec![]);
+
+        mapper.cpu_write(0x9000, 0x10);
+        assert_eq!(mapper.mirroring(), None);
+    }
+}
@@ -0,0 +1,650 @@
+/**
+* Famicom Disk System RAM adapter (mapper 20)
+*
+* 32K of PRG RAM at $6000-$DFFF, the 8K BIOS at $E000-$FFFF and 8K of CHR RAM. Registers:
+*   $4020/$4021: timer reload, $4022: timer control, $4023: disk/sound I/O enable,
+*   $4024: write data, $4025: drive control, $4026: expansion port output,
+*   $4030: status (acknowledges IRQs), $4031: read data, $4032: drive status,
+*   $4033: expansion port input
+* The timer counts down every CPU cycle and raises an IRQ at zero. Disks are streamed one byte
+* every 150 CPU cycles (about 96.4 kbit/s); each byte read or written can raise an IRQ. Disk sides
+* are stored as the drive sees them, with gaps, start marks and CRCs between the blocks, and
+* turned back into .fds sides (disk_image) when the frontend saves what games wrote to them.
+* The sound channel ($4040-$4097) isn't emulated.
+*/
+use super::{Mapper, Mirroring};
+use crate::cartridge::fds::SIDE_SIZE;
+use crate::cartridge::CartridgeHeader;
+
+// CPU cycles between bytes, and from the motor starting to the head reaching the first gap
+const BYTE_CYCLES: u32 = 150;
+const SPIN_UP_CYCLES: u32 = 50000;
+// About half a second with no disk in the drive, so the BIOS notices a side was changed
+const SWAP_CYCLES: u32 = 900000;
+
+// In bits, as written by the disk writer
+const LEAD_IN_GAP: usize = 28300;
+const BLOCK_GAP: usize = 976;
+
+pub struct Fds {
+    bios: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+
+    sides: Vec<Vec<u8>>,
+    side: Option<usize>,
+    pending_side: Option<usize>,
+    swap_delay: u32,
+
+    disk_enabled: bool,
+    sound_enabled: bool,
+    mirroring: Mirroring,
+
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_enabled: bool,
+    timer_repeat: bool,
+    timer_irq: bool,
+
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    crc_control: bool,
+    transfer_enabled: bool,
+    disk_irq_enabled: bool,
+    disk_irq: bool,
+
+    position: usize,
+    delay: u32,
+    scanning: bool,
+    end_of_head: bool,
+    gap_ended: bool,
+    byte_transferred: bool,
+    read_data: u8,
+    write_data: u8,
+    crc: u16,
+    external_output: u8,
+}
+
+impl Fds {
+    // `sides` are 65500 byte sides as stored in .fds files
+    pub fn new(_header: &CartridgeHeader, bios: Vec<u8>, sides: Vec<Vec<u8>>) -> Fds {
+        Fds {
+            bios,
+            prg_ram: vec![0; 0x8000],
+            chr_ram: vec![0; 0x2000],
+
+            sides: sides.iter().map(|side| add_gaps(side)).collect(),
+            side: Some(0),
+            pending_side: None,
+            swap_delay: 0,
+
+            disk_enabled: false,
+            sound_enabled: false,
+            mirroring: Mirroring::Vertical,
+
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_enabled: false,
+            timer_repeat: false,
+            timer_irq: false,
+
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            crc_control: false,
+            transfer_enabled: false,
+            disk_irq_enabled: false,
+            disk_irq: false,
+
+            position: 0,
+            delay: 0,
+            scanning: false,
+            end_of_head: true,
+            gap_ended: false,
+            byte_transferred: false,
+            read_data: 0,
+            write_data: 0,
+            crc: 0,
+            external_output: 0,
+        }
+    }
+
+    pub fn side_count(&self) -> usize {
+        self.sides.len()
+    }
+
+    // The side in the drive, or None when it's empty
+    pub fn current_side(&self) -> Option<usize> {
+        self.side.or(self.pending_side)
+    }
+
+    pub fn eject(&mut self) {
+        self.side = None;
+        self.pending_side = None;
+    }
+
+    // Ejects whatever is in the drive and inserts `side` a moment later, like swapping by hand
+    pub fn insert(&mut self, side: usize) {
+        if side >= self.sides.len() {
+            return;
+        }
+        self.side = None;
+        self.pending_side = Some(side);
+        self.swap_delay = SWAP_CYCLES;
+    }
+
+    // Side A to side B, B to the next disk's A, and the last side back to the first
+    pub fn flip(&mut self) {
+        let next = self.current_side().map_or(0, |side| side + 1) % self.sides.len().max(1);
+        self.insert(next);
+    }
+
+    // The sides as stored in .fds files, back to back, including anything games wrote
+    pub fn disk_image(&self) -> Vec<u8> {
+        self.sides
+            .iter()
+            .flat_map(|disk| strip_gaps(disk))
+            .collect()
+    }
+
+    // Replaces the sides with those in a .fds image without its header, e.g. a saved one
+    pub fn load_image(&mut self, image: &[u8]) {
+        self.sides = image.chunks(SIDE_SIZE).map(add_gaps).collect();
+    }
+
+    fn disk_inserted(&self) -> bool {
+        self.side.is_some()
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.timer_enabled {
+            return;
+        }
+        if self.timer_counter == 0 {
+            self.timer_irq = true;
+            self.timer_counter = self.timer_reload;
+            if !self.timer_repeat {
+                self.timer_enabled = false;
+            }
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    fn clock_disk(&mut self) {
+        let side = match self.side {
+            Some(side) if self.motor_on => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning = false;
+                return;
+            }
+        };
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            // Head back at the start of the disk
+            self.delay = SPIN_UP_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let disk = &mut self.sides[side];
+        let mut irq = self.disk_irq_enabled;
+        if self.read_mode {
+            let byte = disk[self.position];
+            if !self.transfer_enabled {
+                self.gap_ended = false;
+            } else if byte != 0 && !self.gap_ended {
+                // The start mark ending a gap isn't handed to the CPU
+                self.gap_ended = true;
+                irq = false;
+            }
+            if self.gap_ended {
+                self.byte_transferred = true;
+                self.read_data = byte;
+                self.disk_irq |= irq;
+            }
+        } else {
+            let mut byte = 0;
+            if !self.crc_control {
+                self.byte_transferred = true;
+                byte = self.write_data;
+                self.disk_irq |= irq;
+            }
+            if !self.transfer_enabled {
+                byte = 0;
+            }
+            if self.crc_control {
+                // The two CRC bytes shift out after the block
+                byte = self.crc as u8;
+                self.crc >>= 8;
+            } else {
+                self.crc = update_crc(self.crc, byte);
+            }
+            disk[self.position] = byte;
+            self.gap_ended = false;
+        }
+
+        self.position += 1;
+        if self.position >= disk.len() {
+            // The drive stops at the end of the disk and the head goes back to the start
+            self.motor_on = false;
+            self.end_of_head = true;
+            self.position = 0;
+        } else {
+            self.delay = BYTE_CYCLES;
+        }
+    }
+}
+
+impl Mapper for Fds {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x4030 if self.disk_enabled => {
+                let mut status = 0;
+                if self.timer_irq {
+                    status |= 0x01;
+                }
+                if self.byte_transferred {
+                    status |= 0x02;
+                }
+                if self.end_of_head {
+                    status |= 0x40;
+                }
+                self.timer_irq = false;
+                self.disk_irq = false;
+                self.byte_transferred = false;
+                status
+            }
+            0x4031 if self.disk_enabled => {
+                self.disk_irq = false;
+                self.byte_transferred = false;
+                self.read_data
+            }
+            0x4032 if self.disk_enabled => {
+                // Bit 0: no disk, 1: not ready, 2: write protected (only without a disk)
+                let mut status = 0x40;
+                if !self.disk_inserted() {
+                    status |= 0x05;
+                }
+                if !self.disk_inserted() || !self.scanning {
+                    status |= 0x02;
+                }
+                status
+            }
+            // Bit 7 is the battery check, always good
+            0x4033 if self.disk_enabled => 0x80,
+            0x6000..=0xDFFF => self.prg_ram[address as usize - 0x6000],
+            0xE000..=0xFFFF => self.bios[(address as usize - 0xE000) % self.bios.len().max(1)],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x4020 => self.timer_reload = (self.timer_reload & 0xFF00) | value as u16,
+            0x4021 => self.timer_reload = (self.timer_reload & 0x00FF) | (value as u16) << 8,
+            0x4022 => {
+                self.timer_repeat = value & 0x01 != 0;
+                self.timer_enabled = value & 0x02 != 0 && self.disk_enabled;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_enabled = value & 0x01 != 0;
+                self.sound_enabled = value & 0x02 != 0;
+                if !self.disk_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                }
+            }
+            0x4024 if self.disk_enabled => {
+                self.write_data = value;
+                self.disk_irq = false;
+                self.byte_transferred = false;
+            }
+            0x4025 if self.disk_enabled => {
+                self.motor_on = value & 0x01 != 0;
+                self.reset_transfer = value & 0x02 != 0;
+                self.read_mode = value & 0x04 != 0;
+                self.mirroring = if value & 0x08 != 0 {
+                    Mirroring::Horizontal
+                } else {
+                    Mirroring::Vertical
+                };
+                self.crc_control = value & 0x10 != 0;
+                self.transfer_enabled = value & 0x40 != 0;
+                self.disk_irq_enabled = value & 0x80 != 0;
+                self.disk_irq = false;
+                if !self.transfer_enabled {
+                    self.crc = 0;
+                }
+            }
+            0x4026 if self.disk_enabled => self.external_output = value,
+            0x6000..=0xDFFF => self.prg_ram[address as usize - 0x6000] = value,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        self.chr_ram[address as usize & 0x1FFF]
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        self.chr_ram[address as usize & 0x1FFF] = value;
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+
+    fn cpu_clock(&mut self) {
+        if self.pending_side.is_some() {
+            if self.swap_delay == 0 {
+                self.side = self.pending_side.take();
+            } else {
+                self.swap_delay -= 1;
+            }
+        }
+
+        self.clock_timer();
+        self.clock_disk();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+}
+
+// Lays a .fds side out the way it sits on the disk: a lead-in gap, then every block behind a
+// start mark, followed by its CRC and a gap
+fn add_gaps(side: &[u8]) -> Vec<u8> {
+    let mut disk = vec![0; LEAD_IN_GAP / 8];
+    let mut position = 0;
+    let mut file_size = 0;
+
+    while position < side.len() {
+        let Some(length) = block_length(side[position], file_size) else {
+            break;
+        };
+        let Some(block) = side.get(position..position + length) else {
+            break;
+        };
+        if block[0] == 3 {
+            file_size = block[13] as usize | (block[14] as usize) << 8;
+        }
+
+        disk.push(0x80);
+        disk.extend_from_slice(block);
+        let crc = block_crc(block);
+        disk.extend_from_slice(&crc.to_le_bytes());
+        disk.extend(std::iter::repeat_n(0, BLOCK_GAP / 8));
+        position += length;
+    }
+
+    // Pad to the length of a real side so games that write new files have room for them
+    disk.resize(disk.len().max(side.len() + LEAD_IN_GAP / 8), 0);
+    disk
+}
+
+// Inverse of add_gaps, for blocks however they were written
+fn strip_gaps(disk: &[u8]) -> Vec<u8> {
+    let mut side = Vec::with_capacity(SIDE_SIZE);
+    let mut position = 0;
+    let mut file_size = 0;
+
+    // Each block follows a gap of zeros and a start mark
+    while let Some(gap) = disk
+        .get(position..)
+        .and_then(|rest| rest.iter().position(|&byte| byte != 0))
+    {
+        position += gap;
+        if disk[position] != 0x80 {
+            break;
+        }
+        position += 1;
+        let Some(length) = disk
+            .get(position)
+            .and_then(|&kind| block_length(kind, file_size))
+        else {
+            break;
+        };
+        let Some(block) = disk.get(position..position + length) else {
+            break;
+        };
+        if block[0] == 3 {
+            file_size = block[13] as usize | (block[14] as usize) << 8;
+        }
+
+        side.extend_from_slice(block);
+        // Skip the CRC
+        position += length + 2;
+    }
+
+    side.resize(SIDE_SIZE, 0);
+    side
+}
+
+// Length of a block by its type; file data blocks are as long as the preceding file header says
+fn block_length(kind: u8, file_size: usize) -> Option<usize> {
+    match kind {
+        1 => Some(56),
+        2 => Some(2),
+        3 => Some(16),
+        4 => Some(1 + file_size),
+        _ => None,
+    }
+}
+
+// The drive's CRC: CRC-16/KERMIT over the start mark and the block
+fn block_crc(block: &[u8]) -> u16 {
+    let mut crc = update_crc(0, 0x80);
+    for &byte in block {
+        crc = update_crc(crc, byte);
+    }
+    crc
+}
+
+fn update_crc(mut crc: u16, byte: u8) -> u16 {
+    crc ^= byte as u16;
+    for _ in 0..8 {
+        crc = if crc & 1 != 0 {
+            (crc >> 1) ^ 0x8408
+        } else {
+            crc >> 1
+        };
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::mappers::test_rom;
+
+    fn side() -> Vec<u8> {
+        let mut side = vec![0x01];
+        side.extend_from_slice(b"*NINTENDO-HVC*");
+        side.resize(56, 0);
+        side.extend_from_slice(&[0x02, 0x01]);
+        let mut file_header = vec![0x03; 16];
+        file_header[13] = 3;
+        file_header[14] = 0;
+        side.extend_from_slice(&file_header);
+        side.extend_from_slice(&[0x04, 0xAA, 0xBB, 0xCC]);
+        side.resize(65500, 0);
+        side
+    }
+
+    fn fds() -> Fds {
+        let mut bios = vec![0; 0x2000];
+        bios[0x1FFC] = 0x24;
+        Fds::new(&test_rom::header(20, 0), bios, vec![side(), side()])
+    }
+
+    #[test]
+    fn maps_ram_and_bios() {
+        let mut fds = fds();
+        fds.cpu_write(0x6000, 0x11);
+        fds.cpu_write(0xDFFF, 0x22);
+        assert_eq!(fds.cpu_read(0x6000), 0x11);
+        assert_eq!(fds.cpu_read(0xDFFF), 0x22);
+        assert_eq!(fds.cpu_read(0xFFFC), 0x24);
+        fds.cpu_write(0xFFFC, 0x00);
+        assert_eq!(fds.cpu_read(0xFFFC), 0x24);
+    }
+
+    #[test]
+    fn timer_irq() {
+        let mut fds = fds();
+        fds.cpu_write(0x4023, 0x01);
+        fds.cpu_write(0x4020, 0x02);
+        fds.cpu_write(0x4021, 0x00);
+        fds.cpu_write(0x4022, 0x03);
+
+        for _ in 0..2 {
+            fds.cpu_clock();
+            assert!(!fds.irq_pending());
+        }
+        fds.cpu_clock();
+        assert!(fds.irq_pending());
+        assert_eq!(fds.cpu_read(0x4030) & 0x01, 0x01);
+        assert!(!fds.irq_pending());
+
+        // Repeat mode reloads, and disabling disk I/O stops it
+        for _ in 0..3 {
+            fds.cpu_clock();
+        }
+        assert!(fds.irq_pending());
+        fds.cpu_write(0x4023, 0x00);
+        assert!(!fds.irq_pending());
+    }
+
+    #[test]
+    fn reads_blocks_with_transfer_irqs() {
+        let mut fds = fds();
+        fds.cpu_write(0x4023, 0x01);
+        // Motor on, read mode, transfer enabled, IRQ on every byte
+        fds.cpu_write(0x4025, 0xC5);
+
+        let mut bytes = vec![];
+        while bytes.len() < 15 {
+            fds.cpu_clock();
+            if fds.irq_pending() {
+                bytes.push(fds.cpu_read(0x4031));
+            }
+        }
+        assert_eq!(bytes[0], 0x01);
+        assert_eq!(&bytes[1..15], b"*NINTENDO-HVC*");
+        assert_eq!(fds.cpu_read(0x4032) & 0x07, 0x00);
+    }
+
+    #[test]
+    fn stops_at_end_of_disk() {
+        let mut fds = fds();
+        fds.cpu_write(0x4023, 0x01);
+        fds.cpu_write(0x4025, 0x05);
+        // Skip ahead to the last byte
+        fds.end_of_head = false;
+        fds.delay = 0;
+        fds.position = fds.sides[0].len() - 1;
+        fds.cpu_clock();
+        assert!(!fds.motor_on);
+        assert_eq!(fds.cpu_read(0x4030) & 0x40, 0x40);
+
+        // Turning the motor back on starts again from the beginning
+        fds.cpu_write(0x4025, 0x05);
+        fds.cpu_clock();
+        assert_eq!(fds.position, 0);
+        assert_eq!(fds.delay, SPIN_UP_CYCLES);
+    }
+
+    #[test]
+    fn lays_out_gaps_and_crcs() {
+        let disk = add_gaps(&side());
+        let start = LEAD_IN_GAP / 8;
+        assert!(disk[..start].iter().all(|&byte| byte == 0));
+        assert_eq!(disk[start], 0x80);
+        assert_eq!(disk[start + 1], 0x01);
+        // Block 1, its CRC and the gap, then block 2
+        let block_2 = start + 1 + 56 + 2 + BLOCK_GAP / 8;
+        assert_eq!(&disk[block_2..block_2 + 3], &[0x80, 0x02, 0x01]);
+        let file_data = block_2 + 3 + 2 + BLOCK_GAP / 8 + 1 + 16 + 2 + BLOCK_GAP / 8;
+        assert_eq!(
+            &disk[file_data..file_data + 5],
+            &[0x80, 0x04, 0xAA, 0xBB, 0xCC]
+        );
+    }
+
+    #[test]
+    fn disk_image_keeps_written_blocks() {
+        let mut fds = fds();
+        let mut image = side();
+        image.extend(side());
+        assert_eq!(fds.disk_image(), image);
+
+        // A new file after the last block on side 2, as the BIOS would write it
+        let mut file_header = vec![0x03; 16];
+        file_header[13] = 1;
+        file_header[14] = 0;
+        let file_data = vec![0x04, 0xEE];
+        let mut position = LEAD_IN_GAP / 8
+            + [56, 2, 16, 4]
+                .map(|length| 3 + length + BLOCK_GAP / 8)
+                .iter()
+                .sum::<usize>();
+        for block in [&file_header, &file_data] {
+            let disk = &mut fds.sides[1];
+            disk[position] = 0x80;
+            disk[position + 1..position + 1 + block.len()].copy_from_slice(block);
+            position += 1 + block.len();
+            disk[position..position + 2].copy_from_slice(&block_crc(block).to_le_bytes());
+            position += 2 + BLOCK_GAP / 8;
+        }
+
+        let saved = fds.disk_image();
+        let written = &saved[SIDE_SIZE + 78..SIDE_SIZE + 96];
+        assert_eq!(&written[..16], file_header.as_slice());
+        assert_eq!(&written[16..], file_data.as_slice());
+
+        // And they come back when the image is loaded again
+        let mut fds = Fds::new(&test_rom::header(20, 0), vec![0; 0x2000], vec![side()]);
+        fds.load_image(&saved);
+        assert_eq!(fds.side_count(), 2);
+        assert_eq!(fds.disk_image(), saved);
+    }
+
+    #[test]
+    fn swaps_and_ejects_disks() {
+        let mut fds = fds();
+        fds.cpu_write(0x4023, 0x01);
+        assert_eq!(fds.current_side(), Some(0));
+
+        fds.flip();
+        assert_eq!(fds.cpu_read(0x4032) & 0x01, 0x01);
+        for _ in 0..=SWAP_CYCLES {
+            fds.cpu_clock();
+        }
+        assert_eq!(fds.current_side(), Some(1));
+        assert_eq!(fds.cpu_read(0x4032) & 0x01, 0x00);
+
+        fds.flip();
+        assert_eq!(fds.current_side(), Some(0));
+        fds.eject();
+        assert_eq!(fds.current_side(), None);
+        assert_eq!(fds.cpu_read(0x4032) & 0x05, 0x05);
+    }
+}
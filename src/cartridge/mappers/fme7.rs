@@ -0,0 +1,238 @@
+/**
+* Sunsoft FME-7 and 5B (mapper 69)
+*
+* Commands are written to $8000 and their parameter to $A000. The $6000-$7FFF window can map
+* either PRG ROM or PRG RAM, and the IRQ is a 16-bit counter decremented every CPU cycle. The
+* 5B's audio registers ($C000/$E000) are kept so a sound core can be driven from them.
+*/
+use super::{read_bank, write_bank, Mapper, Mirroring};
+use crate::cartridge::CartridgeHeader;
+
+pub struct Fme7 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+    prg_ram: Vec<u8>,
+
+    command: u8,
+    chr_banks: [u8; 8],
+    prg_6000: u8, // command 8
+    prg_banks: [u8; 3],
+    mirroring: Mirroring,
+
+    irq_enabled: bool,
+    irq_counter_enabled: bool,
+    irq_counter: u16,
+    irq_pending: bool,
+
+    audio_register: u8,
+    audio_registers: [u8; 0x10],
+}
+
+impl Fme7 {
+    pub fn new(header: &CartridgeHeader, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Fme7 {
+        Fme7 {
+            prg_rom,
+            chr: chr_rom,
+            chr_writable: header.chr_rom_bytes() == 0,
+            prg_ram: vec![0; header.prg_ram_bytes(0x2000)],
+
+            command: 0,
+            chr_banks: [0; 8],
+            prg_6000: 0,
+            prg_banks: [0; 3],
+            mirroring: Mirroring::Vertical,
+
+            irq_enabled: false,
+            irq_counter_enabled: false,
+            irq_counter: 0,
+            irq_pending: false,
+
+            audio_register: 0,
+            audio_registers: [0; 0x10],
+        }
+    }
+
+    fn ram_selected(&self) -> bool {
+        self.prg_6000 & 0x40 != 0
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.prg_6000 & 0x80 != 0
+    }
+}
+
+impl Mapper for Fme7 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF => {
+                let bank = (self.prg_6000 & 0x3F) as usize;
+                if !self.ram_selected() {
+                    return read_bank(&self.prg_rom, bank, 0x2000, address as usize);
+                }
+                if self.ram_enabled() {
+                    return read_bank(&self.prg_ram, bank, 0x2000, address as usize);
+                }
+                0
+            }
+            0x8000..=0xDFFF => {
+                let bank = self.prg_banks[(address as usize - 0x8000) / 0x2000];
+                read_bank(&self.prg_rom, bank as usize, 0x2000, address as usize)
+            }
+            0xE000..=0xFFFF => {
+                let last = (self.prg_rom.len() / 0x2000).saturating_sub(1);
+                read_bank(&self.prg_rom, last, 0x2000, address as usize)
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x6000..=0x7FFF if self.ram_selected() && self.ram_enabled() => {
+                let bank = (self.prg_6000 & 0x3F) as usize;
+                write_bank(&mut self.prg_ram, bank, 0x2000, address as usize, value);
+            }
+            0x8000..=0x9FFF => self.command = value & 0x0F,
+            0xA000..=0xBFFF => match self.command {
+                0..=7 => self.chr_banks[self.command as usize] = value,
+                8 => self.prg_6000 = value,
+                9..=0xB => self.prg_banks[self.command as usize - 9] = value & 0x3F,
+                0xC => {
+                    self.mirroring = match value & 0x03 {
+                        0 => Mirroring::Vertical,
+                        1 => Mirroring::Horizontal,
+                        2 => Mirroring::SingleScreenA,
+                        _ => Mirroring::SingleScreenB,
+                    };
+                }
+                0xD => {
+                    self.irq_enabled = value & 0x01 != 0;
+                    self.irq_counter_enabled = value & 0x80 != 0;
+                    self.irq_pending = false;
+                }
+                0xE => self.irq_counter = (self.irq_counter & 0xFF00) | value as u16,
+                _ => self.irq_counter = (self.irq_counter & 0x00FF) | (value as u16) << 8,
+            },
+            0xC000..=0xDFFF => self.audio_register = value & 0x0F,
+            0xE000..=0xFFFF => self.audio_registers[self.audio_register as usize] = value,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        let bank = self.chr_banks[address as usize / 0x400];
+        read_bank(&self.chr, bank as usize, 0x400, address as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            let bank = self.chr_banks[address as usize / 0x400];
+            write_bank(&mut self.chr, bank as usize, 0x400, address as usize, value);
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+
+    fn cpu_clock(&mut self) {
+        if !self.irq_counter_enabled {
+            return;
+        }
+
+        self.irq_counter = self.irq_counter.wrapping_sub(1);
+        if self.irq_counter == 0xFFFF && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::mappers::test_rom;
+
+    fn fme7() -> Fme7 {
+        let mut header = test_rom::header(69, 0);
+        header.chr_rom_size = 32;
+        header.prg_ram_size = 7;
+        let prg = test_rom::banked(0x40000, 0x2000);
+        let chr = test_rom::banked(0x40000, 0x400);
+        Fme7::new(&header, prg, chr)
+    }
+
+    fn command(mapper: &mut Fme7, command: u8, value: u8) {
+        mapper.cpu_write(0x8000, command);
+        mapper.cpu_write(0xA000, value);
+    }
+
+    #[test]
+    fn prg_and_chr_banking() {
+        let mut mapper = fme7();
+        command(&mut mapper, 9, 3);
+        command(&mut mapper, 10, 4);
+        command(&mut mapper, 11, 5);
+        let banks = [0x8000, 0xA000, 0xC000, 0xE000].map(|address| mapper.cpu_read(address));
+        assert_eq!(banks, [3, 4, 5, 31]);
+
+        command(&mut mapper, 0, 40);
+        command(&mut mapper, 7, 47);
+        assert_eq!(mapper.ppu_read(0x0000), 40);
+        assert_eq!(mapper.ppu_read(0x1C00), 47);
+
+        command(&mut mapper, 12, 3);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::SingleScreenB));
+    }
+
+    #[test]
+    fn prg_rom_or_ram_at_6000() {
+        let mut mapper = fme7();
+        command(&mut mapper, 8, 6);
+        assert_eq!(mapper.cpu_read(0x6000), 6);
+
+        // RAM selected but disabled reads open bus and ignores writes
+        command(&mut mapper, 8, 0x40);
+        mapper.cpu_write(0x6000, 0x77);
+        assert_eq!(mapper.cpu_read(0x6000), 0);
+        command(&mut mapper, 8, 0xC0);
+        mapper.cpu_write(0x6000, 0x77);
+        assert_eq!(mapper.cpu_read(0x6000), 0x77);
+    }
+
+    #[test]
+    fn cycle_irq() {
+        let mut mapper = fme7();
+        command(&mut mapper, 14, 0x02);
+        command(&mut mapper, 15, 0x00);
+        command(&mut mapper, 13, 0x81);
+
+        // Fires when the counter wraps from 0 to $FFFF
+        for _ in 0..2 {
+            mapper.cpu_clock();
+        }
+        assert!(!mapper.irq_pending());
+        mapper.cpu_clock();
+        assert!(mapper.irq_pending());
+
+        // Writing the control register acknowledges it; the counter keeps running
+        command(&mut mapper, 13, 0x80);
+        assert!(!mapper.irq_pending());
+        for _ in 0..0x10000 {
+            mapper.cpu_clock();
+        }
+        assert!(!mapper.irq_pending());
+    }
+}
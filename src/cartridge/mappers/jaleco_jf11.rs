@@ -0,0 +1,93 @@
+/**
+* Jaleco JF-11 and JF-14 (mapper 140)
+*
+* A single register decoded at $6000-$7FFF: bits 4-5 select the 32K PRG bank and bits 0-3 the
+* 8K CHR bank.
+*/
+use super::{read_bank, write_bank, Mapper};
+use crate::cartridge::CartridgeHeader;
+
+pub struct JalecoJf11 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+
+    prg_bank: u8,
+    chr_bank: u8,
+}
+
+impl JalecoJf11 {
+    pub fn new(header: &CartridgeHeader, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> JalecoJf11 {
+        JalecoJf11 {
+            prg_rom,
+            chr: chr_rom,
+            chr_writable: header.chr_rom_bytes() == 0,
+
+            prg_bank: 0,
+            chr_bank: 0,
+        }
+    }
+}
+
+impl Mapper for JalecoJf11 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x8000..=0xFFFF => read_bank(
+                &self.prg_rom,
+                self.prg_bank as usize,
+                0x8000,
+                address as usize,
+            ),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if let 0x6000..=0x7FFF = address {
+            self.prg_bank = (value >> 4) & 0x03;
+            self.chr_bank = value & 0x0F;
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        read_bank(&self.chr, self.chr_bank as usize, 0x2000, address as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            write_bank(
+                &mut self.chr,
+                self.chr_bank as usize,
+                0x2000,
+                address as usize,
+                value,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::mappers::test_rom;
+
+    #[test]
+    fn register_at_6000() {
+        let prg = test_rom::banked(0x20000, 0x8000);
+        let chr = test_rom::banked(0x20000, 0x2000);
+        let mut mapper = JalecoJf11::new(&test_rom::header(140, 0), prg, chr);
+
+        mapper.cpu_write(0x6000, 0x2B);
+        assert_eq!(mapper.cpu_read(0x8000), 2);
+        assert_eq!(mapper.ppu_read(0x0000), 11);
+
+        // Mirrored throughout $6000-$7FFF
+        mapper.cpu_write(0x7FFF, 0x31);
+        assert_eq!(mapper.cpu_read(0xFFFF), 3);
+        assert_eq!(mapper.ppu_read(0x1FFF), 1);
+
+        // ROM writes don't reach the register
+        mapper.cpu_write(0x8000, 0x00);
+        assert_eq!(mapper.cpu_read(0x8000), 3);
+    }
+}
@@ -0,0 +1,706 @@
+/**
+* MMC5 (mapper 5): ExROM boards such as Castlevania III and Just Breed
+*
+* Supports all four PRG and CHR banking modes, separate sprite/background CHR banks in 8x16
+* sprite mode, ExRAM as a nametable, extended attributes or plain CPU RAM, fill mode, the
+* vertical split, the scanline IRQ, the 8x8 multiplier and the expansion audio: two APU pulse
+* channels without sweep units and an 8-bit PCM channel, written through $5011 or sampled from
+* reads of $8000-$BFFF.
+*/
+use super::{read_bank, write_bank, Mapper};
+use crate::apu::{pulse_level, Pulse, Sweep};
+use crate::cartridge::{CartridgeHeader, Nametables};
+use crate::region::Region;
+
+// The MMC5 clocks its envelopes and length counters at a fixed 240 Hz
+const FRAME_PERIOD: u16 = 7457;
+
+pub struct Mmc5 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+    prg_ram: Vec<u8>,
+    exram: [u8; 0x400],
+
+    prg_mode: u8,             // $5100
+    chr_mode: u8,             // $5101
+    prg_ram_protect: [u8; 2], // $5102, $5103
+    exram_mode: u8,           // $5104
+    nametable_mapping: u8,    // $5105
+    fill_tile: u8,            // $5106
+    fill_attribute: u8,       // $5107
+    prg_banks: [u8; 5],       // $5113-$5117
+    chr_banks_a: [u16; 8],    // $5120-$5127, sprites in 8x16 mode
+    chr_banks_b: [u16; 4],    // $5128-$512B, background in 8x16 mode
+    chr_upper: u8,            // $5130
+    last_chr_write_b: bool,
+
+    split_control: u8, // $5200
+    split_scroll: u8,  // $5201
+    split_bank: u8,    // $5202
+
+    irq_compare: u8, // $5203
+    irq_enabled: bool,
+    irq_pending: bool,
+    // Line count and pre-render line follow the console's region
+    region: Region,
+    in_frame: bool,
+    scanline_counter: u8,
+
+    multiplicand: u8, // $5205
+    multiplier: u8,   // $5206
+
+    // Snooped PPU state
+    sprite_8x16: bool,
+    rendering: bool,
+    fetching_sprites: bool,
+    tile_column: u16,
+    split_y: u16,
+    in_split: bool,
+    ext_bank: u8,
+    ext_palette: u8,
+
+    // Expansion audio
+    pulse1: Pulse,
+    pulse2: Pulse,
+    pcm_read_mode: bool,
+    pcm_irq_enabled: bool,
+    pcm_irq_pending: bool,
+    pcm_value: u8,
+    frame_divider: u16,
+}
+
+impl Mmc5 {
+    pub fn new(header: &CartridgeHeader, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Mmc5 {
+        Mmc5 {
+            prg_rom,
+            chr: chr_rom,
+            chr_writable: header.chr_rom_bytes() == 0,
+            // Largest PRG RAM configuration the board supports (2x 32K chips)
+            prg_ram: vec![0; 0x10000],
+            exram: [0; 0x400],
+
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [0; 2],
+            exram_mode: 0,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            prg_banks: [0, 0, 0, 0, 0xFF],
+            chr_banks_a: [0; 8],
+            chr_banks_b: [0; 4],
+            chr_upper: 0,
+            last_chr_write_b: false,
+
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            region: Region::from_timing(header.cpu_ppu_timing),
+            in_frame: false,
+            scanline_counter: 0,
+
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+
+            sprite_8x16: false,
+            rendering: false,
+            fetching_sprites: false,
+            tile_column: 0,
+            split_y: 0,
+            in_split: false,
+            ext_bank: 0,
+            ext_palette: 0,
+
+            pulse1: Pulse::new(Sweep::None),
+            pulse2: Pulse::new(Sweep::None),
+            pcm_read_mode: false,
+            pcm_irq_enabled: false,
+            pcm_irq_pending: false,
+            pcm_value: 0,
+            frame_divider: 0,
+        }
+    }
+
+    // Returns (is_rom, 8K bank number) for a CPU address in $8000-$FFFF
+    fn map_prg(&self, address: u16) -> (bool, usize) {
+        let (register, size) = match (self.prg_mode, address) {
+            (0, _) => (4, 4),
+            (1, 0x8000..=0xBFFF) => (2, 2),
+            (1, _) => (4, 2),
+            (2, 0x8000..=0xBFFF) => (2, 2),
+            (2, 0xC000..=0xDFFF) => (3, 1),
+            (2, _) => (4, 1),
+            _ => (1 + ((address as usize - 0x8000) >> 13), 1),
+        };
+
+        let value = self.prg_banks[register] as usize;
+        // $5117 can only select ROM
+        let is_rom = register == 4 || value & 0x80 != 0;
+        let bank = (value & 0x7F & !(size - 1)) | ((address as usize >> 13) & (size - 1));
+        (is_rom, if is_rom { bank } else { bank & 0x07 })
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect[0] & 0x03 == 0x02 && self.prg_ram_protect[1] & 0x03 == 0x01
+    }
+
+    fn background_fetch(&self) -> bool {
+        self.rendering && !self.fetching_sprites
+    }
+
+    fn chr_address(&self, address: u16) -> usize {
+        let address = address as usize;
+
+        if self.background_fetch() && self.in_split {
+            // The split region always uses a 4K bank and its own vertical scroll
+            let offset = (address & 0xFF8) | (self.split_y as usize & 0x07);
+            return self.split_bank as usize * 0x1000 + offset;
+        }
+
+        if self.background_fetch() && self.exram_mode == 1 {
+            let bank = self.ext_bank as usize | (self.chr_upper as usize & 0x03) << 6;
+            return bank * 0x1000 + (address & 0xFFF);
+        }
+
+        let use_b = if self.sprite_8x16 && self.rendering {
+            !self.fetching_sprites
+        } else {
+            self.last_chr_write_b
+        };
+
+        let slot = address / 0x400;
+        let (index, size) = match self.chr_mode {
+            0 => (7, 0x2000),
+            1 => (slot | 3, 0x1000),
+            2 => (slot | 1, 0x800),
+            _ => (slot, 0x400),
+        };
+        let bank = if use_b {
+            self.chr_banks_b[index & 3]
+        } else {
+            self.chr_banks_a[index]
+        };
+
+        bank as usize * size + (address % size)
+    }
+
+    fn split_region(&self) -> bool {
+        if self.split_control & 0x80 == 0 || self.exram_mode > 1 {
+            return false;
+        }
+
+        let count = (self.split_control & 0x1F) as u16;
+        if self.split_control & 0x40 == 0 {
+            return self.tile_column < count;
+        }
+        self.tile_column >= count
+    }
+
+    fn clock_frame(&mut self) {
+        self.frame_divider += 1;
+        if self.frame_divider >= FRAME_PERIOD {
+            self.frame_divider = 0;
+            for pulse in [&mut self.pulse1, &mut self.pulse2] {
+                pulse.clock_quarter_frame();
+                pulse.clock_half_frame();
+            }
+        }
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x5010 => {
+                let value = (self.pcm_irq_pending as u8) << 7 | self.pcm_read_mode as u8;
+                self.pcm_irq_pending = false;
+                value
+            }
+            0x5015 => self.pulse1.active() as u8 | (self.pulse2.active() as u8) << 1,
+            0x5204 => {
+                let value = (self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6;
+                self.irq_pending = false;
+                value
+            }
+            0x5205 => (self.multiplicand as u16 * self.multiplier as u16) as u8,
+            0x5206 => ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8,
+            0x5C00..=0x5FFF => {
+                if self.exram_mode >= 2 {
+                    return self.exram[address as usize - 0x5C00];
+                }
+                0
+            }
+            0x6000..=0x7FFF => {
+                let bank = self.prg_banks[0] as usize & 0x07;
+                read_bank(&self.prg_ram, bank, 0x2000, address as usize)
+            }
+            0x8000..=0xFFFF => {
+                let (is_rom, bank) = self.map_prg(address);
+                let value = if is_rom {
+                    read_bank(&self.prg_rom, bank, 0x2000, address as usize)
+                } else {
+                    read_bank(&self.prg_ram, bank, 0x2000, address as usize)
+                };
+
+                // In read mode the PCM channel samples reads from $8000-$BFFF and stops on a 0
+                if self.pcm_read_mode && address <= 0xBFFF {
+                    if value == 0 {
+                        self.pcm_irq_pending = true;
+                    } else {
+                        self.pcm_value = value;
+                    }
+                }
+                value
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x5000..=0x5003 => self.pulse1.write(address, value),
+            0x5004..=0x5007 => self.pulse2.write(address, value),
+            0x5010 => {
+                self.pcm_read_mode = value & 0x01 != 0;
+                self.pcm_irq_enabled = value & 0x80 != 0;
+            }
+            0x5011 if !self.pcm_read_mode && value != 0 => self.pcm_value = value,
+            0x5015 => {
+                self.pulse1.set_enabled(value & 0x01 != 0);
+                self.pulse2.set_enabled(value & 0x02 != 0);
+            }
+            0x5100 => self.prg_mode = value & 0x03,
+            0x5101 => self.chr_mode = value & 0x03,
+            0x5102 => self.prg_ram_protect[0] = value,
+            0x5103 => self.prg_ram_protect[1] = value,
+            0x5104 => self.exram_mode = value & 0x03,
+            0x5105 => self.nametable_mapping = value,
+            0x5106 => self.fill_tile = value,
+            0x5107 => self.fill_attribute = value & 0x03,
+            0x5113..=0x5117 => self.prg_banks[address as usize - 0x5113] = value,
+            0x5120..=0x5127 => {
+                self.chr_banks_a[address as usize - 0x5120] =
+                    value as u16 | (self.chr_upper as u16) << 8;
+                self.last_chr_write_b = false;
+            }
+            0x5128..=0x512B => {
+                self.chr_banks_b[address as usize - 0x5128] =
+                    value as u16 | (self.chr_upper as u16) << 8;
+                self.last_chr_write_b = true;
+            }
+            0x5130 => self.chr_upper = value & 0x03,
+            0x5200 => self.split_control = value,
+            0x5201 => self.split_scroll = value,
+            0x5202 => self.split_bank = value,
+            0x5203 => self.irq_compare = value,
+            0x5204 => self.irq_enabled = value & 0x80 != 0,
+            0x5205 => self.multiplicand = value,
+            0x5206 => self.multiplier = value,
+            0x5C00..=0x5FFF => {
+                let offset = address as usize - 0x5C00;
+                match self.exram_mode {
+                    // Only writable while rendering in the nametable modes, otherwise 0 is stored
+                    0 | 1 => self.exram[offset] = if self.in_frame { value } else { 0 },
+                    2 => self.exram[offset] = value,
+                    _ => {}
+                }
+            }
+            0x6000..=0x7FFF if self.prg_ram_writable() => {
+                let bank = self.prg_banks[0] as usize & 0x07;
+                write_bank(&mut self.prg_ram, bank, 0x2000, address as usize, value);
+            }
+            0x8000..=0xDFFF => {
+                let (is_rom, bank) = self.map_prg(address);
+                if !is_rom && self.prg_ram_writable() {
+                    write_bank(&mut self.prg_ram, bank, 0x2000, address as usize, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        let chr_address = self.chr_address(address);
+        read_bank(&self.chr, 0, self.chr.len().max(1), chr_address)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            let chr_address = self.chr_address(address);
+            let size = self.chr.len().max(1);
+            write_bank(&mut self.chr, 0, size, chr_address, value);
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn read_nametable(&mut self, address: u16, nametables: &Nametables) -> u8 {
+        let offset = address as usize & 0x3FF;
+        let is_attribute = offset >= 0x3C0;
+
+        if self.background_fetch() && self.in_split {
+            let column = self.tile_column as usize & 0x1F;
+            let row = self.split_y as usize / 8;
+            if !is_attribute {
+                return self.exram[row * 32 + column];
+            }
+            let attribute = self.exram[0x3C0 + (row / 4) * 8 + column / 4];
+            let shift = ((row & 0x02) << 1) | (column & 0x02);
+            return ((attribute >> shift) & 0x03) * 0x55;
+        }
+
+        if self.background_fetch() && self.exram_mode == 1 {
+            // Extended attributes: every tile gets its own 4K CHR bank and palette
+            if !is_attribute {
+                self.ext_bank = self.exram[offset] & 0x3F;
+                self.ext_palette = self.exram[offset] >> 6;
+            } else {
+                return self.ext_palette * 0x55;
+            }
+        }
+
+        let table = (address as usize >> 10) & 0x03;
+        match (self.nametable_mapping >> (table * 2)) & 0x03 {
+            0 => nametables.read_page(0, address),
+            1 => nametables.read_page(1, address),
+            2 => {
+                if self.exram_mode > 1 {
+                    return 0;
+                }
+                self.exram[offset]
+            }
+            _ => {
+                if is_attribute {
+                    return self.fill_attribute * 0x55;
+                }
+                self.fill_tile
+            }
+        }
+    }
+
+    fn write_nametable(&mut self, address: u16, value: u8, nametables: &mut Nametables) {
+        let offset = address as usize & 0x3FF;
+        let table = (address as usize >> 10) & 0x03;
+        match (self.nametable_mapping >> (table * 2)) & 0x03 {
+            0 => nametables.write_page(0, address, value),
+            1 => nametables.write_page(1, address, value),
+            2 if self.exram_mode <= 1 => {
+                self.exram[offset] = value;
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_register_write(&mut self, address: u16, value: u8) {
+        match address & 0x2007 {
+            0x2000 => self.sprite_8x16 = value & 0x20 != 0,
+            0x2001 if value & 0x18 == 0 => {
+                self.in_frame = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_tick(&mut self, scanline: u16, dot: u16, rendering: bool) {
+        let pre_render_line = self.region.pre_render_line();
+        self.rendering = rendering && (scanline < 240 || scanline == pre_render_line);
+        self.fetching_sprites = (257..=320).contains(&dot);
+        match dot {
+            1..=256 => self.tile_column = (dot - 1) / 8 + 2,
+            321..=336 => self.tile_column = (dot - 321) / 8,
+            _ => {}
+        }
+
+        // Tiles fetched at the end of a line belong to the next one
+        let line = if dot >= 321 {
+            (scanline + 1) % self.region.scanlines()
+        } else {
+            scanline
+        };
+        self.split_y = (self.split_scroll as u16 + line.min(239)) % 240;
+        self.in_split = self.split_region();
+
+        if dot != 1 {
+            return;
+        }
+
+        if !rendering || scanline >= 240 {
+            self.in_frame = false;
+            return;
+        }
+
+        if !self.in_frame {
+            self.in_frame = true;
+            self.scanline_counter = 0;
+        } else {
+            self.scanline_counter = self.scanline_counter.wrapping_add(1);
+            if self.scanline_counter == self.irq_compare {
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        self.pulse1.clock_timer();
+        self.pulse2.clock_timer();
+        self.clock_frame();
+    }
+
+    fn irq_pending(&self) -> bool {
+        (self.irq_pending && self.irq_enabled) || (self.pcm_irq_pending && self.pcm_irq_enabled)
+    }
+
+    fn audio_output(&self) -> f32 {
+        // The PCM channel sits at roughly the same level as the APU's DMC
+        pulse_level(self.pulse1.output() + self.pulse2.output()) + self.pcm_value as f32 * 0.00168
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::mappers::test_rom;
+    use crate::cartridge::Mirroring;
+
+    fn mmc5() -> Mmc5 {
+        // 256K of 8K PRG banks, 256K of 1K CHR banks
+        let mut header = test_rom::header(5, 0);
+        header.chr_rom_size = 32;
+        let prg = test_rom::banked(0x40000, 0x2000);
+        let chr = test_rom::banked(0x40000, 0x400);
+        Mmc5::new(&header, prg, chr)
+    }
+
+    fn prg_banks(mapper: &mut Mmc5) -> [u8; 4] {
+        [0x8000, 0xA000, 0xC000, 0xE000].map(|address| mapper.cpu_read(address))
+    }
+
+    #[test]
+    fn prg_banking_modes() {
+        let mut mapper = mmc5();
+        // Mode 3 at power on, with the last bank at $E000
+        assert_eq!(mapper.cpu_read(0xE000), 31);
+
+        mapper.cpu_write(0x5100, 0);
+        mapper.cpu_write(0x5117, 0x05);
+        assert_eq!(prg_banks(&mut mapper), [4, 5, 6, 7]);
+
+        mapper.cpu_write(0x5100, 1);
+        mapper.cpu_write(0x5115, 0x87);
+        mapper.cpu_write(0x5117, 0x0A);
+        assert_eq!(prg_banks(&mut mapper), [6, 7, 10, 11]);
+
+        mapper.cpu_write(0x5100, 2);
+        mapper.cpu_write(0x5116, 0x89);
+        mapper.cpu_write(0x5117, 0x0E);
+        assert_eq!(prg_banks(&mut mapper), [6, 7, 9, 14]);
+
+        mapper.cpu_write(0x5100, 3);
+        for (register, bank) in [(0x5114, 0x81), (0x5115, 0x82), (0x5116, 0x83)] {
+            mapper.cpu_write(register, bank);
+        }
+        assert_eq!(prg_banks(&mut mapper), [1, 2, 3, 14]);
+    }
+
+    #[test]
+    fn prg_ram_in_rom_space_needs_both_unlock_registers() {
+        let mut mapper = mmc5();
+        mapper.cpu_write(0x5114, 0x01); // RAM bank 1 at $8000
+        mapper.cpu_write(0x8000, 0x55);
+        assert_eq!(mapper.cpu_read(0x8000), 0x00);
+
+        mapper.cpu_write(0x5102, 0x02);
+        mapper.cpu_write(0x5103, 0x01);
+        mapper.cpu_write(0x8000, 0x55);
+        assert_eq!(mapper.cpu_read(0x8000), 0x55);
+        // The same RAM bank at $6000
+        mapper.cpu_write(0x5113, 0x01);
+        assert_eq!(mapper.cpu_read(0x6000), 0x55);
+    }
+
+    #[test]
+    fn chr_banking_modes() {
+        let mut mapper = mmc5();
+        let slots =
+            |mapper: &mut Mmc5| [0x0000, 0x0800, 0x1000, 0x1C00].map(|a| mapper.ppu_read(a));
+
+        mapper.cpu_write(0x5101, 0);
+        mapper.cpu_write(0x5127, 2);
+        assert_eq!(slots(&mut mapper), [16, 18, 20, 23]);
+
+        mapper.cpu_write(0x5101, 1);
+        mapper.cpu_write(0x5123, 1);
+        mapper.cpu_write(0x5127, 3);
+        assert_eq!(slots(&mut mapper), [4, 6, 12, 15]);
+
+        mapper.cpu_write(0x5101, 2);
+        mapper.cpu_write(0x5121, 5);
+        mapper.cpu_write(0x5123, 6);
+        assert_eq!(mapper.ppu_read(0x0000), 10);
+        assert_eq!(mapper.ppu_read(0x0800), 12);
+
+        mapper.cpu_write(0x5101, 3);
+        for slot in 0..8 {
+            mapper.cpu_write(0x5120 + slot, 40 + slot as u8);
+        }
+        assert_eq!(slots(&mut mapper), [40, 42, 44, 47]);
+
+        // Outside 8x16 sprites, the last set written to picks the banks
+        mapper.cpu_write(0x5128, 60);
+        assert_eq!(mapper.ppu_read(0x0000), 60);
+        assert_eq!(mapper.ppu_read(0x1000), 60);
+        mapper.cpu_write(0x5120, 40);
+        assert_eq!(mapper.ppu_read(0x0000), 40);
+    }
+
+    #[test]
+    fn exram_modes() {
+        let mut mapper = mmc5();
+        let mut nametables = Nametables::with_mirroring(Mirroring::Vertical);
+
+        // As a nametable it's filled through the PPU; the CPU can only write 0 outside a frame
+        mapper.cpu_write(0x5105, 0x02);
+        mapper.write_nametable(0x2005, 0x42, &mut nametables);
+        assert_eq!(mapper.read_nametable(0x2005, &nametables), 0x42);
+        mapper.cpu_write(0x5C05, 0x99);
+        assert_eq!(mapper.read_nametable(0x2005, &nametables), 0x00);
+        assert_eq!(mapper.cpu_read(0x5C05), 0x00);
+
+        // Plain RAM, then read only
+        mapper.cpu_write(0x5104, 2);
+        mapper.cpu_write(0x5C05, 0x99);
+        assert_eq!(mapper.cpu_read(0x5C05), 0x99);
+        assert_eq!(mapper.read_nametable(0x2005, &nametables), 0x00);
+        mapper.cpu_write(0x5104, 3);
+        mapper.cpu_write(0x5C05, 0x11);
+        assert_eq!(mapper.cpu_read(0x5C05), 0x99);
+    }
+
+    #[test]
+    fn fill_mode() {
+        let mut mapper = mmc5();
+        let nametables = Nametables::with_mirroring(Mirroring::Vertical);
+        mapper.cpu_write(0x5105, 0xFF);
+        mapper.cpu_write(0x5106, 0x33);
+        mapper.cpu_write(0x5107, 0x02);
+        assert_eq!(mapper.read_nametable(0x2C10, &nametables), 0x33);
+        assert_eq!(mapper.read_nametable(0x23C0, &nametables), 0xAA);
+    }
+
+    #[test]
+    fn scanline_irq() {
+        let mut mapper = mmc5();
+        mapper.cpu_write(0x5203, 2);
+        mapper.cpu_write(0x5204, 0x80);
+
+        mapper.ppu_tick(0, 1, true);
+        mapper.ppu_tick(1, 1, true);
+        assert!(!mapper.irq_pending());
+        assert_eq!(mapper.cpu_read(0x5204), 0x40);
+        mapper.ppu_tick(2, 1, true);
+        assert!(mapper.irq_pending());
+
+        // Reading the status acknowledges it
+        assert_eq!(mapper.cpu_read(0x5204), 0xC0);
+        assert!(!mapper.irq_pending());
+
+        // Vblank leaves the frame and restarts the count
+        mapper.ppu_tick(240, 1, true);
+        assert_eq!(mapper.cpu_read(0x5204), 0x00);
+        mapper.ppu_tick(0, 1, true);
+        mapper.ppu_tick(1, 1, true);
+        mapper.ppu_tick(2, 1, true);
+        assert!(mapper.irq_pending());
+    }
+
+    #[test]
+    fn pal_pre_render_line_fetches_line_0() {
+        let mut header = test_rom::header(5, 0);
+        header.chr_rom_size = 32;
+        header.cpu_ppu_timing = crate::cartridge::TIMING_PAL;
+        let prg = test_rom::banked(0x40000, 0x2000);
+        let chr = test_rom::banked(0x40000, 0x400);
+        let mut mapper = Mmc5::new(&header, prg, chr);
+        let nametables = Nametables::with_mirroring(Mirroring::Vertical);
+
+        // Split tiles come from ExRAM rows picked by the line being fetched
+        mapper.cpu_write(0x5104, 2);
+        mapper.cpu_write(0x5C00, 0x11);
+        mapper.cpu_write(0x5C00 + 6 * 32, 0x66);
+        mapper.cpu_write(0x5104, 0);
+        mapper.cpu_write(0x5200, 0x80 | 0x1F);
+
+        mapper.ppu_tick(311, 321, true);
+        assert_eq!(mapper.read_nametable(0x2000, &nametables), 0x11);
+
+        // Line 261 is in PAL's vblank, so nothing is being rendered
+        mapper.ppu_tick(261, 321, true);
+        assert_eq!(mapper.read_nametable(0x2000, &nametables), 0x00);
+    }
+
+    #[test]
+    fn multiplier() {
+        let mut mapper = mmc5();
+        assert_eq!(mapper.cpu_read(0x5205), 0x01);
+        assert_eq!(mapper.cpu_read(0x5206), 0xFE);
+
+        mapper.cpu_write(0x5205, 200);
+        mapper.cpu_write(0x5206, 100);
+        assert_eq!(mapper.cpu_read(0x5205), 0x20);
+        assert_eq!(mapper.cpu_read(0x5206), 0x4E);
+    }
+
+    #[test]
+    fn length_counter_status() {
+        let mut mapper = mmc5();
+        mapper.cpu_write(0x5003, 0x08);
+        assert_eq!(mapper.cpu_read(0x5015), 0x00);
+        mapper.cpu_write(0x5015, 0x01);
+        mapper.cpu_write(0x5003, 0x18); // Length 2
+        assert_eq!(mapper.cpu_read(0x5015), 0x01);
+        for _ in 0..FRAME_PERIOD as usize * 2 {
+            mapper.cpu_clock();
+        }
+        assert_eq!(mapper.cpu_read(0x5015), 0x00);
+    }
+
+    #[test]
+    fn audio_output() {
+        let mut mapper = mmc5();
+        assert_eq!(mapper.audio_output(), 0.0);
+
+        // A constant volume pulse at 50% duty is heard half the time
+        mapper.cpu_write(0x5015, 0x01);
+        mapper.cpu_write(0x5000, 0xBF);
+        mapper.cpu_write(0x5002, 0x10);
+        mapper.cpu_write(0x5003, 0x00);
+        let loud = (0..0x200)
+            .filter(|_| {
+                mapper.cpu_clock();
+                mapper.audio_output() > 0.0
+            })
+            .count();
+        assert!(loud > 0x40 && loud < 0x1C0);
+
+        // PCM in write mode ignores 0, in read mode it takes bytes read from $8000-$BFFF
+        mapper.cpu_write(0x5015, 0x00);
+        mapper.cpu_write(0x5011, 0x80);
+        mapper.cpu_write(0x5011, 0x00);
+        assert_eq!(mapper.audio_output(), 0x80 as f32 * 0.00168);
+        mapper.cpu_write(0x5010, 0x01);
+        mapper.cpu_write(0x5114, 0x83);
+        mapper.cpu_read(0x8000);
+        assert_eq!(mapper.audio_output(), 3.0 * 0.00168);
+    }
+}
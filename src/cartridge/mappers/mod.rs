@@ -0,0 +1,220 @@
+/**
+* Cartridge memory bank controllers (mappers)
+*
+* Every board implements the `Mapper` trait. The CPU hands it every access in $4020-$FFFF and
+* the PPU hands it every pattern table and nametable access, so a mapper sees the same bus
+* traffic the real board would.
+*/
+mod action52;
+mod bnrom;
+mod camerica;
+mod fds;
+mod fme7;
+mod jaleco_jf11;
+mod mmc5;
+mod namco163;
+mod nina03_06;
+mod nrom;
+mod nrom_multicart;
+mod nsf_player;
+mod sachen8259;
+mod vrc2_4;
+mod vrc6;
+mod vrc7;
+mod vrc_irq;
+
+use std::sync::{Arc, Mutex};
+
+use super::{CartridgeHeader, Nametables, RomError};
+pub use action52::Action52;
+pub use bnrom::Bnrom;
+pub use camerica::Camerica;
+pub use fds::Fds;
+pub use fme7::Fme7;
+pub use jaleco_jf11::JalecoJf11;
+pub use mmc5::Mmc5;
+pub use namco163::Namco163;
+pub use nina03_06::Nina03_06;
+pub use nrom::Nrom;
+pub use nrom_multicart::NromMulticart;
+pub use nsf_player::NsfPlayer;
+pub use sachen8259::Sachen8259;
+pub use vrc2_4::Vrc2_4;
+pub use vrc6::Vrc6;
+pub use vrc7::Vrc7;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    SingleScreenA,
+    SingleScreenB,
+    FourScreen,
+}
+
+impl Mirroring {
+    // Offset into nametable memory for a nametable address: the console's 2K of CIRAM, or 4K
+    // including the cart's own RAM for four-screen boards
+    pub fn vram_offset(self, address: u16) -> usize {
+        let address = address as usize & 0xFFF;
+        match self {
+            Mirroring::Horizontal => ((address >> 1) & 0x400) | (address & 0x3FF),
+            Mirroring::Vertical => address & 0x7FF,
+            Mirroring::SingleScreenA => address & 0x3FF,
+            Mirroring::SingleScreenB => 0x400 | (address & 0x3FF),
+            Mirroring::FourScreen => address,
+        }
+    }
+}
+
+pub trait Mapper: Send {
+    // CPU bus, $4020-$FFFF
+    fn cpu_read(&mut self, address: u16) -> u8;
+    fn cpu_write(&mut self, address: u16, value: u8);
+
+    // PPU bus, pattern tables at $0000-$1FFF
+    fn ppu_read(&mut self, address: u16) -> u8;
+    fn ppu_write(&mut self, address: u16, value: u8);
+
+    // Work RAM at $6000-$7FFF as one flat buffer, for loading trainers and battery saves
+    fn prg_ram(&self) -> &[u8] {
+        &[]
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+
+    // Nametable arrangement for boards that switch between the standard layouts, or None when
+    // it's soldered and comes from the header
+    fn mirroring(&self) -> Option<Mirroring> {
+        None
+    }
+
+    // PPU bus, nametables at $2000-$2FFF
+    fn read_nametable(&mut self, address: u16, nametables: &Nametables) -> u8 {
+        nametables.read(self.mirroring(), address)
+    }
+
+    fn write_nametable(&mut self, address: u16, value: u8, nametables: &mut Nametables) {
+        nametables.write(self.mirroring(), address, value);
+    }
+
+    // PPU bus, pattern tables, for boards that can map CIRAM there too
+    fn read_pattern(&mut self, address: u16, _nametables: &Nametables) -> u8 {
+        self.ppu_read(address)
+    }
+
+    fn write_pattern(&mut self, address: u16, value: u8, _nametables: &mut Nametables) {
+        self.ppu_write(address, value);
+    }
+
+    // Called for every CPU write to $2000-$2007, for boards that snoop the PPU registers
+    fn ppu_register_write(&mut self, _address: u16, _value: u8) {}
+
+    // Called once per PPU dot, before any fetch the PPU makes on that dot
+    fn ppu_tick(&mut self, _scanline: u16, _dot: u16, _rendering: bool) {}
+
+    // Called once per CPU cycle
+    fn cpu_clock(&mut self) {}
+
+    // Level of the cartridge /IRQ line
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    // Expansion audio at the current CPU cycle, mixed in by the APU
+    fn audio_output(&self) -> f32 {
+        0.0
+    }
+}
+
+pub fn new_mapper(
+    header: &CartridgeHeader,
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+) -> Result<Arc<Mutex<dyn Mapper>>, RomError> {
+    let mapper: Arc<Mutex<dyn Mapper>> = match header.mapper {
+        0 => Arc::new(Mutex::new(Nrom::new(header, prg_rom, chr_rom))),
+        5 => Arc::new(Mutex::new(Mmc5::new(header, prg_rom, chr_rom))),
+        19 => Arc::new(Mutex::new(Namco163::new(header, prg_rom, chr_rom))),
+        21 | 22 | 23 | 25 => Arc::new(Mutex::new(Vrc2_4::new(header, prg_rom, chr_rom))),
+        24 | 26 => Arc::new(Mutex::new(Vrc6::new(header, prg_rom, chr_rom))),
+        34 => Arc::new(Mutex::new(Bnrom::new(header, prg_rom, chr_rom))),
+        69 => Arc::new(Mutex::new(Fme7::new(header, prg_rom, chr_rom))),
+        71 => Arc::new(Mutex::new(Camerica::new(header, prg_rom, chr_rom))),
+        79 => Arc::new(Mutex::new(Nina03_06::new(header, prg_rom, chr_rom))),
+        85 => Arc::new(Mutex::new(Vrc7::new(header, prg_rom, chr_rom))),
+        137..=139 | 141 => Arc::new(Mutex::new(Sachen8259::new(header, prg_rom, chr_rom))),
+        140 => Arc::new(Mutex::new(JalecoJf11::new(header, prg_rom, chr_rom))),
+        225..=227 => Arc::new(Mutex::new(NromMulticart::new(header, prg_rom, chr_rom))),
+        228 => Arc::new(Mutex::new(Action52::new(header, prg_rom, chr_rom))),
+        _ => {
+            return Err(RomError::UnsupportedMapper {
+                mapper: header.mapper,
+                submapper: header.submapper,
+            })
+        }
+    };
+    Ok(mapper)
+}
+
+// Reads `offset` inside bank number `bank` of `bank_size` bytes, wrapping banks past the end of memory
+pub fn read_bank(memory: &[u8], bank: usize, bank_size: usize, offset: usize) -> u8 {
+    if memory.is_empty() {
+        return 0;
+    }
+
+    let bank_count = (memory.len() / bank_size).max(1);
+    let address = (bank % bank_count) * bank_size + (offset % bank_size);
+    memory[address % memory.len()]
+}
+
+pub fn write_bank(memory: &mut [u8], bank: usize, bank_size: usize, offset: usize, value: u8) {
+    if memory.is_empty() {
+        return;
+    }
+
+    let bank_count = (memory.len() / bank_size).max(1);
+    let address = (bank % bank_count) * bank_size + (offset % bank_size);
+    let len = memory.len();
+    memory[address % len] = value;
+}
+
+// Helpers for building synthetic carts in mapper tests
+#[cfg(test)]
+pub mod test_rom {
+    use crate::cartridge::CartridgeHeader;
+
+    pub fn header(mapper: u16, submapper: u8) -> CartridgeHeader {
+        CartridgeHeader {
+            ines: true,
+            nes2: true,
+            prg_rom_size: 0,
+            chr_rom_size: 0,
+            flags: 0,
+            mapper,
+            submapper,
+            prg_msb_rom_size: 0,
+            chr_msb_rom_size: 0,
+            prg_ram_size: 0,
+            prg_nvram_size: 0,
+            chr_ram_size: 0,
+            chr_nvram_size: 0,
+            cpu_ppu_timing: 0,
+            console_type: 0,
+            is_vs_unisystem: false,
+            vs_unisystem: 0,
+            is_extended_console: false,
+            extended_console: 0,
+            misc_roms: 0,
+            default_expansion_device: 0,
+            single_screen: None,
+        }
+    }
+
+    // `size` bytes where every byte holds the number of the `bank_size` bank it sits in
+    pub fn banked(size: usize, bank_size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i / bank_size) as u8).collect()
+    }
+}
@@ -0,0 +1,293 @@
+/**
+* Namco 129/163 (mapper 19)
+*
+* Eight 1K CHR banks plus four nametable banks, each of which can point at CHR ROM or at one
+* of the console's two CIRAM pages (bank numbers $E0 and up). The chip also carries 128 bytes
+* of internal RAM, used by the wavetable sound unit and accessed through $4800/$F800, and a
+* 15-bit CPU cycle IRQ counter.
+*/
+use super::{read_bank, write_bank, Mapper};
+use crate::cartridge::{CartridgeHeader, Nametables};
+
+pub struct Namco163 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+    prg_ram: Vec<u8>,
+    internal_ram: [u8; 0x80],
+
+    chr_banks: [u8; 8],
+    nametable_banks: [u8; 4],
+    prg_banks: [u8; 3],
+    // $E800 bits 6 and 7: use CHR ROM even for bank numbers >= $E0
+    chr_ram_disabled: [bool; 2],
+    // $F800: internal RAM address and auto-increment, plus PRG RAM write protection
+    ram_address: u8,
+    ram_auto_increment: bool,
+    write_protect: u8,
+
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl Namco163 {
+    pub fn new(header: &CartridgeHeader, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Namco163 {
+        Namco163 {
+            prg_rom,
+            chr: chr_rom,
+            chr_writable: header.chr_rom_bytes() == 0,
+            prg_ram: vec![0; header.prg_ram_bytes(0x2000)],
+            internal_ram: [0; 0x80],
+
+            chr_banks: [0; 8],
+            nametable_banks: [0xE0, 0xE1, 0xE0, 0xE1],
+            prg_banks: [0; 3],
+            chr_ram_disabled: [false; 2],
+            ram_address: 0,
+            ram_auto_increment: false,
+            write_protect: 0xFF,
+
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    // Each 2K quarter of PRG RAM has its own write-enable bit, behind the $4x key in the top nibble
+    fn prg_ram_writable(&self, address: u16) -> bool {
+        let section = (address as usize - 0x6000) / 0x800;
+        self.write_protect & 0xF0 == 0x40 && self.write_protect & (1 << section) == 0
+    }
+
+    fn nametable_bank(&self, address: u16) -> u8 {
+        self.nametable_banks[(address as usize >> 10) & 0x03]
+    }
+
+    // CIRAM page for pattern table banks >= $E0, unless $E800 forces CHR ROM for that half
+    fn ciram_page(&self, address: u16) -> Option<usize> {
+        let bank = self.chr_banks[address as usize / 0x400];
+        if bank >= 0xE0 && !self.chr_ram_disabled[address as usize / 0x1000] {
+            return Some(bank as usize & 0x01);
+        }
+        None
+    }
+}
+
+impl Mapper for Namco163 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x4800..=0x4FFF => {
+                let value = self.internal_ram[self.ram_address as usize];
+                if self.ram_auto_increment {
+                    self.ram_address = (self.ram_address + 1) & 0x7F;
+                }
+                value
+            }
+            0x5000..=0x57FF => self.irq_counter as u8,
+            0x5800..=0x5FFF => (self.irq_counter >> 8) as u8 | (self.irq_enabled as u8) << 7,
+            0x6000..=0x7FFF => read_bank(&self.prg_ram, 0, 0x2000, address as usize),
+            0x8000..=0xDFFF => {
+                let bank = self.prg_banks[(address as usize - 0x8000) / 0x2000];
+                read_bank(&self.prg_rom, bank as usize, 0x2000, address as usize)
+            }
+            0xE000..=0xFFFF => {
+                let last = (self.prg_rom.len() / 0x2000).saturating_sub(1);
+                read_bank(&self.prg_rom, last, 0x2000, address as usize)
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x4800..=0x4FFF => {
+                self.internal_ram[self.ram_address as usize] = value;
+                if self.ram_auto_increment {
+                    self.ram_address = (self.ram_address + 1) & 0x7F;
+                }
+            }
+            0x5000..=0x57FF => {
+                self.irq_counter = (self.irq_counter & 0x7F00) | value as u16;
+                self.irq_pending = false;
+            }
+            0x5800..=0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | (value as u16 & 0x7F) << 8;
+                self.irq_enabled = value & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0x6000..=0x7FFF if self.prg_ram_writable(address) => {
+                write_bank(&mut self.prg_ram, 0, 0x2000, address as usize, value);
+            }
+            0x8000..=0xBFFF => self.chr_banks[(address as usize - 0x8000) / 0x800] = value,
+            0xC000..=0xDFFF => {
+                self.nametable_banks[(address as usize - 0xC000) / 0x800] = value;
+            }
+            0xE000..=0xE7FF => self.prg_banks[0] = value & 0x3F,
+            0xE800..=0xEFFF => {
+                self.prg_banks[1] = value & 0x3F;
+                self.chr_ram_disabled = [value & 0x40 != 0, value & 0x80 != 0];
+            }
+            0xF000..=0xF7FF => self.prg_banks[2] = value & 0x3F,
+            0xF800..=0xFFFF => {
+                self.ram_address = value & 0x7F;
+                self.ram_auto_increment = value & 0x80 != 0;
+                self.write_protect = value;
+            }
+            _ => {}
+        }
+    }
+
+    // Without the console's CIRAM at hand, banks mapped to it read as 0 and ignore writes
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        if self.ciram_page(address).is_some() {
+            return 0;
+        }
+        let bank = self.chr_banks[address as usize / 0x400];
+        read_bank(&self.chr, bank as usize, 0x400, address as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        if self.chr_writable && self.ciram_page(address).is_none() {
+            let bank = self.chr_banks[address as usize / 0x400];
+            write_bank(&mut self.chr, bank as usize, 0x400, address as usize, value);
+        }
+    }
+
+    fn read_pattern(&mut self, address: u16, nametables: &Nametables) -> u8 {
+        match self.ciram_page(address) {
+            Some(page) => nametables.read_page(page, address),
+            None => self.ppu_read(address),
+        }
+    }
+
+    fn write_pattern(&mut self, address: u16, value: u8, nametables: &mut Nametables) {
+        match self.ciram_page(address) {
+            Some(page) => nametables.write_page(page, address, value),
+            None => self.ppu_write(address, value),
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn read_nametable(&mut self, address: u16, nametables: &Nametables) -> u8 {
+        let bank = self.nametable_bank(address);
+        let offset = address as usize & 0x3FF;
+        if bank >= 0xE0 {
+            return nametables.read_page(bank as usize & 0x01, address);
+        }
+        read_bank(&self.chr, bank as usize, 0x400, offset)
+    }
+
+    fn write_nametable(&mut self, address: u16, value: u8, nametables: &mut Nametables) {
+        let bank = self.nametable_bank(address);
+        if bank >= 0xE0 {
+            nametables.write_page(bank as usize & 0x01, address, value);
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        if !self.irq_enabled || self.irq_counter == 0x7FFF {
+            return;
+        }
+
+        self.irq_counter += 1;
+        if self.irq_counter == 0x7FFF {
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::mappers::test_rom;
+    use crate::cartridge::Mirroring;
+
+    fn namco163() -> Namco163 {
+        let mut header = test_rom::header(19, 0);
+        header.chr_rom_size = 32;
+        let prg = test_rom::banked(0x20000, 0x2000);
+        let chr = test_rom::banked(0x40000, 0x400);
+        Namco163::new(&header, prg, chr)
+    }
+
+    #[test]
+    fn prg_and_chr_banking() {
+        let mut mapper = namco163();
+        mapper.cpu_write(0xE000, 3);
+        mapper.cpu_write(0xE800, 4);
+        mapper.cpu_write(0xF000, 5);
+        let banks = [0x8000, 0xA000, 0xC000, 0xE000].map(|address| mapper.cpu_read(address));
+        assert_eq!(banks, [3, 4, 5, 15]);
+
+        for slot in 0..8 {
+            mapper.cpu_write(0x8000 + slot * 0x800, 20 + slot as u8);
+        }
+        assert_eq!(mapper.ppu_read(0x0000), 20);
+        assert_eq!(mapper.ppu_read(0x1C00), 27);
+    }
+
+    #[test]
+    fn ciram_in_pattern_and_nametable_banks() {
+        let mut mapper = namco163();
+        let mut nametables = Nametables::with_mirroring(Mirroring::Vertical);
+
+        // $E1 maps CIRAM page 1 into the first pattern table slot
+        mapper.cpu_write(0x8000, 0xE1);
+        mapper.write_pattern(0x0010, 0x5A, &mut nametables);
+        assert_eq!(nametables.read_page(1, 0x0010), 0x5A);
+        assert_eq!(mapper.read_pattern(0x0010, &nametables), 0x5A);
+        assert_eq!(mapper.read_nametable(0x2410, &nametables), 0x5A);
+
+        // $E800 bit 6 forces CHR ROM for the lower pattern table
+        mapper.cpu_write(0xE800, 0x40);
+        assert_eq!(mapper.read_pattern(0x0010, &nametables), 0xE1);
+
+        // Nametable banks below $E0 read CHR ROM
+        mapper.cpu_write(0xC000, 7);
+        assert_eq!(mapper.read_nametable(0x2000, &nametables), 7);
+    }
+
+    #[test]
+    fn irq_counter() {
+        let mut mapper = namco163();
+        mapper.cpu_write(0x5000, 0xFD);
+        mapper.cpu_write(0x5800, 0xFF);
+        assert_eq!(mapper.cpu_read(0x5800), 0xFF);
+
+        mapper.cpu_clock();
+        assert!(!mapper.irq_pending());
+        mapper.cpu_clock();
+        assert!(mapper.irq_pending());
+        // Stops at $7FFF
+        mapper.cpu_clock();
+        assert_eq!(mapper.cpu_read(0x5000), 0xFF);
+
+        // Writing either counter byte acknowledges it
+        mapper.cpu_write(0x5000, 0x00);
+        assert!(!mapper.irq_pending());
+    }
+
+    #[test]
+    fn internal_ram_auto_increments() {
+        let mut mapper = namco163();
+        mapper.cpu_write(0xF800, 0x80 | 0x7F);
+        mapper.cpu_write(0x4800, 0x11);
+        mapper.cpu_write(0x4800, 0x22);
+        mapper.cpu_write(0xF800, 0x7F);
+        assert_eq!(mapper.cpu_read(0x4800), 0x11);
+        mapper.cpu_write(0xF800, 0x00);
+        assert_eq!(mapper.cpu_read(0x4800), 0x22);
+    }
+}
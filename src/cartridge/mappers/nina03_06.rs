@@ -0,0 +1,93 @@
+/**
+* AVE NINA-03 and NINA-06 (mapper 79)
+*
+* A single register decoded at $4100-$5FFF whenever A8 is set: bit 3 selects the 32K PRG bank
+* and bits 0-2 the 8K CHR bank.
+*/
+use super::{read_bank, write_bank, Mapper};
+use crate::cartridge::CartridgeHeader;
+
+pub struct Nina03_06 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+
+    prg_bank: u8,
+    chr_bank: u8,
+}
+
+impl Nina03_06 {
+    pub fn new(header: &CartridgeHeader, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Nina03_06 {
+        Nina03_06 {
+            prg_rom,
+            chr: chr_rom,
+            chr_writable: header.chr_rom_bytes() == 0,
+
+            prg_bank: 0,
+            chr_bank: 0,
+        }
+    }
+}
+
+impl Mapper for Nina03_06 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x8000..=0xFFFF => read_bank(
+                &self.prg_rom,
+                self.prg_bank as usize,
+                0x8000,
+                address as usize,
+            ),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if address & 0xE100 == 0x4100 {
+            self.prg_bank = (value >> 3) & 0x01;
+            self.chr_bank = value & 0x07;
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        read_bank(&self.chr, self.chr_bank as usize, 0x2000, address as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            write_bank(
+                &mut self.chr,
+                self.chr_bank as usize,
+                0x2000,
+                address as usize,
+                value,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::mappers::test_rom;
+
+    #[test]
+    fn register_decodes_on_a8() {
+        let prg = test_rom::banked(0x10000, 0x8000);
+        let chr = test_rom::banked(0x10000, 0x2000);
+        let mut mapper = Nina03_06::new(&test_rom::header(79, 0), prg, chr);
+
+        mapper.cpu_write(0x4100, 0x0D);
+        assert_eq!(mapper.cpu_read(0x8000), 1);
+        assert_eq!(mapper.ppu_read(0x0000), 5);
+
+        // A8 clear, not the register
+        mapper.cpu_write(0x4200, 0x00);
+        assert_eq!(mapper.cpu_read(0x8000), 1);
+
+        // Mirrored throughout $4100-$5FFF
+        mapper.cpu_write(0x5F00, 0x02);
+        assert_eq!(mapper.cpu_read(0x8000), 0);
+        assert_eq!(mapper.ppu_read(0x1FFF), 2);
+    }
+}
@@ -0,0 +1,58 @@
+/**
+* NROM (mapper 0): no bank switching, 16K or 32K of PRG ROM and 8K of CHR
+*/
+use super::{read_bank, write_bank, Mapper};
+use crate::cartridge::CartridgeHeader;
+
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+    prg_ram: Vec<u8>,
+}
+
+impl Nrom {
+    pub fn new(header: &CartridgeHeader, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Nrom {
+        Nrom {
+            prg_rom,
+            chr: chr_rom,
+            chr_writable: header.chr_rom_bytes() == 0,
+            prg_ram: vec![0; 0x2000],
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF => self.prg_ram[address as usize - 0x6000],
+            // 16K carts mirror their only bank into $C000-$FFFF
+            0x8000..=0xFFFF => read_bank(&self.prg_rom, 0, 0x8000, address as usize - 0x8000),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if let 0x6000..=0x7FFF = address {
+            self.prg_ram[address as usize - 0x6000] = value;
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        read_bank(&self.chr, 0, 0x2000, address as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            write_bank(&mut self.chr, 0, 0x2000, address as usize, value);
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+}
@@ -0,0 +1,217 @@
+/**
+* Generic NROM multicarts: mappers 225, 226 and 227
+*
+* These boards pick one NROM game out of a large ROM with latches written to $8000-$FFFF, in
+* 16K or 32K PRG mode.
+*   225 (64-in-1 and similar): everything comes from the written address, plus 4 nibbles of RAM
+*   226 (76-in-1 and similar): two data registers at even/odd addresses, CHR RAM
+*   227 (1200-in-1 and similar): address latch, with an UNROM-like mode, CHR RAM
+*/
+use super::{read_bank, write_bank, Mapper, Mirroring};
+use crate::cartridge::CartridgeHeader;
+
+pub struct NromMulticart {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+
+    mapper: u16,
+    registers: [u8; 2],
+    prg_banks: [usize; 2],
+    chr_bank: usize,
+    mirroring: Mirroring,
+    ram: [u8; 4],
+}
+
+impl NromMulticart {
+    pub fn new(header: &CartridgeHeader, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> NromMulticart {
+        NromMulticart {
+            prg_rom,
+            chr: chr_rom,
+            chr_writable: header.chr_rom_bytes() == 0,
+
+            mapper: header.mapper,
+            registers: [0; 2],
+            prg_banks: [0, 1],
+            chr_bank: 0,
+            mirroring: Mirroring::Vertical,
+            ram: [0; 4],
+        }
+    }
+
+    fn write_225(&mut self, address: usize) {
+        let high = (address >> 8) & 0x40;
+        let prg = high | ((address >> 6) & 0x3F);
+
+        self.prg_banks = if address & 0x1000 != 0 {
+            [prg, prg]
+        } else {
+            [prg & !1, prg | 1]
+        };
+        self.chr_bank = high | (address & 0x3F);
+        self.mirroring = if address & 0x2000 != 0 {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        };
+    }
+
+    fn write_226(&mut self, address: usize, value: u8) {
+        self.registers[address & 0x01] = value;
+
+        let low = self.registers[0] as usize;
+        let prg = (low & 0x1F) | ((low & 0x80) >> 2) | ((self.registers[1] as usize & 0x01) << 6);
+        self.prg_banks = if low & 0x20 != 0 {
+            [prg, prg]
+        } else {
+            [prg & !1, prg | 1]
+        };
+        self.mirroring = if low & 0x40 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+    }
+
+    fn write_227(&mut self, address: usize) {
+        let prg = ((address >> 2) & 0x1F) | ((address & 0x100) >> 3);
+        let size_32k = address & 0x01 != 0;
+        let last_bank = address & 0x200 != 0;
+        let nrom_mode = address & 0x80 != 0;
+
+        self.prg_banks = match (nrom_mode, size_32k) {
+            (true, true) => [prg & !1, prg | 1],
+            (true, false) => [prg, prg],
+            // UNROM-like: $C000 is fixed to the first or last bank of the 128K block
+            (false, _) => {
+                let first = if size_32k { prg & 0x3E } else { prg };
+                let fixed = if last_bank { prg | 0x07 } else { prg & 0x38 };
+                [first, fixed]
+            }
+        };
+        self.mirroring = if address & 0x02 != 0 {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        };
+    }
+}
+
+impl Mapper for NromMulticart {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x5800..=0x5FFF if self.mapper == 225 => self.ram[address as usize & 0x03] & 0x0F,
+            0x8000..=0xFFFF => {
+                let bank = self.prg_banks[(address as usize - 0x8000) / 0x4000];
+                read_bank(&self.prg_rom, bank, 0x4000, address as usize)
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match (self.mapper, address) {
+            (225, 0x5800..=0x5FFF) => self.ram[address as usize & 0x03] = value & 0x0F,
+            (225, 0x8000..=0xFFFF) => self.write_225(address as usize),
+            (226, 0x8000..=0xFFFF) => self.write_226(address as usize, value),
+            (227, 0x8000..=0xFFFF) => self.write_227(address as usize),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        read_bank(&self.chr, self.chr_bank, 0x2000, address as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            write_bank(
+                &mut self.chr,
+                self.chr_bank,
+                0x2000,
+                address as usize,
+                value,
+            );
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::mappers::test_rom;
+
+    fn multicart(mapper: u16, chr: Vec<u8>) -> NromMulticart {
+        let prg = test_rom::banked(0x200000, 0x4000);
+        NromMulticart::new(&test_rom::header(mapper, 0), prg, chr)
+    }
+
+    #[test]
+    fn mapper_225_decodes_address_latch() {
+        let chr = test_rom::banked(0x100000, 0x2000);
+        let mut mapper = multicart(225, chr);
+
+        // 16K mode, PRG 9, CHR 3, horizontal
+        mapper.cpu_write(0x8000 | 0x2000 | 0x1000 | (9 << 6) | 3, 0);
+        assert_eq!(mapper.cpu_read(0x8000), 9);
+        assert_eq!(mapper.cpu_read(0xC000), 9);
+        assert_eq!(mapper.ppu_read(0x0000), 3);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+
+        // 32K mode with the A14 high bit set
+        mapper.cpu_write(0xC000 | (9 << 6), 0);
+        assert_eq!(mapper.cpu_read(0x8000), 0x48);
+        assert_eq!(mapper.cpu_read(0xC000), 0x49);
+        assert_eq!(mapper.ppu_read(0x0000), 0x40);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Vertical));
+
+        mapper.cpu_write(0x5802, 0x3C);
+        assert_eq!(mapper.cpu_read(0x5802), 0x0C);
+    }
+
+    #[test]
+    fn mapper_226_combines_two_registers() {
+        let mut mapper = multicart(226, vec![]);
+
+        // 16K mode, low bits 3, bit 5 from D7, bit 6 from the second register
+        mapper.cpu_write(0x8001, 0x01);
+        mapper.cpu_write(0x8000, 0x80 | 0x40 | 0x20 | 0x03);
+        assert_eq!(mapper.cpu_read(0x8000), 0x63);
+        assert_eq!(mapper.cpu_read(0xC000), 0x63);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Vertical));
+
+        mapper.cpu_write(0x8000, 0x03);
+        assert_eq!(mapper.cpu_read(0x8000), 0x42);
+        assert_eq!(mapper.cpu_read(0xC000), 0x43);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+    }
+
+    #[test]
+    fn mapper_227_nrom_and_unrom_modes() {
+        let mut mapper = multicart(227, vec![]);
+
+        // NROM-256 at PRG 10/11
+        mapper.cpu_write(0x8000 | 0x80 | (10 << 2) | 0x01, 0);
+        assert_eq!(mapper.cpu_read(0x8000), 10);
+        assert_eq!(mapper.cpu_read(0xC000), 11);
+
+        // NROM-128 at PRG 10
+        mapper.cpu_write(0x8000 | 0x80 | (10 << 2) | 0x02, 0);
+        assert_eq!(mapper.cpu_read(0x8000), 10);
+        assert_eq!(mapper.cpu_read(0xC000), 10);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+
+        // UNROM-like with the last bank of the block fixed
+        mapper.cpu_write(0x8000 | 0x200 | (10 << 2), 0);
+        assert_eq!(mapper.cpu_read(0x8000), 10);
+        assert_eq!(mapper.cpu_read(0xC000), 15);
+
+        // ... and with the first bank of the block fixed
+        mapper.cpu_write(0x8000 | (10 << 2), 0);
+        assert_eq!(mapper.cpu_read(0xC000), 8);
+    }
+}
@@ -0,0 +1,328 @@
+/**
+* NSF player
+*
+* Plays an NSF as if it were a cartridge. A small driver program at $4100 is what the reset
+* vector points to: it clears RAM, silences the APU, calls INIT with the track in A and the
+* region in X, then polls $41F2 and calls PLAY whenever the PLAY timer has expired. The driver's
+* registers:
+*   $41F0: current track (reading it also restores the initial banks and clears $6000-$7FFF)
+*   $41F1: 0 for NTSC, 1 for PAL (and Dendy, which plays at PAL's rate)
+*   $41F2: $01 when PLAY is due, $80 after a track change to restart the driver
+* Bankswitched tunes map 4K banks into $8000-$FFFF through $5FF8-$5FFF, everything else is loaded
+* at its load address. Expansion audio registers are accepted and ignored.
+*/
+use super::Mapper;
+use crate::cartridge::Nsf;
+use crate::region::Region;
+
+const DRIVER: u16 = 0x4100;
+const TRACK: u16 = 0x41F0;
+const REGION: u16 = 0x41F1;
+const STATUS: u16 = 0x41F2;
+
+pub struct NsfPlayer {
+    nsf: Nsf,
+    pal: bool,
+    driver: Vec<u8>,
+    rom: Vec<u8>,
+    banks: [u8; 8],
+    prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+
+    track: usize,
+    restart: bool,
+    play_due: bool,
+    play_period: u64,
+    play_counter: u64,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: Nsf, region: Region) -> NsfPlayer {
+        let pal = region != Region::Ntsc;
+        let speed = if pal { nsf.pal_speed } else { nsf.ntsc_speed };
+        let cpu_hz = region.cpu_hz() as u64;
+
+        // Bankswitched data starts at the load address's offset into its bank; anything else is
+        // laid out from $8000
+        let padding = if nsf.is_bankswitched() {
+            nsf.load_address as usize & 0x0FFF
+        } else {
+            nsf.load_address as usize - 0x8000
+        };
+        let mut rom = vec![0; padding];
+        rom.extend_from_slice(&nsf.data);
+        rom.resize(rom.len().max(0x8000).next_multiple_of(0x1000), 0);
+
+        NsfPlayer {
+            driver: driver(nsf.init_address, nsf.play_address),
+            rom,
+            banks: initial_banks(&nsf),
+            prg_ram: vec![0; 0x2000],
+            chr_ram: vec![0; 0x2000],
+
+            track: nsf.starting_song.min(nsf.song_count.saturating_sub(1)),
+            restart: false,
+            play_due: false,
+            play_period: (speed as u64 * cpu_hz / 1_000_000).max(1),
+            play_counter: 0,
+
+            pal,
+            nsf,
+        }
+    }
+
+    pub fn nsf(&self) -> &Nsf {
+        &self.nsf
+    }
+
+    pub fn track(&self) -> usize {
+        self.track
+    }
+
+    // Restarts the driver on `track`, as soon as the current INIT or PLAY call returns
+    pub fn select_track(&mut self, track: usize) {
+        if track < self.nsf.song_count {
+            self.track = track;
+            self.restart = true;
+        }
+    }
+
+    fn read_rom(&self, address: u16) -> u8 {
+        let window = (address as usize - 0x8000) / 0x1000;
+        let bank = self.banks[window] as usize % (self.rom.len() / 0x1000);
+        self.rom[bank * 0x1000 + (address as usize & 0x0FFF)]
+    }
+}
+
+impl Mapper for NsfPlayer {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            TRACK => {
+                self.banks = initial_banks(&self.nsf);
+                self.prg_ram.fill(0);
+                self.restart = false;
+                self.play_due = false;
+                self.play_counter = 0;
+                self.track as u8
+            }
+            REGION => self.pal as u8,
+            STATUS => {
+                if self.restart {
+                    return 0x80;
+                }
+                let status = self.play_due as u8;
+                self.play_due = false;
+                status
+            }
+            0x4100..=0x41EF => self
+                .driver
+                .get((address - DRIVER) as usize)
+                .copied()
+                .unwrap_or(0),
+            0x6000..=0x7FFF => self.prg_ram[address as usize - 0x6000],
+            // NMI and IRQ return straight away, reset starts the driver
+            0xFFFA..=0xFFFF => {
+                let rti = DRIVER + self.driver.len() as u16 - 1;
+                let vector = if address & 0x06 == 0x04 { DRIVER } else { rti };
+                if address & 0x01 == 0 {
+                    vector as u8
+                } else {
+                    (vector >> 8) as u8
+                }
+            }
+            0x8000..=0xFFF9 => self.read_rom(address),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x5FF8..=0x5FFF => self.banks[address as usize - 0x5FF8] = value,
+            0x6000..=0x7FFF => self.prg_ram[address as usize - 0x6000] = value,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        self.chr_ram[address as usize & 0x1FFF]
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        self.chr_ram[address as usize & 0x1FFF] = value;
+    }
+
+    fn cpu_clock(&mut self) {
+        self.play_counter += 1;
+        if self.play_counter >= self.play_period {
+            self.play_counter = 0;
+            self.play_due = true;
+        }
+    }
+}
+
+fn initial_banks(nsf: &Nsf) -> [u8; 8] {
+    if nsf.is_bankswitched() {
+        return nsf.banks;
+    }
+    [0, 1, 2, 3, 4, 5, 6, 7]
+}
+
+fn driver(init: u16, play: u16) -> Vec<u8> {
+    let [init_low, init_high] = init.to_le_bytes();
+    let [play_low, play_high] = play.to_le_bytes();
+    let mut code = vec![
+        0x78, // $4100 SEI
+        0xD8, //       CLD
+        0xA2, 0xFF, // LDX #$FF
+        0x9A, //       TXS
+        0xA9, 0x00, // LDA #$00
+        0xAA, //       TAX
+    ];
+    // $4108: clear $0000-$07FF
+    for page in 0..8 {
+        code.extend_from_slice(&[0x9D, 0x00, page]); // STA $pp00,X
+    }
+    code.extend_from_slice(&[
+        0xE8, //       INX
+        0xD0, 0xE5, // BNE $4108
+        // Silence the APU
+        0xA2, 0x13, // LDX #$13
+        0x9D, 0x00, 0x40, // $4125 STA $4000,X
+        0xCA, //       DEX
+        0x10, 0xFA, // BPL $4125
+        0x8D, 0x15, 0x40, // STA $4015
+        0xA9, 0x0F, // LDA #$0F
+        0x8D, 0x15, 0x40, // STA $4015
+        0xA9, 0x40, // LDA #$40
+        0x8D, 0x17, 0x40, // STA $4017
+        // INIT
+        0xAE, 0xF1, 0x41, // LDX $41F1
+        0xAD, 0xF0, 0x41, // LDA $41F0
+        0x20, init_low, init_high, // JSR INIT
+        // $4141: wait for PLAY
+        0xAD, 0xF2, 0x41, // LDA $41F2
+        0x30, 0xBA, // BMI $4100
+        0xF0, 0xF9, // BEQ $4141
+        0x20, play_low, play_high, // JSR PLAY
+        0x4C, 0x41, 0x41, // JMP $4141
+        // $414E: NMI and IRQ
+        0x40, //       RTI
+    ]);
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nsf(banks: [u8; 8]) -> Nsf {
+        Nsf {
+            song_count: 4,
+            starting_song: 2,
+            load_address: 0x8100,
+            init_address: 0x8100,
+            play_address: 0x8103,
+            ntsc_speed: 1000,
+            pal_speed: 20000,
+            banks,
+            data: (0..0x3000).map(|i| (i / 0x1000) as u8 + 1).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn word(player: &mut NsfPlayer, address: u16) -> u16 {
+        u16::from_le_bytes([player.cpu_read(address), player.cpu_read(address + 1)])
+    }
+
+    #[test]
+    fn driver_layout() {
+        let mut player = NsfPlayer::new(nsf([0; 8]), Region::Ntsc);
+        assert_eq!(word(&mut player, 0xFFFC), 0x4100);
+        assert_eq!(word(&mut player, 0xFFFA), 0x414E);
+        assert_eq!(player.cpu_read(0x414E), 0x40);
+        // JSR INIT and JSR PLAY land where the branches expect them
+        assert_eq!(player.cpu_read(0x413E), 0x20);
+        assert_eq!(word(&mut player, 0x413F), 0x8100);
+        assert_eq!(player.cpu_read(0x4141), 0xAD);
+        assert_eq!(word(&mut player, 0x4149), 0x8103);
+        assert_eq!(player.cpu_read(0x4125), 0x9D);
+    }
+
+    // Where the relative branch at address goes when taken
+    fn branch_target(player: &mut NsfPlayer, address: u16) -> u16 {
+        let offset = player.cpu_read(address + 1) as i8;
+        (address + 2).wrapping_add_signed(offset as i16)
+    }
+
+    #[test]
+    fn driver_branches() {
+        let mut player = NsfPlayer::new(nsf([0; 8]), Region::Ntsc);
+        // RAM clear and APU silencing loops
+        assert_eq!(player.cpu_read(0x4121), 0xD0);
+        assert_eq!(branch_target(&mut player, 0x4121), 0x4108);
+        assert_eq!(player.cpu_read(0x4129), 0x10);
+        assert_eq!(branch_target(&mut player, 0x4129), 0x4125);
+        // Idle loop: restart on $80, poll $41F2 again on $00
+        assert_eq!(player.cpu_read(0x4144), 0x30);
+        assert_eq!(branch_target(&mut player, 0x4144), 0x4100);
+        assert_eq!(player.cpu_read(0x4146), 0xF0);
+        assert_eq!(branch_target(&mut player, 0x4146), 0x4141);
+        assert_eq!(player.cpu_read(0x4141), 0xAD);
+        assert_eq!(word(&mut player, 0x4142), STATUS);
+    }
+
+    #[test]
+    fn loads_unbanked_data_at_load_address() {
+        let mut player = NsfPlayer::new(nsf([0; 8]), Region::Ntsc);
+        assert_eq!(player.cpu_read(0x80FF), 0x00);
+        assert_eq!(player.cpu_read(0x8100), 0x01);
+        assert_eq!(player.cpu_read(0x9100), 0x02);
+        assert_eq!(player.cpu_read(0xA100), 0x03);
+    }
+
+    #[test]
+    fn bankswitches_4k_windows() {
+        let mut player = NsfPlayer::new(nsf([0, 1, 2, 0, 0, 0, 0, 0]), Region::Ntsc);
+        // Padding only fills the load address's offset into the first bank
+        assert_eq!(player.cpu_read(0x8100), 0x01);
+        assert_eq!(player.cpu_read(0x9100), 0x02);
+        player.cpu_write(0x5FF8, 2);
+        assert_eq!(player.cpu_read(0x8100), 0x03);
+
+        // Starting INIT puts the initial banks back
+        player.cpu_read(TRACK);
+        assert_eq!(player.cpu_read(0x8100), 0x01);
+    }
+
+    #[test]
+    fn play_timer_and_track_changes() {
+        let mut player = NsfPlayer::new(nsf([0; 8]), Region::Ntsc);
+        assert_eq!(player.cpu_read(TRACK), 2);
+        assert_eq!(player.cpu_read(REGION), 0);
+
+        // 1000 microseconds at NTSC speed
+        for _ in 0..1789 {
+            player.cpu_clock();
+        }
+        assert_eq!(player.cpu_read(STATUS), 0x01);
+        assert_eq!(player.cpu_read(STATUS), 0x00);
+
+        player.select_track(3);
+        assert_eq!(player.cpu_read(STATUS), 0x80);
+        assert_eq!(player.cpu_read(TRACK), 3);
+        assert_eq!(player.cpu_read(STATUS), 0x00);
+        player.select_track(4);
+        assert_eq!(player.track(), 3);
+    }
+
+    #[test]
+    fn pal_rate() {
+        let mut player = NsfPlayer::new(nsf([0; 8]), Region::Pal);
+        assert_eq!(player.cpu_read(REGION), 1);
+        assert_eq!(player.play_period, 20000 * 1662607 / 1_000_000);
+
+        let mut player = NsfPlayer::new(nsf([0; 8]), Region::Dendy);
+        assert_eq!(player.cpu_read(REGION), 1);
+        assert_eq!(player.play_period, 20000 * 1773448 / 1_000_000);
+    }
+}
@@ -0,0 +1,217 @@
+/**
+* Sachen 8259 family: 8259A (mapper 141), 8259B (138), 8259C (139) and 8259D (137)
+*
+* Registers are selected with $4100 and written through $4101 (mirrored up to $7FFF):
+*   0-3: CHR bank low bits for each quarter, 4: CHR bank high bits, 5: 32K PRG bank,
+*   6: extra CHR bit (8259D), 7: bit 0 "simple" mode, bits 1-2 mirroring
+* The variants only differ in how the CHR bank numbers are scaled: 2K banks of 2K, 4K and 8K
+* granularity for B, A and C, and four 1K banks plus a fixed 4K for D.
+*/
+use super::{read_bank, write_bank, Mapper};
+use crate::cartridge::{CartridgeHeader, Nametables};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Variant {
+    A,
+    B,
+    C,
+    D,
+}
+
+pub struct Sachen8259 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+
+    variant: Variant,
+    register: u8,
+    registers: [u8; 8],
+}
+
+impl Sachen8259 {
+    pub fn new(header: &CartridgeHeader, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Sachen8259 {
+        Sachen8259 {
+            prg_rom,
+            chr: chr_rom,
+            chr_writable: header.chr_rom_bytes() == 0,
+
+            variant: match header.mapper {
+                137 => Variant::D,
+                138 => Variant::B,
+                139 => Variant::C,
+                _ => Variant::A,
+            },
+            register: 0,
+            registers: [0; 8],
+        }
+    }
+
+    fn simple_mode(&self) -> bool {
+        self.registers[7] & 0x01 != 0
+    }
+
+    // Returns (bank, bank size) for a pattern table address
+    fn chr_bank(&self, address: u16) -> (usize, usize) {
+        if self.variant == Variant::D {
+            let slot = address as usize / 0x400;
+            let high = self.registers[4] as usize;
+            let bank = match slot {
+                0 => self.registers[0] as usize & 0x07,
+                1 => (self.registers[1] as usize & 0x07) | ((high << 4) & 0x10),
+                2 => (self.registers[2] as usize & 0x07) | ((high << 3) & 0x10),
+                3 => {
+                    (self.registers[3] as usize & 0x07)
+                        | ((high << 2) & 0x10)
+                        | (((self.registers[6] as usize) << 3) & 0x08)
+                }
+                // The upper pattern table is fixed to the last 4K
+                _ => return ((self.chr.len() / 0x1000).saturating_sub(1), 0x1000),
+            };
+            return (bank, 0x400);
+        }
+
+        let slot = address as usize / 0x800;
+        let low = if self.simple_mode() {
+            self.registers[0]
+        } else {
+            self.registers[slot]
+        };
+        let bank = ((self.registers[4] as usize & 0x07) << 3) | (low as usize & 0x07);
+        match self.variant {
+            Variant::A => ((bank << 1) | (slot & 0x01), 0x800),
+            Variant::C => ((bank << 2) | (slot & 0x03), 0x800),
+            _ => (bank, 0x800),
+        }
+    }
+
+    fn nametable_page(&self, address: u16) -> usize {
+        let table = (address as usize >> 10) & 0x03;
+        match (self.registers[7] >> 1) & 0x03 {
+            0 => table & 0x01,
+            1 => table >> 1,
+            // Page 0 for the top-left nametable, page 1 for the other three
+            2 => (table != 0) as usize,
+            _ => 0,
+        }
+    }
+}
+
+impl Mapper for Sachen8259 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x8000..=0xFFFF => {
+                let bank = self.registers[5] as usize & 0x07;
+                read_bank(&self.prg_rom, bank, 0x8000, address as usize)
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address & 0xC101 {
+            0x4100 => self.register = value & 0x07,
+            0x4101 => self.registers[self.register as usize] = value & 0x07,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        let (bank, size) = self.chr_bank(address);
+        read_bank(&self.chr, bank, size, address as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            let (bank, size) = self.chr_bank(address);
+            write_bank(&mut self.chr, bank, size, address as usize, value);
+        }
+    }
+
+    fn read_nametable(&mut self, address: u16, nametables: &Nametables) -> u8 {
+        nametables.read_page(self.nametable_page(address), address)
+    }
+
+    fn write_nametable(&mut self, address: u16, value: u8, nametables: &mut Nametables) {
+        nametables.write_page(self.nametable_page(address), address, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::mappers::{test_rom, Mirroring};
+
+    fn sachen(mapper: u16) -> Sachen8259 {
+        let prg = test_rom::banked(0x40000, 0x8000);
+        let chr = test_rom::banked(0x40000, 0x400);
+        Sachen8259::new(&test_rom::header(mapper, 0), prg, chr)
+    }
+
+    fn write_register(mapper: &mut Sachen8259, register: u8, value: u8) {
+        mapper.cpu_write(0x4100, register);
+        mapper.cpu_write(0x4101, value);
+    }
+
+    #[test]
+    fn selects_32k_prg_bank() {
+        let mut mapper = sachen(141);
+
+        write_register(&mut mapper, 5, 3);
+        assert_eq!(mapper.cpu_read(0x8000), 3);
+
+        // Registers are mirrored up to $7FFF
+        mapper.cpu_write(0x7F00, 5);
+        mapper.cpu_write(0x7F01, 6);
+        assert_eq!(mapper.cpu_read(0xFFFF), 6);
+    }
+
+    #[test]
+    fn scales_chr_banks_per_variant() {
+        // CHR is tagged per 1K, so a 2K bank n starts at 1K bank 2n
+        let mut a = sachen(141);
+        write_register(&mut a, 1, 3);
+        assert_eq!(a.ppu_read(0x0800), ((3 << 1) | 1) * 2);
+
+        let mut b = sachen(138);
+        write_register(&mut b, 1, 3);
+        assert_eq!(b.ppu_read(0x0800), 3 * 2);
+
+        let mut c = sachen(139);
+        write_register(&mut c, 1, 3);
+        assert_eq!(c.ppu_read(0x0800), ((3 << 2) | 1) * 2);
+    }
+
+    #[test]
+    fn simple_mode_uses_first_register_everywhere() {
+        let mut mapper = sachen(138);
+
+        write_register(&mut mapper, 0, 2);
+        write_register(&mut mapper, 3, 5);
+        write_register(&mut mapper, 7, 1);
+        assert_eq!(mapper.ppu_read(0x1800), 2 * 2);
+    }
+
+    #[test]
+    fn variant_d_uses_1k_banks_and_fixed_upper_table() {
+        let mut mapper = sachen(137);
+
+        write_register(&mut mapper, 1, 2);
+        write_register(&mut mapper, 4, 1);
+        assert_eq!(mapper.ppu_read(0x0400), 0x12);
+        assert_eq!(mapper.ppu_read(0x1000), 0xFC);
+    }
+
+    #[test]
+    fn l_shaped_mirroring() {
+        let mut mapper = sachen(141);
+        let mut nametables = Nametables::with_mirroring(Mirroring::Vertical);
+        nametables.vram[0x000] = 1;
+        nametables.vram[0x400] = 2;
+
+        write_register(&mut mapper, 7, 2 << 1);
+        assert_eq!(mapper.read_nametable(0x2000, &nametables), 1);
+        assert_eq!(mapper.read_nametable(0x2400, &nametables), 2);
+        assert_eq!(mapper.read_nametable(0x2800, &nametables), 2);
+        assert_eq!(mapper.read_nametable(0x2C00, &nametables), 2);
+    }
+}
@@ -0,0 +1,242 @@
+/**
+* Konami VRC2 and VRC4 (mappers 21, 22, 23 and 25)
+*
+* The boards only differ in which CPU address lines are wired to the chip's A0/A1 register
+* select pins. The NES 2.0 submapper picks the exact wiring; without one every candidate line
+* is decoded at once, which is how most emulators handle plain iNES dumps.
+*/
+use super::vrc_irq::VrcIrq;
+use super::{read_bank, write_bank, Mapper, Mirroring};
+use crate::cartridge::CartridgeHeader;
+
+pub struct Vrc2_4 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+    prg_ram: Vec<u8>,
+
+    // CPU address lines acting as the chip's A0 and A1
+    a0_lines: u16,
+    a1_lines: u16,
+    is_vrc2: bool,
+    // VRC2a ignores the low bit of every CHR bank number
+    chr_shift: u8,
+
+    prg_banks: [u8; 2],
+    prg_swap_mode: bool,
+    chr_banks: [u16; 8],
+    mirroring: Mirroring,
+    // VRC2 boards without PRG RAM have a 1-bit latch at $6000-$6FFF
+    latch: u8,
+    irq: VrcIrq,
+}
+
+impl Vrc2_4 {
+    pub fn new(header: &CartridgeHeader, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Vrc2_4 {
+        let (a0_lines, a1_lines, is_vrc2) = match (header.mapper, header.submapper) {
+            (21, 1) => (0x02, 0x04, false), // VRC4a
+            (21, 2) => (0x40, 0x80, false), // VRC4c
+            (21, _) => (0x42, 0x84, false),
+            (22, _) => (0x02, 0x01, true),  // VRC2a
+            (23, 1) => (0x01, 0x02, false), // VRC4f
+            (23, 2) => (0x04, 0x08, false), // VRC4e
+            (23, 3) => (0x01, 0x02, true),  // VRC2b
+            (23, _) => (0x05, 0x0A, false),
+            (25, 1) => (0x02, 0x01, false), // VRC4b
+            (25, 2) => (0x08, 0x04, false), // VRC4d
+            (25, 3) => (0x02, 0x01, true),  // VRC2c
+            (_, _) => (0x0A, 0x05, false),
+        };
+
+        Vrc2_4 {
+            prg_rom,
+            chr: chr_rom,
+            chr_writable: header.chr_rom_bytes() == 0,
+            prg_ram: vec![0; 0x2000],
+
+            a0_lines,
+            a1_lines,
+            is_vrc2,
+            chr_shift: if header.mapper == 22 { 1 } else { 0 },
+
+            prg_banks: [0, 0],
+            prg_swap_mode: false,
+            chr_banks: [0; 8],
+            mirroring: Mirroring::Vertical,
+            latch: 0,
+            irq: VrcIrq::new(),
+        }
+    }
+
+    // Translates a CPU address to the chip's register index 0-3
+    fn register(&self, address: u16) -> u16 {
+        (address & self.a0_lines != 0) as u16 | ((address & self.a1_lines != 0) as u16) << 1
+    }
+
+    fn prg_bank(&self, address: u16) -> usize {
+        let last = (self.prg_rom.len() / 0x2000).saturating_sub(1);
+        match (address, self.prg_swap_mode) {
+            (0x8000..=0x9FFF, false) => self.prg_banks[0] as usize,
+            (0x8000..=0x9FFF, true) => last.saturating_sub(1),
+            (0xA000..=0xBFFF, _) => self.prg_banks[1] as usize,
+            (0xC000..=0xDFFF, false) => last.saturating_sub(1),
+            (0xC000..=0xDFFF, true) => self.prg_banks[0] as usize,
+            _ => last,
+        }
+    }
+}
+
+impl Mapper for Vrc2_4 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x6FFF if self.is_vrc2 => self.latch,
+            0x6000..=0x7FFF => self.prg_ram[address as usize - 0x6000],
+            0x8000..=0xFFFF => {
+                let bank = self.prg_bank(address);
+                read_bank(&self.prg_rom, bank, 0x2000, address as usize)
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if address < 0x8000 {
+            match address {
+                0x6000..=0x6FFF if self.is_vrc2 => self.latch = value & 0x01,
+                0x6000..=0x7FFF => self.prg_ram[address as usize - 0x6000] = value,
+                _ => {}
+            }
+            return;
+        }
+
+        let register = self.register(address);
+        match (address & 0xF000, register) {
+            (0x8000, _) => self.prg_banks[0] = value & 0x1F,
+            (0x9000, 0..=1) => {
+                self.mirroring = match value & if self.is_vrc2 { 0x01 } else { 0x03 } {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenA,
+                    _ => Mirroring::SingleScreenB,
+                };
+            }
+            (0x9000, _) if !self.is_vrc2 => self.prg_swap_mode = value & 0x02 != 0,
+            (0xA000, _) => self.prg_banks[1] = value & 0x1F,
+            (0xB000..=0xE000, _) => {
+                let index =
+                    (((address & 0xF000) as usize - 0xB000) >> 11) | (register as usize >> 1);
+                let bank = self.chr_banks[index];
+                self.chr_banks[index] = if register & 0x01 == 0 {
+                    (bank & 0x1F0) | (value as u16 & 0x0F)
+                } else {
+                    (bank & 0x0F) | ((value as u16 & 0x1F) << 4)
+                };
+            }
+            (0xF000, 0) if !self.is_vrc2 => self.irq.write_latch_low(value),
+            (0xF000, 1) if !self.is_vrc2 => self.irq.write_latch_high(value),
+            (0xF000, 2) if !self.is_vrc2 => self.irq.write_control(value),
+            (0xF000, 3) if !self.is_vrc2 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        let bank = self.chr_banks[address as usize / 0x400] >> self.chr_shift;
+        read_bank(&self.chr, bank as usize, 0x400, address as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            let bank = self.chr_banks[address as usize / 0x400] >> self.chr_shift;
+            write_bank(&mut self.chr, bank as usize, 0x400, address as usize, value);
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::mappers::test_rom;
+
+    fn vrc(mapper: u16, submapper: u8) -> Vrc2_4 {
+        let mut header = test_rom::header(mapper, submapper);
+        header.chr_rom_size = 32;
+        let prg = test_rom::banked(0x20000, 0x2000);
+        let chr = test_rom::banked(0x40000, 0x400);
+        Vrc2_4::new(&header, prg, chr)
+    }
+
+    #[test]
+    fn register_select_lines() {
+        // (mapper, submapper, offset decoded as A0, offset decoded as A1)
+        let variants = [
+            (21, 1, 0x02, 0x04),
+            (21, 2, 0x40, 0x80),
+            (21, 0, 0x40, 0x04),
+            (23, 1, 0x01, 0x02),
+            (23, 2, 0x04, 0x08),
+            (23, 0, 0x04, 0x02),
+            (25, 1, 0x02, 0x01),
+            (25, 2, 0x08, 0x04),
+            (25, 0, 0x08, 0x01),
+        ];
+        for (mapper, submapper, a0, a1) in variants {
+            let mut vrc = vrc(mapper, submapper);
+            // Register 1 is the high nibble of bank 0, register 2 the low nibble of bank 1
+            vrc.cpu_write(0xB000 | a0, 0x01);
+            vrc.cpu_write(0xB000 | a1, 0x03);
+            assert_eq!(
+                (vrc.ppu_read(0x0000), vrc.ppu_read(0x0400)),
+                (0x10, 0x03),
+                "mapper {} submapper {}",
+                mapper,
+                submapper
+            );
+        }
+    }
+
+    #[test]
+    fn vrc2a_drops_chr_low_bit() {
+        let mut vrc = vrc(22, 0);
+        vrc.cpu_write(0xB000, 0x07);
+        vrc.cpu_write(0xB002, 0x01);
+        assert_eq!(vrc.ppu_read(0x0000), 0x17 >> 1);
+
+        // The 1-bit latch stands in for PRG RAM
+        vrc.cpu_write(0x6000, 0xFF);
+        assert_eq!(vrc.cpu_read(0x6000), 0x01);
+    }
+
+    #[test]
+    fn prg_swap_mode() {
+        let mut vrc = vrc(21, 1);
+        vrc.cpu_write(0x8000, 3);
+        vrc.cpu_write(0xA000, 4);
+        let banks = [0x8000, 0xA000, 0xC000, 0xE000].map(|address| vrc.cpu_read(address));
+        assert_eq!(banks, [3, 4, 14, 15]);
+
+        vrc.cpu_write(0x9004, 0x02);
+        let banks = [0x8000, 0xA000, 0xC000, 0xE000].map(|address| vrc.cpu_read(address));
+        assert_eq!(banks, [14, 4, 3, 15]);
+    }
+}
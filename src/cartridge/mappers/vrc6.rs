@@ -0,0 +1,189 @@
+/**
+* Konami VRC6 (mappers 24 and 26)
+*
+* VRC6a (mapper 24) and VRC6b (mapper 26) swap the A0 and A1 register select lines. Besides
+* banking and the shared VRC IRQ counter the chip has two pulse channels and a sawtooth, whose
+* registers are accepted and ignored until there's an APU to mix them into.
+*/
+use super::vrc_irq::VrcIrq;
+use super::{read_bank, write_bank, Mapper, Mirroring};
+use crate::cartridge::CartridgeHeader;
+
+pub struct Vrc6 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+    prg_ram: Vec<u8>,
+
+    swap_lines: bool,
+    prg_16k_bank: u8, // $8000
+    prg_8k_bank: u8,  // $C000
+    chr_banks: [u8; 8],
+    banking_control: u8, // $B003
+    irq: VrcIrq,
+}
+
+impl Vrc6 {
+    pub fn new(header: &CartridgeHeader, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Vrc6 {
+        Vrc6 {
+            prg_rom,
+            chr: chr_rom,
+            chr_writable: header.chr_rom_bytes() == 0,
+            prg_ram: vec![0; 0x2000],
+
+            swap_lines: header.mapper == 26,
+            prg_16k_bank: 0,
+            prg_8k_bank: 0,
+            chr_banks: [0; 8],
+            banking_control: 0,
+            irq: VrcIrq::new(),
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.banking_control & 0x80 != 0
+    }
+
+    fn chr_bank(&self, address: u16) -> (usize, usize) {
+        let slot = address as usize / 0x400;
+        match self.banking_control & 0x03 {
+            0 => (self.chr_banks[slot] as usize, 0x400),
+            1 => (self.chr_banks[slot / 2] as usize, 0x800),
+            // Modes 2 and 3: 1K banks for the left pattern table, 2K banks for the right
+            _ => {
+                if slot < 4 {
+                    (self.chr_banks[slot] as usize, 0x400)
+                } else {
+                    (self.chr_banks[4 + (slot - 4) / 2] as usize, 0x800)
+                }
+            }
+        }
+    }
+}
+
+impl Mapper for Vrc6 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => self.prg_ram[address as usize - 0x6000],
+            0x8000..=0xBFFF => read_bank(
+                &self.prg_rom,
+                self.prg_16k_bank as usize,
+                0x4000,
+                address as usize,
+            ),
+            0xC000..=0xDFFF => read_bank(
+                &self.prg_rom,
+                self.prg_8k_bank as usize,
+                0x2000,
+                address as usize,
+            ),
+            0xE000..=0xFFFF => {
+                let last = (self.prg_rom.len() / 0x2000).saturating_sub(1);
+                read_bank(&self.prg_rom, last, 0x2000, address as usize)
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if address < 0x8000 {
+            if (0x6000..=0x7FFF).contains(&address) && self.prg_ram_enabled() {
+                self.prg_ram[address as usize - 0x6000] = value;
+            }
+            return;
+        }
+
+        let register = if self.swap_lines {
+            (address & 0x01) << 1 | (address & 0x02) >> 1
+        } else {
+            address & 0x03
+        };
+
+        match (address & 0xF000, register) {
+            (0x8000, _) => self.prg_16k_bank = value & 0x0F,
+            (0xB000, 3) => self.banking_control = value,
+            (0xC000, _) => self.prg_8k_bank = value & 0x1F,
+            (0xD000, _) => self.chr_banks[register as usize] = value,
+            (0xE000, _) => self.chr_banks[4 + register as usize] = value,
+            (0xF000, 0) => self.irq.write_latch(value),
+            (0xF000, 1) => self.irq.write_control(value),
+            (0xF000, 2) => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        let (bank, size) = self.chr_bank(address);
+        read_bank(&self.chr, bank, size, address as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            let (bank, size) = self.chr_bank(address);
+            write_bank(&mut self.chr, bank, size, address as usize, value);
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(match (self.banking_control >> 2) & 0x03 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenA,
+            _ => Mirroring::SingleScreenB,
+        })
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::mappers::test_rom;
+
+    fn vrc6(mapper: u16) -> Vrc6 {
+        let mut header = test_rom::header(mapper, 0);
+        header.chr_rom_size = 32;
+        let prg = test_rom::banked(0x40000, 0x2000);
+        let chr = test_rom::banked(0x40000, 0x400);
+        Vrc6::new(&header, prg, chr)
+    }
+
+    #[test]
+    fn vrc6b_swaps_a0_and_a1() {
+        for (mapper, expected) in [(24, [7, 9]), (26, [9, 7])] {
+            let mut vrc6 = vrc6(mapper);
+            vrc6.cpu_write(0xD001, 7);
+            vrc6.cpu_write(0xD002, 9);
+            assert_eq!([vrc6.ppu_read(0x0400), vrc6.ppu_read(0x0800)], expected);
+        }
+
+        // $B003 is register 3 on both
+        let mut vrc6 = vrc6(26);
+        vrc6.cpu_write(0xB003, 0x04);
+        assert_eq!(vrc6.mirroring(), Some(Mirroring::Horizontal));
+    }
+
+    #[test]
+    fn prg_banking() {
+        let mut vrc6 = vrc6(24);
+        vrc6.cpu_write(0x8000, 3);
+        vrc6.cpu_write(0xC000, 5);
+        let banks = [0x8000, 0xA000, 0xC000, 0xE000].map(|address| vrc6.cpu_read(address));
+        assert_eq!(banks, [6, 7, 5, 31]);
+    }
+}
@@ -0,0 +1,141 @@
+/**
+* Konami VRC7 (mapper 85)
+*
+* VRC7b (submapper 1) decodes its odd registers on A3, VRC7a (submapper 2) on A4. The FM
+* synthesis unit is a separate concern; its register file is kept here so it can be driven
+* from the writes games make.
+*/
+use super::vrc_irq::VrcIrq;
+use super::{read_bank, write_bank, Mapper, Mirroring};
+use crate::cartridge::CartridgeHeader;
+
+pub struct Vrc7 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+    prg_ram: Vec<u8>,
+
+    odd_lines: u16,
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    control: u8, // $E000
+    irq: VrcIrq,
+
+    audio_register: u8,
+    audio_registers: [u8; 0x40],
+}
+
+impl Vrc7 {
+    pub fn new(header: &CartridgeHeader, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Vrc7 {
+        Vrc7 {
+            prg_rom,
+            chr: chr_rom,
+            chr_writable: header.chr_rom_bytes() == 0,
+            prg_ram: vec![0; 0x2000],
+
+            odd_lines: match header.submapper {
+                1 => 0x08,
+                2 => 0x10,
+                _ => 0x18,
+            },
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            control: 0,
+            irq: VrcIrq::new(),
+
+            audio_register: 0,
+            audio_registers: [0; 0x40],
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+}
+
+impl Mapper for Vrc7 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => self.prg_ram[address as usize - 0x6000],
+            0x8000..=0xDFFF => {
+                let bank = self.prg_banks[(address as usize - 0x8000) / 0x2000];
+                read_bank(&self.prg_rom, bank as usize, 0x2000, address as usize)
+            }
+            0xE000..=0xFFFF => {
+                let last = (self.prg_rom.len() / 0x2000).saturating_sub(1);
+                read_bank(&self.prg_rom, last, 0x2000, address as usize)
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if address < 0x8000 {
+            if (0x6000..=0x7FFF).contains(&address) && self.prg_ram_enabled() {
+                self.prg_ram[address as usize - 0x6000] = value;
+            }
+            return;
+        }
+
+        let odd = address & self.odd_lines != 0;
+        match (address & 0xF000, odd) {
+            (0x8000, false) => self.prg_banks[0] = value & 0x3F,
+            (0x8000, true) => self.prg_banks[1] = value & 0x3F,
+            (0x9000, false) => self.prg_banks[2] = value & 0x3F,
+            (0x9000, true) => {
+                // $9010 selects the audio register, $9030 writes it
+                if address & 0x20 == 0 {
+                    self.audio_register = value & 0x3F;
+                } else {
+                    self.audio_registers[self.audio_register as usize] = value;
+                }
+            }
+            (0xA000..=0xD000, _) => {
+                let index = (((address & 0xF000) as usize - 0xA000) >> 11) | odd as usize;
+                self.chr_banks[index] = value;
+            }
+            (0xE000, false) => self.control = value,
+            (0xE000, true) => self.irq.write_latch(value),
+            (0xF000, false) => self.irq.write_control(value),
+            (0xF000, true) => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, address: u16) -> u8 {
+        let bank = self.chr_banks[address as usize / 0x400];
+        read_bank(&self.chr, bank as usize, 0x400, address as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            let bank = self.chr_banks[address as usize / 0x400];
+            write_bank(&mut self.chr, bank as usize, 0x400, address as usize, value);
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(match self.control & 0x03 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenA,
+            _ => Mirroring::SingleScreenB,
+        })
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending()
+    }
+}
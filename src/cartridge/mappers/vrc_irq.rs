@@ -0,0 +1,135 @@
+/**
+* The IRQ counter shared by the Konami VRC4, VRC6 and VRC7
+*
+* An 8-bit up-counter that reloads from the latch and fires when it overflows. In scanline
+* mode a prescaler divides the CPU clock by 113.667 (341 / 3) to approximate one clock per
+* scanline; in cycle mode it is clocked on every CPU cycle.
+*/
+pub struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    enable_after_ack: bool,
+    cycle_mode: bool,
+    pending: bool,
+}
+
+impl VrcIrq {
+    pub fn new() -> VrcIrq {
+        VrcIrq {
+            latch: 0,
+            counter: 0,
+            prescaler: 341,
+            enabled: false,
+            enable_after_ack: false,
+            cycle_mode: false,
+            pending: false,
+        }
+    }
+
+    pub fn write_latch(&mut self, value: u8) {
+        self.latch = value;
+    }
+
+    pub fn write_latch_low(&mut self, value: u8) {
+        self.latch = (self.latch & 0xF0) | (value & 0x0F);
+    }
+
+    pub fn write_latch_high(&mut self, value: u8) {
+        self.latch = (self.latch & 0x0F) | ((value & 0x0F) << 4);
+    }
+
+    pub fn write_control(&mut self, value: u8) {
+        self.enable_after_ack = value & 0x01 != 0;
+        self.enabled = value & 0x02 != 0;
+        self.cycle_mode = value & 0x04 != 0;
+        self.pending = false;
+
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = 341;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    pub fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.cycle_mode {
+            self.clock_counter();
+            return;
+        }
+
+        self.prescaler -= 3;
+        if self.prescaler <= 0 {
+            self.prescaler += 341;
+            self.clock_counter();
+        }
+    }
+
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+
+    fn clock_counter(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(irq: &mut VrcIrq, cycles: usize) {
+        for _ in 0..cycles {
+            irq.clock();
+        }
+    }
+
+    #[test]
+    fn prescaler_gives_three_scanlines_per_341_cycles() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xFD);
+        irq.write_control(0x02);
+
+        // Counter clocks after 114, 114 and 113 cycles
+        clock(&mut irq, 340);
+        assert!(!irq.pending());
+        clock(&mut irq, 1);
+        assert!(irq.pending());
+    }
+
+    #[test]
+    fn cycle_mode_and_acknowledge() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xFE);
+        irq.write_control(0x07);
+        clock(&mut irq, 1);
+        assert!(!irq.pending());
+        clock(&mut irq, 1);
+        assert!(irq.pending());
+
+        // Reloaded from the latch, and enabled again by the A bit
+        irq.acknowledge();
+        assert!(!irq.pending());
+        clock(&mut irq, 2);
+        assert!(irq.pending());
+
+        irq.write_control(0x04);
+        assert!(!irq.pending());
+        clock(&mut irq, 0x200);
+        assert!(!irq.pending());
+    }
+}
@@ -1,81 +1,244 @@
-use std::fs;
-
-pub fn get_rom() -> Cartridge {
-    let contents = fs::read("nestest.nes");
-
-    match contents {
-        Ok(file) => {
-            let is_ines = file[0..4] == [0x4E, 0x45, 0x53, 0x1A];
-            if !is_ines {
-                panic!("Not a valid iNES file");
-            }
-            let is_nes2 = file[7] & 0x0C == 0x08;
+mod archive;
+mod battery;
+mod crc32;
+mod database;
+mod error;
+mod fds;
+mod mappers;
+mod nametables;
+mod nsf;
+mod patch;
+mod sha1;
+mod unif;
 
-            let cart_header = CartridgeHeader {
-                ines: is_ines,
-                nes2: is_nes2,
-                prg_rom_size: file[4],
-                chr_rom_size: file[5],
-                flags: (file[6] as u16) << 8 | file[7] as u16,
-                mapper: (file[6] & 0xF0) | (file[7] >> 4),
-                submapper: (file[8] & 0xF0) | (file[9] >> 4),
-                prg_msb_rom_size: file[9] & 0x0F,
-                chr_msb_rom_size: file[10] & 0x0F,
-                prg_ram_size: file[10] >> 4,
-                chr_ram_size: file[10] & 0x0F,
-                cpu_ppu_timing: file[11] & 0x03,
-                is_vs_unisystem: file[7] & 0x01 == 0x01,
-                vs_unisystem: file[8] & 0x0F,
-                is_extended_console: file[12] & 0x0C == 0x08,
-                extended_console: file[12] & 0x0C,
-                misc_roms: file[12] & 0x03,
-                default_expansion_device: file[13],
-            };
-
-            let prg_rom_start = 16;
-            let prg_rom_end = prg_rom_start + (cart_header.prg_rom_size as usize * 0x4000);
-            let prg_rom = file[prg_rom_start..prg_rom_end].to_vec();
-
-            // println!("DEBUG {:X?}", &prg_rom[prg_rom.len() - 16..]);
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
-            let chr_rom_start = prg_rom_end;
-            let chr_rom_end = chr_rom_start + (cart_header.chr_rom_size as usize * 0x2000);
-            let chr_rom = file[chr_rom_start..chr_rom_end].to_vec();
+use crate::region::Region;
 
-            let cart: Cartridge = Cartridge {
-                header: cart_header,
-                prg_rom: prg_rom,
-                chr_rom: chr_rom,
-            };
+pub use battery::Battery;
+pub use database::Database;
+pub use error::RomError;
+pub use mappers::{Fds, Mapper, Mirroring, NsfPlayer};
+pub use nametables::Nametables;
+pub use nsf::Nsf;
 
-            return cart;
-        }
-        Err(e) => {
-            panic!("Error reading file: {}", e)
-        }
-    }
-}
-
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct CartridgeHeader {
     pub ines: bool,
     pub nes2: bool,
     pub prg_rom_size: u8,
     pub chr_rom_size: u8,
     pub flags: u16,
-    pub mapper: u8,
+    pub mapper: u16,
     pub submapper: u8,
     pub prg_msb_rom_size: u8,
     pub chr_msb_rom_size: u8,
+    // NES 2.0 RAM sizes are shift counts: 64 << n bytes, or none when 0
     pub prg_ram_size: u8,
+    pub prg_nvram_size: u8,
     pub chr_ram_size: u8,
+    pub chr_nvram_size: u8,
     pub cpu_ppu_timing: u8,
+    pub console_type: u8,
     pub is_vs_unisystem: bool,
     pub vs_unisystem: u8,
     pub is_extended_console: bool,
     pub extended_console: u8,
     pub misc_roms: u8,
     pub default_expansion_device: u8,
+    // Single-screen layout for formats that can describe one (UNIF), which iNES flags can't
+    pub single_screen: Option<Mirroring>,
+}
+
+// Console types, flags 7 bits 0-1; 2 is the PlayChoice-10, which runs like a plain NES here
+pub const CONSOLE_NES: u8 = 0;
+pub const CONSOLE_VS_SYSTEM: u8 = 1;
+pub const CONSOLE_EXTENDED: u8 = 3;
+
+// CPU/PPU timing, byte 12 bits 0-1
+pub const TIMING_NTSC: u8 = 0;
+pub const TIMING_PAL: u8 = 1;
+pub const TIMING_MULTI_REGION: u8 = 2;
+pub const TIMING_DENDY: u8 = 3;
+
+impl CartridgeHeader {
+    // Decodes the 16 byte iNES / NES 2.0 header at the start of `file`
+    pub fn parse(file: &[u8]) -> CartridgeHeader {
+        let ines = file[0..4] == [0x4E, 0x45, 0x53, 0x1A];
+        let nes2 = file[7] & 0x0C == 0x08;
+        let console_type = file[7] & 0x03;
+
+        // Old dumping tools wrote junk like "DiskDude!" over bytes 7-15, so an iNES 1.0 header
+        // is only trusted past byte 6 when bytes 12-15 are all zero
+        let dirty = !nes2 && file[12..16].iter().any(|&byte| byte != 0);
+        let mut mapper = (file[6] >> 4) as u16;
+        if !dirty {
+            mapper |= (file[7] & 0xF0) as u16;
+        }
+
+        if !nes2 {
+            return CartridgeHeader {
+                ines,
+                nes2,
+                prg_rom_size: file[4],
+                chr_rom_size: file[5],
+                flags: (file[6] as u16) << 8 | file[7] as u16,
+                mapper,
+                submapper: 0,
+                prg_msb_rom_size: 0,
+                chr_msb_rom_size: 0,
+                prg_ram_size: 0,
+                prg_nvram_size: 0,
+                chr_ram_size: 0,
+                chr_nvram_size: 0,
+                cpu_ppu_timing: if !dirty && file[9] & 0x01 != 0 {
+                    TIMING_PAL
+                } else {
+                    TIMING_NTSC
+                },
+                console_type: if dirty { CONSOLE_NES } else { console_type },
+                is_vs_unisystem: !dirty && console_type == CONSOLE_VS_SYSTEM,
+                vs_unisystem: 0,
+                is_extended_console: false,
+                extended_console: 0,
+                misc_roms: 0,
+                default_expansion_device: 0,
+                single_screen: None,
+            };
+        }
+
+        CartridgeHeader {
+            ines,
+            nes2,
+            prg_rom_size: file[4],
+            chr_rom_size: file[5],
+            flags: (file[6] as u16) << 8 | file[7] as u16,
+            mapper: mapper | ((file[8] & 0x0F) as u16) << 8,
+            submapper: file[8] >> 4,
+            prg_msb_rom_size: file[9] & 0x0F,
+            chr_msb_rom_size: file[9] >> 4,
+            prg_ram_size: file[10] & 0x0F,
+            prg_nvram_size: file[10] >> 4,
+            chr_ram_size: file[11] & 0x0F,
+            chr_nvram_size: file[11] >> 4,
+            cpu_ppu_timing: file[12] & 0x03,
+            console_type,
+            is_vs_unisystem: console_type == CONSOLE_VS_SYSTEM,
+            // PPU type in the low nibble, hardware type in the high nibble
+            vs_unisystem: if console_type == CONSOLE_VS_SYSTEM {
+                file[13]
+            } else {
+                0
+            },
+            is_extended_console: console_type == CONSOLE_EXTENDED,
+            extended_console: if console_type == CONSOLE_EXTENDED {
+                file[13] & 0x0F
+            } else {
+                0
+            },
+            misc_roms: file[14] & 0x03,
+            default_expansion_device: file[15] & 0x3F,
+            single_screen: None,
+        }
+    }
+
+    // Nametable layout soldered on the board, flags 6 bits 0 and 3
+    pub fn mirroring(&self) -> Mirroring {
+        if self.flags & 0x0800 != 0 {
+            return Mirroring::FourScreen;
+        }
+        if let Some(single_screen) = self.single_screen {
+            return single_screen;
+        }
+        if self.flags & 0x0100 != 0 {
+            return Mirroring::Vertical;
+        }
+        Mirroring::Horizontal
+    }
+
+    // Flags 6 bit 1, or any NES 2.0 PRG NVRAM
+    pub fn has_battery(&self) -> bool {
+        self.flags & 0x0200 != 0 || self.prg_nvram_size != 0
+    }
+
+    // 512 bytes between the header and PRG ROM, loaded at $7000
+    pub fn has_trainer(&self) -> bool {
+        self.flags & 0x0400 != 0
+    }
+
+    pub fn prg_rom_bytes(&self) -> usize {
+        rom_bytes(self.prg_rom_size, self.prg_msb_rom_size, 0x4000)
+    }
+
+    pub fn chr_rom_bytes(&self) -> usize {
+        rom_bytes(self.chr_rom_size, self.chr_msb_rom_size, 0x2000)
+    }
+
+    // PRG RAM size in bytes (volatile plus battery-backed), or `default` for iNES 1.0 headers
+    pub fn prg_ram_bytes(&self, default: usize) -> usize {
+        if !self.nes2 {
+            return default;
+        }
+        shift_bytes(self.prg_ram_size) + shift_bytes(self.prg_nvram_size)
+    }
+
+    // CHR RAM size in bytes (volatile plus battery-backed), or `default` for iNES 1.0 headers
+    pub fn chr_ram_bytes(&self, default: usize) -> usize {
+        if !self.nes2 {
+            return default;
+        }
+        shift_bytes(self.chr_ram_size) + shift_bytes(self.chr_nvram_size)
+    }
+}
+
+// NES 2.0 ROM sizes are a 12-bit count of `unit`s, unless the MSB nibble is $F: then the LSB
+// byte is EEEEEEMM and the size is 2^E * (MM * 2 + 1) bytes
+fn rom_bytes(lsb: u8, msb: u8, unit: usize) -> usize {
+    if msb == 0x0F {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0x03) as usize * 2 + 1;
+        return 2usize.saturating_pow(exponent).saturating_mul(multiplier);
+    }
+    ((msb as usize) << 8 | lsb as usize) * unit
+}
+
+fn shift_bytes(shift: u8) -> usize {
+    if shift == 0 {
+        return 0;
+    }
+    64 << shift
+}
+
+pub struct LoadOptions {
+    // Correct iNES 1.0 headers from the built-in game database; NES 2.0 headers are trusted
+    pub use_database: bool,
+    // Extra database entries, checked before the built-in ones
+    pub database: Option<PathBuf>,
+    // IPS/UPS/BPS patch to apply. Without one, `<rom>.ips`, `.ups` or `.bps` is used if present.
+    pub patch: Option<PathBuf>,
+    // Entry to load from a zip file, instead of the first ROM in it
+    pub archive_entry: Option<String>,
+    // disksys.rom, needed to run .fds disk images
+    pub fds_bios: Option<PathBuf>,
+    // Region to run in, instead of the one the header, database or NSF asks for. It's written
+    // into the header's CPU/PPU timing so mappers with region dependent timing see it too.
+    pub region: Option<Region>,
+}
+
+impl Default for LoadOptions {
+    fn default() -> LoadOptions {
+        LoadOptions {
+            use_database: true,
+            database: None,
+            patch: None,
+            archive_entry: None,
+            fds_bios: None,
+            region: None,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -83,26 +246,307 @@ pub struct Cartridge {
     pub header: CartridgeHeader,
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
+    pub mapper: Arc<Mutex<dyn Mapper>>,
+    pub battery: Option<Arc<Mutex<Battery>>>,
+    // The same device as `mapper` when this is a disk image, for swapping disks
+    pub disk_drive: Option<Arc<Mutex<Fds>>>,
+    // The same device as `mapper` when this is an NSF, for picking tracks
+    pub nsf_player: Option<Arc<Mutex<NsfPlayer>>>,
 }
 
 impl Cartridge {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Cartridge, RomError> {
+        Cartridge::from_path_with(path, &LoadOptions::default())
+    }
+
+    pub fn from_path_with<P: AsRef<Path>>(
+        path: P,
+        options: &LoadOptions,
+    ) -> Result<Cartridge, RomError> {
+        let path = path.as_ref();
+        let mut file = fs::read(path)?;
+        if let Some(rom) = archive::unpack(&file, options.archive_entry.as_deref())? {
+            file = rom;
+        }
+
+        let patch_path = options.patch.clone().or_else(|| patch::find_patch(path));
+        if let Some(patch_path) = patch_path {
+            let patch = fs::read(&patch_path)?;
+            file = patch::apply_patch(&file, &patch)?;
+            println!("Applied patch {}", patch_path.display());
+        }
+
+        Cartridge::from_bytes_with(&file, options)
+    }
+
+    pub fn from_bytes(file: &[u8]) -> Result<Cartridge, RomError> {
+        Cartridge::from_bytes_with(file, &LoadOptions::default())
+    }
+
+    pub fn from_bytes_with(file: &[u8], options: &LoadOptions) -> Result<Cartridge, RomError> {
+        if unif::is_unif(file) {
+            let (header, prg_rom, chr_rom) = unif::parse(file)?;
+            return Cartridge::build(header, None, prg_rom, chr_rom, options);
+        }
+        if fds::is_fds(file) {
+            return Cartridge::from_disk(file, options);
+        }
+        if nsf::is_nsf(file) {
+            return Cartridge::from_nsf(file, options.region);
+        }
+
+        if file.len() < 16 {
+            return Err(RomError::TruncatedHeader { length: file.len() });
+        }
+        let cart_header = CartridgeHeader::parse(file);
+        if !cart_header.ines {
+            return Err(RomError::BadMagic);
+        }
+        if cart_header.prg_rom_bytes() == 0 {
+            return Err(RomError::BadHeader("no PRG ROM".to_string()));
+        }
+
+        let mut prg_rom_start: usize = 16;
+        let mut trainer = None;
+        if cart_header.has_trainer() {
+            prg_rom_start += 0x200;
+            if file.len() < prg_rom_start {
+                return Err(RomError::TruncatedTrainer {
+                    found: file.len() - 16,
+                });
+            }
+            trainer = Some(&file[16..prg_rom_start]);
+        }
+
+        let prg_rom_end = prg_rom_start.saturating_add(cart_header.prg_rom_bytes());
+        if file.len() < prg_rom_end {
+            return Err(RomError::TruncatedPrg {
+                expected: cart_header.prg_rom_bytes(),
+                found: file.len() - prg_rom_start,
+            });
+        }
+        let prg_rom = file[prg_rom_start..prg_rom_end].to_vec();
+
+        let chr_rom_start = prg_rom_end;
+        let chr_rom_end = chr_rom_start.saturating_add(cart_header.chr_rom_bytes());
+        if file.len() < chr_rom_end {
+            return Err(RomError::TruncatedChr {
+                expected: cart_header.chr_rom_bytes(),
+                found: file.len() - chr_rom_start,
+            });
+        }
+        let chr_rom = file[chr_rom_start..chr_rom_end].to_vec();
+
+        Cartridge::build(cart_header, trainer, prg_rom, chr_rom, options)
+    }
+
+    // Everything after the file format specific parsing
+    fn build(
+        mut cart_header: CartridgeHeader,
+        trainer: Option<&[u8]>,
+        prg_rom: Vec<u8>,
+        chr_rom: Vec<u8>,
+        options: &LoadOptions,
+    ) -> Result<Cartridge, RomError> {
+        if options.use_database && !cart_header.nes2 {
+            let extra = match &options.database {
+                Some(path) => Some(Database::from_file(path).map_err(RomError::BadDatabase)?),
+                None => None,
+            };
+            let found = extra
+                .iter()
+                .chain([Database::embedded()])
+                .find_map(|database| database.find(&prg_rom, &chr_rom));
+            if let Some((hash, entry)) = found {
+                println!("ROM database: matched {} ({})", entry.name, hash);
+                entry.apply(&mut cart_header);
+            }
+        }
+        if let Some(region) = options.region {
+            cart_header.cpu_ppu_timing = region.timing();
+        }
+
+        // Carts without CHR ROM get writable CHR RAM in its place
+        let chr = if chr_rom.is_empty() {
+            vec![0; cart_header.chr_ram_bytes(0x2000)]
+        } else {
+            chr_rom.clone()
+        };
+
+        let mapper = mappers::new_mapper(&cart_header, prg_rom.clone(), chr)?;
+
+        if let Some(trainer) = trainer {
+            let mut mapper = mapper.lock().unwrap();
+            let prg_ram = mapper.prg_ram_mut();
+            for (i, byte) in trainer.iter().enumerate() {
+                mappers::write_bank(prg_ram, 0, 0x2000, 0x1000 + i, *byte);
+            }
+        }
+
+        Ok(Cartridge {
+            header: cart_header,
+            prg_rom,
+            chr_rom,
+            mapper,
+            battery: None,
+            disk_drive: None,
+            nsf_player: None,
+        })
+    }
+
+    // FDS disk image: the BIOS takes the place of PRG ROM and the disk goes in the drive
+    fn from_disk(file: &[u8], options: &LoadOptions) -> Result<Cartridge, RomError> {
+        let sides = fds::parse(file)?;
+        let bios_path = options.fds_bios.as_ref().ok_or(RomError::MissingFdsBios)?;
+        let bios = fs::read(bios_path)?;
+        if bios.len() != 0x2000 {
+            return Err(RomError::BadFdsBios { length: bios.len() });
+        }
+
+        let header = CartridgeHeader {
+            mapper: 20,
+            cpu_ppu_timing: options.region.unwrap_or(Region::Ntsc).timing(),
+            ..Default::default()
+        };
+        let disk_drive = Arc::new(Mutex::new(Fds::new(&header, bios.clone(), sides)));
+        Ok(Cartridge {
+            header,
+            prg_rom: bios,
+            chr_rom: vec![],
+            mapper: disk_drive.clone(),
+            battery: None,
+            disk_drive: Some(disk_drive),
+            nsf_player: None,
+        })
+    }
+
+    // NSF/NSFe music file, played by a driver standing in for the cartridge
+    fn from_nsf(file: &[u8], region: Option<Region>) -> Result<Cartridge, RomError> {
+        let nsf = nsf::parse(file)?;
+        let region = region.unwrap_or(if nsf.is_pal() {
+            Region::Pal
+        } else {
+            Region::Ntsc
+        });
+        let header = CartridgeHeader {
+            cpu_ppu_timing: region.timing(),
+            ..Default::default()
+        };
+        let prg_rom = nsf.data.clone();
+        let nsf_player = Arc::new(Mutex::new(NsfPlayer::new(nsf, region)));
+        Ok(Cartridge {
+            header,
+            prg_rom,
+            chr_rom: vec![],
+            mapper: nsf_player.clone(),
+            battery: None,
+            disk_drive: None,
+            nsf_player: Some(nsf_player),
+        })
+    }
+
+    pub fn disk_sides(&self) -> usize {
+        self.disk_drive
+            .as_ref()
+            .map_or(0, |drive| drive.lock().unwrap().side_count())
+    }
+
+    // Swaps in the next disk side, wrapping around after the last
+    pub fn flip_disk(&self) {
+        if let Some(drive) = &self.disk_drive {
+            drive.lock().unwrap().flip();
+        }
+    }
+
+    pub fn insert_disk(&self, side: usize) {
+        if let Some(drive) = &self.disk_drive {
+            drive.lock().unwrap().insert(side);
+        }
+    }
+
+    // Current NSF track (0-based) and the number of tracks
+    pub fn nsf_track(&self) -> Option<(usize, usize)> {
+        self.nsf_player.as_ref().map(|player| {
+            let player = player.lock().unwrap();
+            (player.track(), player.nsf().song_count)
+        })
+    }
+
+    pub fn select_track(&self, track: usize) {
+        if let Some(player) = &self.nsf_player {
+            player.lock().unwrap().select_track(track);
+        }
+    }
+
+    pub fn eject_disk(&self) {
+        if let Some(drive) = &self.disk_drive {
+            drive.lock().unwrap().eject();
+        }
+    }
+
+    // Loads battery-backed PRG RAM from `path` and saves back to it from then on. Disk images
+    // keep a copy of the disk there instead, with whatever games wrote to it. Does nothing for
+    // carts without a battery.
+    pub fn attach_battery(&mut self, path: PathBuf) -> io::Result<()> {
+        if let Some(drive) = &self.disk_drive {
+            let mut drive = drive.lock().unwrap();
+            let mut image = drive.disk_image();
+            let battery = Battery::load(path, &mut image)?;
+            drive.load_image(&image);
+            self.battery = Some(Arc::new(Mutex::new(battery)));
+            return Ok(());
+        }
+        if !self.header.has_battery() {
+            return Ok(());
+        }
+
+        let battery = Battery::load(path, self.mapper.lock().unwrap().prg_ram_mut())?;
+        self.battery = Some(Arc::new(Mutex::new(battery)));
+        Ok(())
+    }
+
+    pub fn save_battery(&self) -> io::Result<()> {
+        let Some(battery) = &self.battery else {
+            return Ok(());
+        };
+        let mut battery = battery.lock().unwrap();
+        match &self.disk_drive {
+            Some(drive) => battery.save(&drive.lock().unwrap().disk_image()),
+            None => battery.save(self.mapper.lock().unwrap().prg_ram()),
+        }
+    }
+
     pub fn get_prg_from_address(&self, address: u16) -> u8 {
         // println!("Address: {:X}", address);
-        if address < 0x8000 {
+        if address < 0x4020 {
             return 0;
         }
 
-        let prg_rom_address = (address - 0x8000) % (self.header.prg_rom_size as u16 * 0x4000);
-        return self.prg_rom[prg_rom_address as usize];
+        return self.mapper.lock().unwrap().cpu_read(address);
+    }
+
+    pub fn set_prg_at_address(&self, address: u16, value: u8) {
+        if address < 0x4020 {
+            return;
+        }
+
+        self.mapper.lock().unwrap().cpu_write(address, value);
     }
 
     pub fn get_chr_from_address(&self, address: u16) -> u8 {
-        if address < 0x2000 {
+        if address >= 0x2000 {
             return 0;
         }
 
-        let chr_rom_address = address - 0x2000;
-        return self.chr_rom[chr_rom_address as usize];
+        return self.mapper.lock().unwrap().ppu_read(address);
+    }
+
+    pub fn set_chr_at_address(&self, address: u16, value: u8) {
+        if address >= 0x2000 {
+            return;
+        }
+
+        self.mapper.lock().unwrap().ppu_write(address, value);
     }
 
     pub fn read_prg_word(&self, addr: u16) -> u16 {
@@ -111,3 +555,454 @@ impl Cartridge {
         return (high << 8) | low;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nes2_header(bytes: [(usize, u8); 8]) -> [u8; 16] {
+        let mut file = [0; 16];
+        file[0..4].copy_from_slice(&[0x4E, 0x45, 0x53, 0x1A]);
+        file[7] = 0x08;
+        for (index, value) in bytes {
+            file[index] |= value;
+        }
+        file
+    }
+
+    fn parse(bytes: &[(usize, u8)]) -> CartridgeHeader {
+        let mut padded = [(0, 0); 8];
+        padded[..bytes.len()].copy_from_slice(bytes);
+        CartridgeHeader::parse(&nes2_header(padded))
+    }
+
+    #[test]
+    fn detects_ines_and_nes2() {
+        let header = parse(&[]);
+        assert!(header.ines);
+        assert!(header.nes2);
+
+        let mut file = nes2_header([(0, 0); 8]);
+        file[7] = 0x00;
+        let header = CartridgeHeader::parse(&file);
+        assert!(header.ines);
+        assert!(!header.nes2);
+
+        file[0] = b'X';
+        assert!(!CartridgeHeader::parse(&file).ines);
+    }
+
+    #[test]
+    fn decodes_12_bit_mapper_and_submapper() {
+        let header = parse(&[(6, 0xA0), (7, 0xB0), (8, 0x5C)]);
+        assert_eq!(header.mapper, 0xCBA);
+        assert_eq!(header.submapper, 5);
+    }
+
+    #[test]
+    fn ines1_ignores_byte_8_and_dirty_headers() {
+        let mut file = [0; 16];
+        file[0..4].copy_from_slice(&[0x4E, 0x45, 0x53, 0x1A]);
+        file[6] = 0x10;
+        file[7] = 0x40;
+        file[8] = 0x3F;
+        assert_eq!(CartridgeHeader::parse(&file).mapper, 0x41);
+        assert_eq!(CartridgeHeader::parse(&file).submapper, 0);
+
+        file[7..16].copy_from_slice(b"DiskDude!");
+        assert_eq!(CartridgeHeader::parse(&file).mapper, 0x01);
+    }
+
+    #[test]
+    fn decodes_flags() {
+        let header = parse(&[(6, 0x0B), (7, 0x01)]);
+        assert_eq!(header.flags, 0x0B09);
+    }
+
+    #[test]
+    fn detects_battery() {
+        assert!(parse(&[(6, 0x02)]).has_battery());
+        assert!(parse(&[(10, 0x70)]).has_battery());
+        assert!(!parse(&[(10, 0x07)]).has_battery());
+    }
+
+    #[test]
+    fn decodes_plain_rom_sizes() {
+        let header = parse(&[(4, 0x02), (5, 0x01), (9, 0x21)]);
+        assert_eq!(header.prg_rom_size, 0x02);
+        assert_eq!(header.prg_msb_rom_size, 0x01);
+        assert_eq!(header.chr_msb_rom_size, 0x02);
+        assert_eq!(header.prg_rom_bytes(), 0x102 * 0x4000);
+        assert_eq!(header.chr_rom_bytes(), 0x201 * 0x2000);
+    }
+
+    #[test]
+    fn decodes_exponent_multiplier_rom_sizes() {
+        // 2^10 * 3 and 2^4 * 7
+        let header = parse(&[(4, (10 << 2) | 1), (5, (4 << 2) | 3), (9, 0xFF)]);
+        assert_eq!(header.prg_rom_bytes(), 3072);
+        assert_eq!(header.chr_rom_bytes(), 112);
+    }
+
+    #[test]
+    fn decodes_ram_shift_counts() {
+        let header = parse(&[(10, 0x97), (11, 0x07)]);
+        assert_eq!(header.prg_ram_size, 7);
+        assert_eq!(header.prg_nvram_size, 9);
+        assert_eq!(header.chr_ram_size, 7);
+        assert_eq!(header.chr_nvram_size, 0);
+        assert_eq!(header.prg_ram_bytes(0), 0x2000 + 0x8000);
+        assert_eq!(header.chr_ram_bytes(0), 0x2000);
+    }
+
+    #[test]
+    fn ines1_uses_default_prg_ram() {
+        let mut file = [0; 16];
+        file[0..4].copy_from_slice(&[0x4E, 0x45, 0x53, 0x1A]);
+        assert_eq!(CartridgeHeader::parse(&file).prg_ram_bytes(0x2000), 0x2000);
+    }
+
+    #[test]
+    fn decodes_timing() {
+        assert_eq!(parse(&[(12, 0x03)]).cpu_ppu_timing, TIMING_DENDY);
+        assert_eq!(parse(&[(12, 0x01)]).cpu_ppu_timing, TIMING_PAL);
+
+        // iNES 1.0 keeps its TV system bit in byte 9
+        let mut file = [0; 16];
+        file[0..4].copy_from_slice(&[0x4E, 0x45, 0x53, 0x1A]);
+        file[9] = 0x01;
+        assert_eq!(CartridgeHeader::parse(&file).cpu_ppu_timing, TIMING_PAL);
+    }
+
+    #[test]
+    fn decodes_console_type() {
+        let header = parse(&[(7, 0x01), (13, 0x35)]);
+        assert_eq!(header.console_type, CONSOLE_VS_SYSTEM);
+        assert!(header.is_vs_unisystem);
+        assert_eq!(header.vs_unisystem, 0x35);
+        assert!(!header.is_extended_console);
+
+        let header = parse(&[(7, 0x03), (13, 0x05)]);
+        assert_eq!(header.console_type, CONSOLE_EXTENDED);
+        assert!(header.is_extended_console);
+        assert_eq!(header.extended_console, 0x05);
+        assert_eq!(header.vs_unisystem, 0);
+
+        // PlayChoice-10
+        assert_eq!(parse(&[(7, 0x02)]).console_type, 2);
+    }
+
+    #[test]
+    fn decodes_misc_roms_and_expansion_device() {
+        let header = parse(&[(14, 0x02), (15, 0x2A)]);
+        assert_eq!(header.misc_roms, 2);
+        assert_eq!(header.default_expansion_device, 0x2A);
+    }
+
+    fn ines_file(prg_banks: u8, chr_banks: u8, mapper: u8) -> Vec<u8> {
+        let mut file = vec![
+            0x4E,
+            0x45,
+            0x53,
+            0x1A,
+            prg_banks,
+            chr_banks,
+            mapper << 4,
+            mapper & 0xF0,
+        ];
+        file.resize(16, 0);
+        file.resize(
+            16 + prg_banks as usize * 0x4000 + chr_banks as usize * 0x2000,
+            0xEA,
+        );
+        file
+    }
+
+    #[test]
+    fn loads_valid_rom() {
+        let cart = Cartridge::from_bytes(&ines_file(2, 1, 0)).unwrap();
+        assert_eq!(cart.prg_rom.len(), 0x8000);
+        assert_eq!(cart.chr_rom.len(), 0x2000);
+        assert_eq!(cart.get_prg_from_address(0xFFFC), 0xEA);
+    }
+
+    #[test]
+    fn rejects_short_file() {
+        let result = Cartridge::from_bytes(&[0x4E, 0x45, 0x53]);
+        assert!(matches!(
+            result,
+            Err(RomError::TruncatedHeader { length: 3 })
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut file = ines_file(1, 1, 0);
+        file[3] = 0x00;
+        assert!(matches!(
+            Cartridge::from_bytes(&file),
+            Err(RomError::BadMagic)
+        ));
+    }
+
+    #[test]
+    fn rejects_missing_prg() {
+        let file = ines_file(0, 1, 0);
+        assert!(matches!(
+            Cartridge::from_bytes(&file),
+            Err(RomError::BadHeader(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_prg_and_chr() {
+        let mut file = ines_file(2, 1, 0);
+        file.truncate(16 + 0x6000);
+        assert!(matches!(
+            Cartridge::from_bytes(&file),
+            Err(RomError::TruncatedPrg {
+                expected: 0x8000,
+                found: 0x6000
+            })
+        ));
+
+        let mut file = ines_file(2, 1, 0);
+        file.truncate(16 + 0x8000 + 0x100);
+        assert!(matches!(
+            Cartridge::from_bytes(&file),
+            Err(RomError::TruncatedChr {
+                expected: 0x2000,
+                found: 0x100
+            })
+        ));
+    }
+
+    #[test]
+    fn applies_same_named_patch() {
+        let dir = std::env::temp_dir().join(format!("nust-patch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("game.nes");
+        fs::write(&rom_path, ines_file(1, 1, 0)).unwrap();
+
+        // Patches cover the header too, so offset 16 is the first byte of PRG ROM
+        let mut ips = b"PATCH".to_vec();
+        ips.extend_from_slice(&[0x00, 0x00, 0x10, 0x00, 0x01, 0x4C]);
+        ips.extend_from_slice(b"EOF");
+        fs::write(dir.join("game.ips"), &ips).unwrap();
+
+        let cart = Cartridge::from_path(&rom_path).unwrap();
+        assert_eq!(cart.get_prg_from_address(0x8000), 0x4C);
+
+        // An explicit patch wins, and one made for another ROM is refused
+        let ups_path = dir.join("other.ups");
+        let mut ups = b"UPS1\x80\x80".to_vec();
+        ups.extend_from_slice(&[0; 8]);
+        ups.extend_from_slice(&crc32::crc32(&ups).to_le_bytes());
+        fs::write(&ups_path, &ups).unwrap();
+        let options = LoadOptions {
+            patch: Some(ups_path),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Cartridge::from_path_with(&rom_path, &options),
+            Err(RomError::Patch(patch::PatchError::SourceChecksum { .. }))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrects_header_from_database_file() {
+        let file = ines_file(1, 1, 0);
+        let mut sha1 = sha1::Sha1::new();
+        sha1.update(&file[16..]);
+        let sha1 = sha1::to_hex(&sha1.finish());
+
+        let path = std::env::temp_dir().join(format!("nust-db-{}.txt", std::process::id()));
+        fs::write(&path, format!("{}  34 - V 0 8192 0 PAL Test Game\n", sha1)).unwrap();
+        let options = LoadOptions {
+            database: Some(path.clone()),
+            ..Default::default()
+        };
+        let cart = Cartridge::from_bytes_with(&file, &options).unwrap();
+        assert_eq!(cart.header.mapper, 34);
+        assert_eq!(cart.header.mirroring(), Mirroring::Vertical);
+        assert!(cart.header.has_battery());
+        assert_eq!(cart.header.cpu_ppu_timing, TIMING_PAL);
+
+        // --no-db skips it
+        let options = LoadOptions {
+            use_database: false,
+            ..options
+        };
+        let cart = Cartridge::from_bytes_with(&file, &options).unwrap();
+        assert_eq!(cart.header.mapper, 0);
+
+        // NES 2.0 headers are left alone
+        let mut nes2 = file.clone();
+        nes2[7] |= 0x08;
+        let options = LoadOptions {
+            use_database: true,
+            ..options
+        };
+        let cart = Cartridge::from_bytes_with(&nes2, &options).unwrap();
+        assert_eq!(cart.header.mapper, 0);
+        assert_eq!(cart.header.cpu_ppu_timing, TIMING_NTSC);
+
+        fs::write(&path, "not a database").unwrap();
+        assert!(matches!(
+            Cartridge::from_bytes_with(
+                &file,
+                &LoadOptions {
+                    use_database: true,
+                    ..options
+                }
+            ),
+            Err(RomError::BadDatabase(_))
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn loads_disk_images_with_bios() {
+        let mut disk = b"\x01*NINTENDO-HVC*".to_vec();
+        disk.resize(fds::SIDE_SIZE * 2, 0);
+        disk[fds::SIDE_SIZE..fds::SIDE_SIZE + 15].copy_from_slice(b"\x01*NINTENDO-HVC*");
+        assert!(matches!(
+            Cartridge::from_bytes(&disk),
+            Err(RomError::MissingFdsBios)
+        ));
+
+        let bios_path = std::env::temp_dir().join(format!("nust-bios-{}.rom", std::process::id()));
+        fs::write(&bios_path, vec![0x4C; 0x2000]).unwrap();
+        let options = LoadOptions {
+            fds_bios: Some(bios_path.clone()),
+            ..Default::default()
+        };
+        let cart = Cartridge::from_bytes_with(&disk, &options).unwrap();
+        fs::remove_file(&bios_path).unwrap();
+
+        assert_eq!(cart.header.mapper, 20);
+        assert_eq!(cart.disk_sides(), 2);
+        assert_eq!(cart.get_prg_from_address(0xE000), 0x4C);
+        cart.eject_disk();
+        assert_eq!(
+            cart.disk_drive.unwrap().lock().unwrap().current_side(),
+            None
+        );
+    }
+
+    #[test]
+    fn saves_disk_writes() {
+        let mut disk = b"\x01*NINTENDO-HVC*".to_vec();
+        disk.resize(56, 0);
+        let dir = std::env::temp_dir().join(format!("nust-disk-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let bios_path = dir.join("disksys.rom");
+        fs::write(&bios_path, vec![0x4C; 0x2000]).unwrap();
+        let options = LoadOptions {
+            fds_bios: Some(bios_path),
+            ..Default::default()
+        };
+        let save_path = dir.join("game.sav");
+
+        let mut cart = Cartridge::from_bytes_with(&disk, &options).unwrap();
+        cart.attach_battery(save_path.clone()).unwrap();
+        cart.save_battery().unwrap();
+        assert!(!save_path.exists());
+
+        // Change the disk as if a game had written to it
+        let drive = cart.disk_drive.clone().unwrap();
+        let mut image = drive.lock().unwrap().disk_image();
+        image[1] = b'#';
+        drive.lock().unwrap().load_image(&image);
+        cart.save_battery().unwrap();
+        assert_eq!(fs::read(&save_path).unwrap(), image);
+
+        let mut cart = Cartridge::from_bytes_with(&disk, &options).unwrap();
+        cart.attach_battery(save_path).unwrap();
+        let drive = cart.disk_drive.unwrap();
+        assert_eq!(drive.lock().unwrap().disk_image()[1], b'#');
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn loads_nsf_as_player() {
+        let mut file = b"NESM\x1A\x01\x02\x01".to_vec();
+        file.extend_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x03, 0x80]);
+        file.resize(0x80, 0);
+        file.extend_from_slice(&[0x60; 6]);
+        let cart = Cartridge::from_bytes(&file).unwrap();
+
+        assert_eq!(cart.read_prg_word(0xFFFC), 0x4100);
+        assert_eq!(cart.nsf_track(), Some((0, 2)));
+        cart.select_track(1);
+        assert_eq!(cart.nsf_track(), Some((1, 2)));
+        assert_eq!(cart.header.cpu_ppu_timing, TIMING_NTSC);
+
+        let options = LoadOptions {
+            region: Some(Region::Dendy),
+            ..Default::default()
+        };
+        let cart = Cartridge::from_bytes_with(&file, &options).unwrap();
+        assert_eq!(cart.header.cpu_ppu_timing, TIMING_DENDY);
+        let cart = Cartridge::from_bytes_with(&ines_file(1, 1, 0), &options).unwrap();
+        assert_eq!(cart.header.cpu_ppu_timing, TIMING_DENDY);
+        assert_eq!(
+            Cartridge::from_bytes(&ines_file(1, 1, 0))
+                .unwrap()
+                .nsf_track(),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_mapper() {
+        let file = ines_file(1, 1, 0xFF);
+        assert!(matches!(
+            Cartridge::from_bytes(&file),
+            Err(RomError::UnsupportedMapper {
+                mapper: 0xFF,
+                submapper: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn reports_missing_file() {
+        let result = Cartridge::from_path("/nonexistent/rom.nes");
+        assert!(matches!(result, Err(RomError::Io(_))));
+    }
+
+    #[test]
+    fn loads_trainer_into_prg_ram() {
+        let mut file = ines_file(1, 1, 0);
+        file[6] |= 0x04;
+        let trainer: Vec<u8> = (0..0x200).map(|i| i as u8).collect();
+        file.splice(16..16, trainer);
+
+        let cart = Cartridge::from_bytes(&file).unwrap();
+        assert_eq!(cart.get_prg_from_address(0x7000), 0x00);
+        assert_eq!(cart.get_prg_from_address(0x71FF), 0xFF);
+        assert_eq!(cart.get_prg_from_address(0x8000), 0xEA);
+
+        file.truncate(16 + 0x100);
+        assert!(matches!(
+            Cartridge::from_bytes(&file),
+            Err(RomError::TruncatedTrainer { found: 0x100 })
+        ));
+    }
+
+    #[test]
+    fn allocates_chr_ram_without_chr_rom() {
+        let cart = Cartridge::from_bytes(&ines_file(1, 0, 0)).unwrap();
+        cart.set_chr_at_address(0x1FFF, 0x5A);
+        assert_eq!(cart.get_chr_from_address(0x1FFF), 0x5A);
+
+        // CHR ROM stays read only
+        let cart = Cartridge::from_bytes(&ines_file(1, 1, 0)).unwrap();
+        cart.set_chr_at_address(0x0000, 0x5A);
+        assert_eq!(cart.get_chr_from_address(0x0000), 0xEA);
+    }
+}
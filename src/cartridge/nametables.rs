@@ -0,0 +1,127 @@
+/**
+* Nametable memory and mirroring
+*
+* The console has 2K of CIRAM for nametables, enough for two of the four tables at
+* $2000-$2FFF; the cartridge decides how the four map onto it. Boards either solder the layout
+* (the header's mirroring bit), switch between the standard layouts at runtime
+* (`Mapper::mirroring`), map each 1K table arbitrarily (`Mapper::read_nametable`), or carry
+* their own extra 2K so all four tables are distinct (the header's four-screen bit).
+*/
+use super::mappers::Mirroring;
+use super::CartridgeHeader;
+
+pub struct Nametables {
+    // CIRAM, followed by the cart's extra 2K on four-screen boards
+    pub vram: Vec<u8>,
+    // Layout soldered on the board, used when the mapper doesn't pick one
+    pub hardwired: Mirroring,
+}
+
+impl Nametables {
+    pub fn new(header: &CartridgeHeader) -> Nametables {
+        Nametables::with_mirroring(header.mirroring())
+    }
+
+    pub fn with_mirroring(hardwired: Mirroring) -> Nametables {
+        let size = if hardwired == Mirroring::FourScreen {
+            0x1000
+        } else {
+            0x800
+        };
+        Nametables {
+            vram: vec![0; size],
+            hardwired,
+        }
+    }
+
+    // Four-screen boards wire every table to their own RAM, whatever the mapper asks for
+    pub fn resolve(&self, mirroring: Option<Mirroring>) -> Mirroring {
+        if self.hardwired == Mirroring::FourScreen {
+            return Mirroring::FourScreen;
+        }
+        mirroring.unwrap_or(self.hardwired)
+    }
+
+    pub fn read(&self, mirroring: Option<Mirroring>, address: u16) -> u8 {
+        self.vram[self.resolve(mirroring).vram_offset(address)]
+    }
+
+    pub fn write(&mut self, mirroring: Option<Mirroring>, address: u16, value: u8) {
+        let offset = self.resolve(mirroring).vram_offset(address);
+        self.vram[offset] = value;
+    }
+
+    // Direct access to one 1K page, for mappers that map tables arbitrarily
+    pub fn read_page(&self, page: usize, address: u16) -> u8 {
+        self.vram[(page * 0x400 + (address as usize & 0x3FF)) % self.vram.len()]
+    }
+
+    pub fn write_page(&mut self, page: usize, address: u16, value: u8) {
+        let len = self.vram.len();
+        self.vram[(page * 0x400 + (address as usize & 0x3FF)) % len] = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes a distinct value to each of the four tables and returns what each one reads back
+    fn layout(nametables: &mut Nametables, mirroring: Option<Mirroring>) -> [u8; 4] {
+        for table in 0..4 {
+            nametables.write(mirroring, 0x2000 + table * 0x400, table as u8 + 1);
+        }
+        [0, 1, 2, 3].map(|table| nametables.read(mirroring, 0x2000 + table * 0x400))
+    }
+
+    #[test]
+    fn standard_layouts() {
+        let mut n = Nametables::with_mirroring(Mirroring::Vertical);
+        assert_eq!(layout(&mut n, None), [3, 4, 3, 4]);
+        assert_eq!(layout(&mut n, Some(Mirroring::Horizontal)), [2, 2, 4, 4]);
+        assert_eq!(layout(&mut n, Some(Mirroring::SingleScreenA)), [4, 4, 4, 4]);
+        assert_eq!(n.read_page(0, 0x2000), 4);
+
+        let mut n = Nametables::with_mirroring(Mirroring::Horizontal);
+        assert_eq!(layout(&mut n, None), [2, 2, 4, 4]);
+        assert_eq!(layout(&mut n, Some(Mirroring::SingleScreenB)), [4, 4, 4, 4]);
+        assert_eq!(n.read_page(1, 0x2000), 4);
+    }
+
+    #[test]
+    fn four_screen_ignores_mapper() {
+        let mut n = Nametables::with_mirroring(Mirroring::FourScreen);
+        assert_eq!(layout(&mut n, Some(Mirroring::Horizontal)), [1, 2, 3, 4]);
+        assert_eq!(n.read_page(3, 0x2C00), 4);
+    }
+
+    #[test]
+    fn mirrors_3000_range() {
+        let mut n = Nametables::with_mirroring(Mirroring::Vertical);
+        n.write(None, 0x2C05, 0x42);
+        assert_eq!(n.read(None, 0x3C05), 0x42);
+        assert_eq!(n.read(None, 0x2405), 0x42);
+    }
+
+    #[test]
+    fn header_selects_hardwired_layout() {
+        let mut file = [0; 16];
+        file[0..4].copy_from_slice(&[0x4E, 0x45, 0x53, 0x1A]);
+        file[6] = 0x01;
+        assert_eq!(
+            Nametables::new(&CartridgeHeader::parse(&file)).hardwired,
+            Mirroring::Vertical
+        );
+
+        file[6] = 0x00;
+        assert_eq!(
+            Nametables::new(&CartridgeHeader::parse(&file)).hardwired,
+            Mirroring::Horizontal
+        );
+
+        file[6] = 0x09;
+        let n = Nametables::new(&CartridgeHeader::parse(&file));
+        assert_eq!(n.hardwired, Mirroring::FourScreen);
+        assert_eq!(n.vram.len(), 0x1000);
+    }
+}
@@ -0,0 +1,320 @@
+/**
+* NSF and NSFe music files
+*
+* NSF: a 128 byte header ("NESM\x1A", song count, load/INIT/PLAY addresses, titles, PLAY rates,
+* initial banks, region, expansion chips) followed by the program data.
+* NSFe: "NSFE" and chunks of a little endian u32 length, four character ID and the data. Chunks
+* used here:
+*   INFO: addresses, region, chips, song count and first song, DATA: program data,
+*   BANK: initial banks, RATE: PLAY rates, auth: title/artist/copyright/ripper,
+*   tlbl: track titles, time: track lengths in milliseconds, NEND: end
+* Other chunks are skipped unless their ID starts with a capital letter, which marks them as
+* required for playback.
+*/
+use std::time::Duration;
+
+use super::RomError;
+
+// Microseconds between PLAY calls when the file doesn't say
+const NTSC_SPEED: u16 = 16639;
+const PAL_SPEED: u16 = 19997;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Nsf {
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub song_count: usize,
+    // 0-based
+    pub starting_song: usize,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    // Microseconds between PLAY calls
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    // Bit 0: PAL, bit 1: both
+    pub region: u8,
+    pub sound_chips: u8,
+    // Initial $8000-$FFFF banks, all zero when the tune doesn't bankswitch
+    pub banks: [u8; 8],
+    pub data: Vec<u8>,
+    pub track_titles: Vec<String>,
+    pub track_durations: Vec<Option<Duration>>,
+}
+
+impl Nsf {
+    pub fn is_bankswitched(&self) -> bool {
+        self.banks.iter().any(|&bank| bank != 0)
+    }
+
+    pub fn is_pal(&self) -> bool {
+        self.region & 0x03 == 0x01
+    }
+
+    pub fn track_title(&self, track: usize) -> Option<&str> {
+        self.track_titles
+            .get(track)
+            .map(|title| title.as_str())
+            .filter(|title| !title.is_empty())
+    }
+
+    pub fn track_duration(&self, track: usize) -> Option<Duration> {
+        self.track_durations.get(track).copied().flatten()
+    }
+}
+
+pub fn is_nsf(file: &[u8]) -> bool {
+    file.starts_with(b"NESM\x1A") || file.starts_with(b"NSFE")
+}
+
+pub fn parse(file: &[u8]) -> Result<Nsf, RomError> {
+    let nsf = if file.starts_with(b"NSFE") {
+        parse_nsfe(file)?
+    } else {
+        parse_nsf(file)?
+    };
+
+    if nsf.song_count == 0 {
+        return Err(RomError::BadHeader("no songs".to_string()));
+    }
+    if nsf.load_address < 0x8000 {
+        return Err(RomError::BadHeader(format!(
+            "load address {:04X} is below $8000",
+            nsf.load_address
+        )));
+    }
+    Ok(nsf)
+}
+
+fn parse_nsf(file: &[u8]) -> Result<Nsf, RomError> {
+    if file.len() < 0x80 {
+        return Err(RomError::TruncatedHeader { length: file.len() });
+    }
+
+    let mut banks = [0; 8];
+    banks.copy_from_slice(&file[0x70..0x78]);
+    Ok(Nsf {
+        title: string(&file[0x0E..0x2E]),
+        artist: string(&file[0x2E..0x4E]),
+        copyright: string(&file[0x4E..0x6E]),
+        song_count: file[0x06] as usize,
+        starting_song: (file[0x07] as usize).saturating_sub(1),
+        load_address: u16_at(file, 0x08),
+        init_address: u16_at(file, 0x0A),
+        play_address: u16_at(file, 0x0C),
+        ntsc_speed: speed(u16_at(file, 0x6E), NTSC_SPEED),
+        pal_speed: speed(u16_at(file, 0x78), PAL_SPEED),
+        region: file[0x7A],
+        sound_chips: file[0x7B],
+        banks,
+        data: file[0x80..].to_vec(),
+        ..Default::default()
+    })
+}
+
+fn parse_nsfe(file: &[u8]) -> Result<Nsf, RomError> {
+    let mut nsf = Nsf {
+        ntsc_speed: NTSC_SPEED,
+        pal_speed: PAL_SPEED,
+        song_count: 1,
+        ..Default::default()
+    };
+    let mut has_info = false;
+    let mut has_data = false;
+
+    let mut position = 4;
+    loop {
+        if file.len() - position < 8 {
+            return Err(RomError::TruncatedChunk("chunk header".to_string()));
+        }
+        let length = u32::from_le_bytes([
+            file[position],
+            file[position + 1],
+            file[position + 2],
+            file[position + 3],
+        ]) as usize;
+        let id = String::from_utf8_lossy(&file[position + 4..position + 8]).to_string();
+        let start = position + 8;
+        if file.len() - start < length {
+            return Err(RomError::TruncatedChunk(id));
+        }
+        let data = &file[start..start + length];
+        position = start + length;
+
+        match id.as_str() {
+            "INFO" => {
+                if data.len() < 9 {
+                    return Err(RomError::TruncatedChunk(id));
+                }
+                nsf.load_address = u16_at(data, 0);
+                nsf.init_address = u16_at(data, 2);
+                nsf.play_address = u16_at(data, 4);
+                nsf.region = data[6];
+                nsf.sound_chips = data[7];
+                nsf.song_count = data[8] as usize;
+                nsf.starting_song = data.get(9).copied().unwrap_or(0) as usize;
+                has_info = true;
+            }
+            "DATA" => {
+                nsf.data = data.to_vec();
+                has_data = true;
+            }
+            "BANK" => {
+                for (bank, &value) in nsf.banks.iter_mut().zip(data) {
+                    *bank = value;
+                }
+            }
+            "RATE" => {
+                if data.len() >= 2 {
+                    nsf.ntsc_speed = speed(u16_at(data, 0), NTSC_SPEED);
+                }
+                if data.len() >= 4 {
+                    nsf.pal_speed = speed(u16_at(data, 2), PAL_SPEED);
+                }
+            }
+            "auth" => {
+                let mut fields = data.split(|&byte| byte == 0).map(string);
+                nsf.title = fields.next().unwrap_or_default();
+                nsf.artist = fields.next().unwrap_or_default();
+                nsf.copyright = fields.next().unwrap_or_default();
+            }
+            "tlbl" => {
+                nsf.track_titles = data.split(|&byte| byte == 0).map(string).collect();
+            }
+            "time" => {
+                nsf.track_durations = data
+                    .chunks_exact(4)
+                    .map(|time| {
+                        let milliseconds = i32::from_le_bytes([time[0], time[1], time[2], time[3]]);
+                        (milliseconds >= 0).then(|| Duration::from_millis(milliseconds as u64))
+                    })
+                    .collect();
+            }
+            "NEND" => break,
+            _ if id.starts_with(|c: char| c.is_ascii_uppercase()) => {
+                return Err(RomError::BadHeader(format!(
+                    "unsupported required chunk {}",
+                    id
+                )));
+            }
+            _ => {}
+        }
+    }
+
+    if !has_info {
+        return Err(RomError::BadHeader("no INFO chunk".to_string()));
+    }
+    if !has_data {
+        return Err(RomError::BadHeader("no DATA chunk".to_string()));
+    }
+    Ok(nsf)
+}
+
+fn speed(speed: u16, default: u16) -> u16 {
+    if speed == 0 {
+        return default;
+    }
+    speed
+}
+
+// Null terminated (or padded) text
+fn string(bytes: &[u8]) -> String {
+    let end = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+fn u16_at(data: &[u8], position: usize) -> u16 {
+    u16::from_le_bytes([data[position], data[position + 1]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &str, data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_le_bytes().to_vec();
+        chunk.extend_from_slice(id.as_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    fn nsf_file() -> Vec<u8> {
+        let mut file = b"NESM\x1A\x01\x03\x02".to_vec();
+        file.extend_from_slice(&[0x00, 0x80, 0x03, 0x80, 0x06, 0x80]);
+        file.resize(0x80, 0);
+        file[0x0E..0x13].copy_from_slice(b"Title");
+        file[0x2E..0x34].copy_from_slice(b"Artist");
+        file[0x6E..0x70].copy_from_slice(&16666u16.to_le_bytes());
+        file.extend_from_slice(&[0x60; 0x10]);
+        file
+    }
+
+    #[test]
+    fn reads_nsf_header() {
+        let nsf = parse(&nsf_file()).unwrap();
+        assert_eq!(nsf.title, "Title");
+        assert_eq!(nsf.artist, "Artist");
+        assert_eq!(nsf.song_count, 3);
+        assert_eq!(nsf.starting_song, 1);
+        assert_eq!(
+            (nsf.load_address, nsf.init_address, nsf.play_address),
+            (0x8000, 0x8003, 0x8006)
+        );
+        assert_eq!(nsf.ntsc_speed, 16666);
+        assert_eq!(nsf.pal_speed, PAL_SPEED);
+        assert!(!nsf.is_bankswitched());
+        assert_eq!(nsf.data.len(), 0x10);
+    }
+
+    #[test]
+    fn reads_nsfe_chunks() {
+        let mut file = b"NSFE".to_vec();
+        file.extend(chunk(
+            "INFO",
+            &[0x00, 0x80, 0x03, 0x80, 0x06, 0x80, 0x01, 0x00, 0x02, 0x01],
+        ));
+        file.extend(chunk("DATA", &[0x60; 4]));
+        file.extend(chunk("BANK", &[0, 1, 2, 3, 4, 5, 6, 7]));
+        file.extend(chunk("auth", b"Game\0Composer\0\0Ripper\0"));
+        file.extend(chunk("tlbl", b"Intro\0Boss\0"));
+        let mut times = 90000i32.to_le_bytes().to_vec();
+        times.extend_from_slice(&(-1i32).to_le_bytes());
+        file.extend(chunk("time", &times));
+        file.extend(chunk("text", b"skipped"));
+        file.extend(chunk("NEND", &[]));
+
+        let nsf = parse(&file).unwrap();
+        assert_eq!(nsf.title, "Game");
+        assert_eq!(nsf.artist, "Composer");
+        assert_eq!(nsf.song_count, 2);
+        assert_eq!(nsf.starting_song, 1);
+        assert!(nsf.is_pal());
+        assert!(nsf.is_bankswitched());
+        assert_eq!(nsf.track_title(1), Some("Boss"));
+        assert_eq!(nsf.track_duration(0), Some(Duration::from_secs(90)));
+        assert_eq!(nsf.track_duration(1), None);
+        assert_eq!(nsf.track_duration(5), None);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let mut file = nsf_file();
+        file[0x06] = 0;
+        assert!(matches!(parse(&file), Err(RomError::BadHeader(_))));
+
+        let mut file = b"NSFE".to_vec();
+        file.extend(chunk("DATA", &[0x60]));
+        file.extend(chunk("VRC7", &[]));
+        file.extend(chunk("NEND", &[]));
+        assert!(matches!(parse(&file), Err(RomError::BadHeader(_))));
+
+        assert!(matches!(
+            parse(&nsf_file()[..0x40]),
+            Err(RomError::TruncatedHeader { length: 0x40 })
+        ));
+    }
+}
@@ -0,0 +1,531 @@
+/**
+* ROM patching: IPS, UPS and BPS
+*
+* Patches apply to the whole ROM file, header included, before it gets parsed.
+*   IPS: "PATCH", then records of a 24-bit offset and 16-bit length (0 for a run of one byte),
+*        up to "EOF" and an optional 24-bit size to truncate to. No checksums.
+*   UPS: "UPS1", source and target sizes, then runs of bytes XORed into the source. Ends with
+*        CRC-32s of the source, target and patch.
+*   BPS: "BPS1", source, target and metadata sizes, then copy commands reading from the source,
+*        the patch or the target written so far. Ends with the same three CRC-32s as UPS.
+*/
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use super::crc32::crc32;
+
+// Far bigger than any NES ROM; stops a corrupt size field from asking for gigabytes up front
+const MAX_TARGET_SIZE: usize = 64 << 20;
+
+#[derive(Debug, PartialEq)]
+pub enum PatchError {
+    UnknownFormat,
+    // The patch ends in the middle of a record
+    Truncated,
+    // A record writes or copies outside the ROM
+    OutOfBounds,
+    TargetTooLarge { size: usize },
+    SourceSize { expected: usize, found: usize },
+    SourceChecksum { expected: u32, found: u32 },
+    TargetChecksum { expected: u32, found: u32 },
+    PatchChecksum { expected: u32, found: u32 },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "not an IPS, UPS or BPS patch"),
+            PatchError::Truncated => write!(f, "patch is truncated"),
+            PatchError::OutOfBounds => write!(f, "patch writes outside the ROM"),
+            PatchError::TargetTooLarge { size } => {
+                write!(f, "patch would make a {} byte ROM", size)
+            }
+            PatchError::SourceSize { expected, found } => write!(
+                f,
+                "patch is for a {} byte ROM, this one is {} bytes",
+                expected, found
+            ),
+            PatchError::SourceChecksum { expected, found } => write!(
+                f,
+                "patch is for a ROM with CRC32 {:08X}, this one is {:08X}",
+                expected, found
+            ),
+            PatchError::TargetChecksum { expected, found } => write!(
+                f,
+                "patched ROM has CRC32 {:08X}, expected {:08X}",
+                found, expected
+            ),
+            PatchError::PatchChecksum { expected, found } => write!(
+                f,
+                "patch has CRC32 {:08X}, expected {:08X}",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+// `<rom>.ips`, `.ups` or `.bps` next to the ROM, whichever exists first
+pub fn find_patch(rom_path: &Path) -> Option<PathBuf> {
+    ["ips", "ups", "bps"]
+        .iter()
+        .map(|extension| rom_path.with_extension(extension))
+        .find(|path| path.is_file())
+}
+
+pub fn apply_patch(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(b"PATCH") {
+        return apply_ips(rom, patch);
+    }
+    if patch.starts_with(b"UPS1") {
+        return apply_ups(rom, patch);
+    }
+    if patch.starts_with(b"BPS1") {
+        return apply_bps(rom, patch);
+    }
+    Err(PatchError::UnknownFormat)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], position: usize) -> Reader<'a> {
+        Reader { data, position }
+    }
+
+    fn byte(&mut self) -> Result<u8, PatchError> {
+        let byte = *self.data.get(self.position).ok_or(PatchError::Truncated)?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], PatchError> {
+        if self.data.len() - self.position < count {
+            return Err(PatchError::Truncated);
+        }
+        let bytes = &self.data[self.position..self.position + count];
+        self.position += count;
+        Ok(bytes)
+    }
+
+    // Big endian, for IPS
+    fn big_endian(&mut self, count: usize) -> Result<usize, PatchError> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 8) | self.byte()? as usize;
+        }
+        Ok(value)
+    }
+
+    // UPS/BPS variable length number: 7 bits per byte, last byte flagged with bit 7, and each
+    // continuation adding one so every number has a single encoding
+    fn number(&mut self) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.byte()?;
+            value = value
+                .checked_add((byte as usize & 0x7F).saturating_mul(shift))
+                .ok_or(PatchError::OutOfBounds)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).ok_or(PatchError::OutOfBounds)?;
+            value = value.checked_add(shift).ok_or(PatchError::OutOfBounds)?;
+        }
+    }
+
+    fn u32_le(&mut self) -> Result<u32, PatchError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut target = rom.to_vec();
+    let mut reader = Reader::new(patch, 5);
+
+    loop {
+        if reader.data[reader.position..].starts_with(b"EOF") {
+            reader.position += 3;
+            break;
+        }
+        let offset = reader.big_endian(3)?;
+        let length = reader.big_endian(2)?;
+        let data = if length == 0 {
+            let run = reader.big_endian(2)?;
+            vec![reader.byte()?; run]
+        } else {
+            reader.bytes(length)?.to_vec()
+        };
+
+        if target.len() < offset + data.len() {
+            target.resize(offset + data.len(), 0);
+        }
+        target[offset..offset + data.len()].copy_from_slice(&data);
+    }
+
+    // Lunar IPS truncation extension
+    if let Ok(size) = reader.big_endian(3) {
+        target.truncate(size);
+    }
+    Ok(target)
+}
+
+// Source, target and patch CRC-32s at the end of UPS and BPS patches
+fn check_footer(rom: &[u8], patch: &[u8]) -> Result<(u32, usize), PatchError> {
+    if patch.len() < 16 {
+        return Err(PatchError::Truncated);
+    }
+    let footer = patch.len() - 12;
+    let mut reader = Reader::new(patch, footer);
+    let source_crc = reader.u32_le()?;
+    let target_crc = reader.u32_le()?;
+    let patch_crc = reader.u32_le()?;
+
+    let found = crc32(&patch[..patch.len() - 4]);
+    if found != patch_crc {
+        return Err(PatchError::PatchChecksum {
+            expected: patch_crc,
+            found,
+        });
+    }
+    let found = crc32(rom);
+    if found != source_crc {
+        return Err(PatchError::SourceChecksum {
+            expected: source_crc,
+            found,
+        });
+    }
+    Ok((target_crc, footer))
+}
+
+fn check_target_size(size: usize) -> Result<(), PatchError> {
+    if size > MAX_TARGET_SIZE {
+        return Err(PatchError::TargetTooLarge { size });
+    }
+    Ok(())
+}
+
+fn check_target(target: &[u8], expected: u32) -> Result<(), PatchError> {
+    let found = crc32(target);
+    if found != expected {
+        return Err(PatchError::TargetChecksum { expected, found });
+    }
+    Ok(())
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (target_crc, footer) = check_footer(rom, patch)?;
+    let mut reader = Reader::new(&patch[..footer], 4);
+
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    if source_size != rom.len() {
+        return Err(PatchError::SourceSize {
+            expected: source_size,
+            found: rom.len(),
+        });
+    }
+    check_target_size(target_size)?;
+
+    let mut target = rom.to_vec();
+    target.resize(target_size, 0);
+
+    let mut output: usize = 0;
+    while reader.position < footer {
+        output = output
+            .checked_add(reader.number()?)
+            .ok_or(PatchError::OutOfBounds)?;
+        loop {
+            let byte = reader.byte()?;
+            let next = output.checked_add(1).ok_or(PatchError::OutOfBounds)?;
+            if byte == 0 {
+                output = next;
+                break;
+            }
+            *target.get_mut(output).ok_or(PatchError::OutOfBounds)? ^= byte;
+            output = next;
+        }
+    }
+
+    check_target(&target, target_crc)?;
+    Ok(target)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (target_crc, footer) = check_footer(rom, patch)?;
+    let mut reader = Reader::new(&patch[..footer], 4);
+
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    let metadata_size = reader.number()?;
+    reader.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(PatchError::SourceSize {
+            expected: source_size,
+            found: rom.len(),
+        });
+    }
+    check_target_size(target_size)?;
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+    while reader.position < footer {
+        let command = reader.number()?;
+        let length = (command >> 2) + 1;
+        if length > target_size - target.len() {
+            return Err(PatchError::OutOfBounds);
+        }
+        match command & 0x03 {
+            // SourceRead: the same bytes as the source at the output position
+            0 => {
+                let start = target.len();
+                let end = start.checked_add(length).ok_or(PatchError::OutOfBounds)?;
+                let bytes = rom.get(start..end).ok_or(PatchError::OutOfBounds)?;
+                target.extend_from_slice(bytes);
+            }
+            // TargetRead: bytes from the patch
+            1 => target.extend_from_slice(reader.bytes(length)?),
+            // SourceCopy: from anywhere in the source
+            2 => {
+                source_offset = relative(source_offset, reader.number()?)?;
+                let end = source_offset
+                    .checked_add(length)
+                    .ok_or(PatchError::OutOfBounds)?;
+                let bytes = rom.get(source_offset..end).ok_or(PatchError::OutOfBounds)?;
+                target.extend_from_slice(bytes);
+                source_offset = end;
+            }
+            // TargetCopy: from the output written so far, one byte at a time so runs can
+            // overlap the bytes they produce
+            _ => {
+                target_offset = relative(target_offset, reader.number()?)?;
+                for _ in 0..length {
+                    let byte = *target.get(target_offset).ok_or(PatchError::OutOfBounds)?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    check_target(&target, target_crc)?;
+    Ok(target)
+}
+
+// BPS copy offsets are signed, with the sign in bit 0
+fn relative(offset: usize, encoded: usize) -> Result<usize, PatchError> {
+    let distance = encoded >> 1;
+    let moved = if encoded & 0x01 != 0 {
+        offset.checked_sub(distance)
+    } else {
+        offset.checked_add(distance)
+    };
+    moved.ok_or(PatchError::OutOfBounds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(mut value: usize) -> Vec<u8> {
+        let mut bytes = vec![];
+        loop {
+            let low = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(0x80 | low);
+                return bytes;
+            }
+            bytes.push(low);
+            value -= 1;
+        }
+    }
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let patch_crc = crc32(&patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn variable_length_numbers_round_trip() {
+        for value in [0, 1, 127, 128, 300, 16511, 16512, 1 << 30] {
+            let bytes = number(value);
+            assert_eq!(Reader::new(&bytes, 0).number(), Ok(value));
+        }
+    }
+
+    #[test]
+    fn ips_records_runs_and_truncation() {
+        let rom = vec![0; 8];
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+        patch.extend_from_slice(&[0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x02, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(
+            apply_patch(&rom, &patch).unwrap(),
+            [0, 0xAA, 0xBB, 0, 0, 0xCC, 0xCC, 0]
+        );
+
+        // Records past the end grow the ROM, and a trailing size truncates it
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x0A, 0x00, 0x01, 0x11]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply_patch(&rom, &patch).unwrap().len(), 11);
+        patch.extend_from_slice(&[0x00, 0x00, 0x04]);
+        assert_eq!(apply_patch(&rom, &patch).unwrap(), [0, 0, 0, 0]);
+
+        let patch = b"PATCH\x00\x00\x01\x00\x05\xAA".to_vec();
+        assert_eq!(apply_patch(&rom, &patch), Err(PatchError::Truncated));
+    }
+
+    #[test]
+    fn ups_xors_and_checks_crcs() {
+        let source = vec![1, 2, 3, 4, 5];
+        let target = vec![1, 9, 3, 4, 5, 6];
+
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(target.len()));
+        patch.extend(number(1));
+        patch.extend_from_slice(&[2 ^ 9, 0]);
+        patch.extend(number(2));
+        patch.extend_from_slice(&[6, 0]);
+        let patch = with_footer(patch, &source, &target);
+        assert_eq!(apply_patch(&source, &patch).unwrap(), target);
+
+        let wrong_source = vec![1, 2, 3, 4, 6];
+        assert_eq!(
+            apply_patch(&wrong_source, &patch),
+            Err(PatchError::SourceChecksum {
+                expected: crc32(&source),
+                found: crc32(&wrong_source)
+            })
+        );
+
+        let mut corrupt = patch.clone();
+        corrupt[8] ^= 0xFF;
+        assert!(matches!(
+            apply_patch(&source, &corrupt),
+            Err(PatchError::PatchChecksum { .. })
+        ));
+    }
+
+    #[test]
+    fn bps_commands() {
+        let source = b"ABCDEFGH".to_vec();
+        let target = b"ABxyEFEFEFCD".to_vec();
+
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(target.len()));
+        patch.extend(number(0));
+        // SourceRead 2: "AB"
+        patch.extend(number(1 << 2));
+        // TargetRead 2: "xy"
+        patch.extend(number((1 << 2) | 1));
+        patch.extend_from_slice(b"xy");
+        // SourceCopy 2 from +4: "EF"
+        patch.extend(number((1 << 2) | 2));
+        patch.extend(number(4 << 1));
+        // TargetCopy 4 from +4: "EFEF", overlapping its own output
+        patch.extend(number((3 << 2) | 3));
+        patch.extend(number(4 << 1));
+        // SourceCopy 2 from -4 (6 back to 2): "CD"
+        patch.extend(number((1 << 2) | 2));
+        patch.extend(number((4 << 1) | 1));
+        let patch = with_footer(patch, &source, &target);
+
+        assert_eq!(apply_patch(&source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn bps_reports_target_mismatch() {
+        let source = b"ABCD".to_vec();
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(number(4));
+        patch.extend(number(4));
+        patch.extend(number(0));
+        patch.extend(number(3 << 2));
+        let patch = with_footer(patch, &source, b"WXYZ");
+
+        assert!(matches!(
+            apply_patch(&source, &patch),
+            Err(PatchError::TargetChecksum { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_huge_target_sizes() {
+        let source = vec![0; 4];
+        let size = 1 << 40;
+
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(size));
+        let patch = with_footer(patch, &source, &[]);
+        assert_eq!(
+            apply_patch(&source, &patch),
+            Err(PatchError::TargetTooLarge { size })
+        );
+
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(size));
+        patch.extend(number(0));
+        let patch = with_footer(patch, &source, &[]);
+        assert_eq!(
+            apply_patch(&source, &patch),
+            Err(PatchError::TargetTooLarge { size })
+        );
+    }
+
+    #[test]
+    fn rejects_overflowing_copies() {
+        let source = b"ABCD".to_vec();
+        let header = |patch: &mut Vec<u8>| {
+            patch.extend(number(source.len()));
+            patch.extend(number(4));
+            patch.extend(number(0));
+        };
+
+        // SourceCopy with a length and offset near the top of the address space
+        let mut patch = b"BPS1".to_vec();
+        header(&mut patch);
+        patch.extend(number(usize::MAX & !0x03 | 2));
+        patch.extend(number(usize::MAX & !0x01));
+        let patch = with_footer(patch, &source, b"ABCD");
+        assert_eq!(apply_patch(&source, &patch), Err(PatchError::OutOfBounds));
+
+        // SourceCopy from far past the end of the source
+        let mut patch = b"BPS1".to_vec();
+        header(&mut patch);
+        patch.extend(number(2));
+        patch.extend(number(usize::MAX & !0x01));
+        let patch = with_footer(patch, &source, b"ABCD");
+        assert_eq!(apply_patch(&source, &patch), Err(PatchError::OutOfBounds));
+
+        // TargetCopy longer than the target
+        let mut patch = b"BPS1".to_vec();
+        header(&mut patch);
+        patch.extend(number(0));
+        patch.extend(number(usize::MAX & !0x03 | 3));
+        patch.extend(number(0));
+        let patch = with_footer(patch, &source, b"ABCD");
+        assert_eq!(apply_patch(&source, &patch), Err(PatchError::OutOfBounds));
+    }
+
+    #[test]
+    fn rejects_unknown_format() {
+        assert_eq!(apply_patch(&[0], b"NOPE"), Err(PatchError::UnknownFormat));
+    }
+}
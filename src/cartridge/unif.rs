@@ -0,0 +1,347 @@
+/**
+* UNIF (Universal NES Image Format) loader
+*
+* A 32 byte header ("UNIF", a little endian revision and padding) followed by chunks of a four
+* character ID, a little endian u32 length and the data. Instead of a mapper number the MAPR
+* chunk names the board, which gets looked up in `BOARDS`. Chunks used here:
+*   MAPR: board name, PRG0-PRGF / CHR0-CHRF: ROM chips in order, MIRR: mirroring,
+*   BATR: battery present, TVCI: TV system
+*/
+use super::{CartridgeHeader, Mirroring, RomError, TIMING_MULTI_REGION, TIMING_NTSC, TIMING_PAL};
+
+// Board name (without its manufacturer prefix) to (mapper, submapper). The Konami chips are
+// named by variant, since each one wires the register select pins differently.
+const BOARDS: &[(&str, u16, u8)] = &[
+    ("NROM", 0, 0),
+    ("NROM-128", 0, 0),
+    ("NROM-256", 0, 0),
+    ("EKROM", 5, 0),
+    ("ELROM", 5, 0),
+    ("ETROM", 5, 0),
+    ("EWROM", 5, 0),
+    ("BNROM", 34, 2),
+    ("NINA-001", 34, 1),
+    ("NINA-01", 34, 1),
+    ("NAMCO-163", 19, 0),
+    ("NAMCO-129", 19, 0),
+    ("163", 19, 0),
+    ("129", 19, 0),
+    ("VRC2A", 22, 0),
+    ("VRC2B", 23, 3),
+    ("VRC2C", 25, 3),
+    ("VRC4A", 21, 1),
+    ("VRC4B", 25, 1),
+    ("VRC4C", 21, 2),
+    ("VRC4D", 25, 2),
+    ("VRC4E", 23, 2),
+    ("VRC4F", 23, 1),
+    ("VRC6A", 24, 0),
+    ("VRC6B", 26, 0),
+    ("VRC7", 85, 0),
+    ("VRC7A", 85, 2),
+    ("VRC7B", 85, 1),
+    ("JLROM", 69, 0),
+    ("JSROM", 69, 0),
+    ("BTR", 69, 0),
+    ("BF9093", 71, 2),
+    ("BF9097", 71, 1),
+    ("NINA-03", 79, 0),
+    ("JF-11", 140, 0),
+    ("JF-14", 140, 0),
+    ("NINA-06", 79, 0),
+    ("Sachen-8259D", 137, 0),
+    ("Sachen-8259B", 138, 0),
+    ("Sachen-8259C", 139, 0),
+    ("Sachen-8259A", 141, 0),
+    ("64in1", 225, 0),
+    ("52Games", 225, 0),
+    ("76in1", 226, 0),
+    ("1200in1", 227, 0),
+    ("MLT-ACTION52", 228, 0),
+];
+
+const PREFIXES: &[&str] = &[
+    "NES-",
+    "UNL-",
+    "HVC-",
+    "BTL-",
+    "BMC-",
+    "KONAMI-",
+    "NAMCOT-",
+    "CAMERICA-",
+    "AVE-",
+    "JALECO-",
+];
+
+pub fn is_unif(file: &[u8]) -> bool {
+    file.starts_with(b"UNIF")
+}
+
+// Looks up a board by name, ignoring case and the manufacturer prefix
+pub fn board_mapper(name: &str) -> Option<(u16, u8)> {
+    let mut name = name.trim();
+    for prefix in PREFIXES {
+        if name.len() > prefix.len() && name[..prefix.len()].eq_ignore_ascii_case(prefix) {
+            name = &name[prefix.len()..];
+            break;
+        }
+    }
+
+    BOARDS
+        .iter()
+        .find(|(board, _, _)| board.eq_ignore_ascii_case(name))
+        .map(|&(_, mapper, submapper)| (mapper, submapper))
+}
+
+// Returns the header equivalent of the UNIF chunks, with PRG and CHR ROM
+pub fn parse(file: &[u8]) -> Result<(CartridgeHeader, Vec<u8>, Vec<u8>), RomError> {
+    if file.len() < 32 {
+        return Err(RomError::TruncatedHeader { length: file.len() });
+    }
+
+    let mut board = None;
+    let mut prg_chips: [Vec<u8>; 16] = Default::default();
+    let mut chr_chips: [Vec<u8>; 16] = Default::default();
+    let mut mirroring = None;
+    let mut battery = false;
+    let mut timing = TIMING_NTSC;
+
+    let mut position = 32;
+    while position < file.len() {
+        if file.len() - position < 8 {
+            return Err(RomError::TruncatedChunk("chunk header".to_string()));
+        }
+        let id = String::from_utf8_lossy(&file[position..position + 4]).to_string();
+        let length = u32::from_le_bytes([
+            file[position + 4],
+            file[position + 5],
+            file[position + 6],
+            file[position + 7],
+        ]) as usize;
+        let start = position + 8;
+        if file.len() - start < length {
+            return Err(RomError::TruncatedChunk(id));
+        }
+        let data = &file[start..start + length];
+        position = start + length;
+
+        match id.as_str() {
+            "MAPR" => {
+                let end = data
+                    .iter()
+                    .position(|&byte| byte == 0)
+                    .unwrap_or(data.len());
+                board = Some(String::from_utf8_lossy(&data[..end]).to_string());
+            }
+            "MIRR" => mirroring = data.first().copied(),
+            "BATR" => battery = data.first() != Some(&0),
+            "TVCI" => {
+                timing = match data.first() {
+                    Some(1) => TIMING_PAL,
+                    Some(2) => TIMING_MULTI_REGION,
+                    _ => TIMING_NTSC,
+                }
+            }
+            _ => {
+                if let Some(chip) = chip_number(&id, "PRG") {
+                    prg_chips[chip] = data.to_vec();
+                } else if let Some(chip) = chip_number(&id, "CHR") {
+                    chr_chips[chip] = data.to_vec();
+                }
+            }
+        }
+    }
+
+    let board = board.ok_or(RomError::BadHeader("no MAPR chunk".to_string()))?;
+    let (mapper, submapper) =
+        board_mapper(&board).ok_or(RomError::UnsupportedBoard(board.clone()))?;
+    let prg_rom = prg_chips.concat();
+    let chr_rom = chr_chips.concat();
+    if prg_rom.is_empty() {
+        return Err(RomError::BadHeader("no PRG chunks".to_string()));
+    }
+
+    // 0: horizontal, 1: vertical, 2/3: single screen on the first/second table, 4: four
+    // screen, 5: mapper controlled, which leaves it up to the mapper
+    let mut flags = match mirroring {
+        Some(1) => 0x0100,
+        Some(4) => 0x0800,
+        _ => 0x0000,
+    };
+    let single_screen = match mirroring {
+        Some(2) => Some(Mirroring::SingleScreenA),
+        Some(3) => Some(Mirroring::SingleScreenB),
+        _ => None,
+    };
+    if battery {
+        flags |= 0x0200;
+    }
+
+    let header = CartridgeHeader {
+        mapper,
+        submapper,
+        flags,
+        cpu_ppu_timing: timing,
+        single_screen,
+        // Rounded up, so only an empty chip reads as missing
+        prg_rom_size: prg_rom.len().div_ceil(0x4000) as u8,
+        prg_msb_rom_size: (prg_rom.len().div_ceil(0x4000) >> 8) as u8,
+        chr_rom_size: chr_rom.len().div_ceil(0x2000) as u8,
+        chr_msb_rom_size: (chr_rom.len().div_ceil(0x2000) >> 8) as u8,
+        ..Default::default()
+    };
+    Ok((header, prg_rom, chr_rom))
+}
+
+// "PRG0" through "PRGF"
+fn chip_number(id: &str, kind: &str) -> Option<usize> {
+    let digit = id.strip_prefix(kind)?;
+    if digit.len() != 1 {
+        return None;
+    }
+    usize::from_str_radix(digit, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &str, data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.as_bytes().to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    fn unif(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = b"UNIF".to_vec();
+        file.extend_from_slice(&7u32.to_le_bytes());
+        file.resize(32, 0);
+        for chunk in chunks {
+            file.extend_from_slice(chunk);
+        }
+        file
+    }
+
+    #[test]
+    fn maps_board_names() {
+        assert_eq!(board_mapper("NES-NROM-256"), Some((0, 0)));
+        assert_eq!(board_mapper("unl-sachen-8259a"), Some((141, 0)));
+        assert_eq!(board_mapper("MLT-ACTION52"), Some((228, 0)));
+        assert_eq!(board_mapper("NES-BNROM"), Some((34, 2)));
+        assert_eq!(board_mapper("NES-SOMETHING"), None);
+    }
+
+    #[test]
+    fn maps_konami_boards() {
+        assert_eq!(board_mapper("KONAMI-VRC2A"), Some((22, 0)));
+        assert_eq!(board_mapper("VRC2B"), Some((23, 3)));
+        assert_eq!(board_mapper("KONAMI-VRC4C"), Some((21, 2)));
+        assert_eq!(board_mapper("KONAMI-VRC4E"), Some((23, 2)));
+        assert_eq!(board_mapper("UNL-VRC4D"), Some((25, 2)));
+        assert_eq!(board_mapper("KONAMI-VRC6B"), Some((26, 0)));
+        assert_eq!(board_mapper("KONAMI-VRC7A"), Some((85, 2)));
+    }
+
+    #[test]
+    fn maps_namco_and_sunsoft_boards() {
+        assert_eq!(board_mapper("NAMCOT-163"), Some((19, 0)));
+        assert_eq!(board_mapper("NAMCO-129"), Some((19, 0)));
+        assert_eq!(board_mapper("NES-JLROM"), Some((69, 0)));
+        assert_eq!(board_mapper("NES-BTR"), Some((69, 0)));
+    }
+
+    #[test]
+    fn maps_camerica_and_nina_boards() {
+        assert_eq!(board_mapper("CAMERICA-BF9093"), Some((71, 2)));
+        assert_eq!(board_mapper("CAMERICA-BF9097"), Some((71, 1)));
+        assert_eq!(board_mapper("AVE-NINA-01"), Some((34, 1)));
+        assert_eq!(board_mapper("NINA-001"), Some((34, 1)));
+        assert_eq!(board_mapper("AVE-NINA-06"), Some((79, 0)));
+    }
+
+    #[test]
+    fn maps_jaleco_boards() {
+        assert_eq!(board_mapper("JALECO-JF-11"), Some((140, 0)));
+        assert_eq!(board_mapper("JALECO-JF-14"), Some((140, 0)));
+        assert_eq!(board_mapper("jf-11"), Some((140, 0)));
+    }
+
+    #[test]
+    fn maps_multicart_boards() {
+        assert_eq!(board_mapper("BMC-64in1"), Some((225, 0)));
+        assert_eq!(board_mapper("BMC-76in1"), Some((226, 0)));
+        assert_eq!(board_mapper("BMC-1200in1"), Some((227, 0)));
+        assert_eq!(board_mapper("UNL-Sachen-8259B"), Some((138, 0)));
+    }
+
+    #[test]
+    fn reads_chunks() {
+        let file = unif(&[
+            chunk("MAPR", b"UNL-Sachen-8259C\0"),
+            chunk("NAME", b"Test\0"),
+            chunk("PRG1", &[2; 0x4000]),
+            chunk("PRG0", &[1; 0x4000]),
+            chunk("CHR0", &[3; 0x1000]),
+            chunk("MIRR", &[1]),
+            chunk("BATR", &[1]),
+            chunk("TVCI", &[1]),
+        ]);
+        let (header, prg_rom, chr_rom) = parse(&file).unwrap();
+
+        assert_eq!(header.mapper, 139);
+        assert_eq!(prg_rom.len(), 0x8000);
+        assert_eq!((prg_rom[0], prg_rom[0x4000]), (1, 2));
+        assert_eq!(chr_rom, vec![3; 0x1000]);
+        assert_eq!(header.chr_rom_bytes(), 0x2000);
+        assert_eq!(header.mirroring(), Mirroring::Vertical);
+        assert!(header.has_battery());
+        assert_eq!(header.cpu_ppu_timing, TIMING_PAL);
+    }
+
+    #[test]
+    fn single_screen_mirroring() {
+        for (mirr, mirroring) in [
+            (0, Mirroring::Horizontal),
+            (2, Mirroring::SingleScreenA),
+            (3, Mirroring::SingleScreenB),
+            (4, Mirroring::FourScreen),
+            (5, Mirroring::Horizontal),
+        ] {
+            let file = unif(&[
+                chunk("MAPR", b"NROM\0"),
+                chunk("PRG0", &[0; 0x4000]),
+                chunk("MIRR", &[mirr]),
+            ]);
+            let (header, _, _) = parse(&file).unwrap();
+            assert_eq!(header.mirroring(), mirroring);
+        }
+    }
+
+    #[test]
+    fn reports_bad_files() {
+        let file = unif(&[chunk("PRG0", &[0; 0x4000])]);
+        assert!(matches!(parse(&file), Err(RomError::BadHeader(_))));
+
+        let file = unif(&[chunk("MAPR", b"BOGUS\0"), chunk("PRG0", &[0; 0x4000])]);
+        assert!(matches!(parse(&file), Err(RomError::UnsupportedBoard(board)) if board == "BOGUS"));
+
+        let mut file = unif(&[chunk("MAPR", b"NROM\0"), chunk("PRG0", &[0; 0x4000])]);
+        file.truncate(file.len() - 1);
+        assert!(matches!(parse(&file), Err(RomError::TruncatedChunk(id)) if id == "PRG0"));
+    }
+
+    #[test]
+    fn loads_as_cartridge() {
+        let file = unif(&[
+            chunk("MAPR", b"NES-NROM-128\0"),
+            chunk("PRG0", &[0xEA; 0x4000]),
+        ]);
+        let cart = crate::cartridge::Cartridge::from_bytes(&file).unwrap();
+        assert_eq!(cart.get_prg_from_address(0xC000), 0xEA);
+
+        // No CHR chunks means CHR RAM
+        cart.set_chr_at_address(0x0010, 0x77);
+        assert_eq!(cart.get_chr_from_address(0x0010), 0x77);
+    }
+}
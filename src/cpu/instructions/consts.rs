@@ -1,4 +1,3 @@
-pub const CPU_CLOCK_SPEED: u64 = 559; // 1.79 MHz
 pub const NMI_VECTOR: u16 = 0xFFFA;
 pub const RESET_VECTOR: u16 = 0xFFFC;
 pub const IRQ_VECTOR: u16 = 0xFFFE;
@@ -76,7 +76,7 @@ pub use cld::cld;
 pub use cli::cli;
 pub use clv::clv;
 pub use cmp::cmp;
-pub use consts::{CPU_CLOCK_SPEED, IRQ_VECTOR, NMI_VECTOR, RESET_VECTOR};
+pub use consts::{IRQ_VECTOR, NMI_VECTOR, RESET_VECTOR};
 pub use cpx::cpx;
 pub use cpy::cpy;
 pub use dec::dec;
@@ -29,8 +29,15 @@ pub struct CPU {
     // Interrupts
     reset_requested: bool,
     irq_requested: bool,
+    // Level of the /IRQ input from the cartridge, held until the mapper is acknowledged
+    irq_line: bool,
     nmi_requested: bool,
     jammed: bool,
+
+    // Cycles run since power on
+    cycles: u64,
+    // A $4014 write during the last instruction, which stalls the CPU
+    oam_dma: bool,
 }
 
 #[derive(Clone, Copy)]
@@ -68,12 +75,49 @@ impl CPU {
 
             reset_requested: true,
             irq_requested: false,
+            irq_line: false,
             nmi_requested: false,
             jammed: false,
+
+            cycles: 0,
+            oam_dma: false,
         }
     }
 
     pub fn tick(&mut self, system: &mut Arc<Mutex<System>>) -> u64 {
+        let mut cycles = self.execute(system);
+        if self.oam_dma {
+            self.oam_dma = false;
+            cycles += self.oam_dma_stall(cycles);
+        }
+        self.cycles += cycles;
+
+        // NMI is polled at the end of the instruction, so a $2002 read in it can still cancel one
+        // the PPU raised a dot or two earlier
+        let ppu = system.lock().unwrap().ppu.clone();
+        if ppu.lock().unwrap().take_nmi() {
+            self.nmi_requested = true;
+        }
+        cycles
+    }
+
+    // OAM DMA halts the CPU for a cycle, waits another if it would start on an odd cycle, then
+    // spends 256 cycles reading and 256 writing
+    fn oam_dma_stall(&self, instruction_cycles: u64) -> u64 {
+        513 + (self.cycles + instruction_cycles) % 2
+    }
+
+    // Copies page $XX00-$XXFF into OAM through $2004, starting at OAMADDR
+    fn oam_dma(&mut self, system: &mut Arc<Mutex<System>>, page: u8) {
+        let ppu = system.lock().unwrap().ppu.clone();
+        for low in 0..=0xFF {
+            let value = self.get_mapped_byte(system, (page as usize) << 8 | low);
+            ppu.lock().unwrap().write_register(system, 0x2004, value);
+        }
+        self.oam_dma = true;
+    }
+
+    fn execute(&mut self, system: &mut Arc<Mutex<System>>) -> u64 {
         if self.reset_requested {
             println!("Resetting CPU");
             // self.reset_vector = ((rom.header.prg_rom_size as u16 * 0x4000) % 0x8000) - 4 + 0x7FFF;
@@ -101,7 +145,7 @@ impl CPU {
             return 0;
         }
 
-        if self.irq_requested {
+        if (self.irq_requested || self.irq_line) && !self.status.interrupt_disable {
             println!("IRQ --------------------------------");
             self.irq_requested = false;
             self.push_stack_word(&system.lock().unwrap().ram, self.pc);
@@ -432,40 +476,7 @@ impl CPU {
             let addr = 0x2000 | (address & 0x7);
             // println!("Getting byte from PPU");
             let ppu = system.lock().unwrap().ppu.clone();
-            if addr == 0x2000 {
-                // println!("TODO: PPU CTRL");
-                return ppu.lock().unwrap().ctrl;
-            }
-            if addr == 0x2001 {
-                // println!("TODO: PPU MASK");
-                return ppu.lock().unwrap().mask;
-            }
-            if addr == 0x2002 {
-                // println!("TODO: PPU STATUS");
-                return ppu.lock().unwrap().status;
-            }
-            if addr == 0x2003 {
-                // println!("TODO: PPU OAM ADDR");
-                return ppu.lock().unwrap().oam_addr;
-            }
-            if addr == 0x2004 {
-                // println!("TODO: PPU OAM DATA");
-                return ppu.lock().unwrap().oam_data;
-            }
-            if addr == 0x2005 {
-                // println!("TODO: PPU SCROLL");
-                return ppu.lock().unwrap().scroll;
-            }
-            if addr == 0x2006 {
-                // println!("TODO: PPU ADDR");
-                return ppu.lock().unwrap().addr;
-            }
-            if addr == 0x2007 {
-                // println!("TODO: PPU DATA");
-                return ppu.lock().unwrap().data;
-            }
-
-            return 0;
+            return ppu.lock().unwrap().read_register(system, addr as u16);
         }
 
         if address <= 0x401F {
@@ -483,24 +494,14 @@ impl CPU {
                 return 0;
             }
             if address == 0x4015 {
-                // println!("TODO: APU STATUS");
-                return 0;
+                let apu = system.lock().unwrap().apu.clone();
+                return apu.lock().unwrap().read_register(address as u16);
             }
             return 0;
         }
 
-        if address <= 0x5FFF {
-            // println!("TODO: EXPANSION ROM");
-            return 0;
-        }
-
-        if address <= 0xBFFF {
-            // println!("TODO: LT ROM");
-            return 0;
-        }
-
         if address <= 0xFFFF {
-            // println!("PRG ROM");
+            // Expansion ROM, SRAM and PRG ROM all live on the cartridge
             return system
                 .lock()
                 .unwrap()
@@ -513,7 +514,7 @@ impl CPU {
         return 0;
     }
 
-    pub fn set_mapped_byte(&self, system: &mut Arc<Mutex<System>>, address: usize, value: u8) {
+    pub fn set_mapped_byte(&mut self, system: &mut Arc<Mutex<System>>, address: usize, value: u8) {
         if address <= 0x1FFF {
             let ram = system.lock().unwrap().ram.clone();
             ram.lock().unwrap()[address & 0x7FF] = value;
@@ -524,81 +525,43 @@ impl CPU {
             // println!("Setting byte in PPU");
             let addr = 0x2000 | (address & 0x7);
             let ppu = system.lock().unwrap().ppu.clone();
-            if addr == 0x2000 {
-                // println!("TODO: PPU CTRL");
-                ppu.lock().unwrap().ctrl = value;
-                return;
-            }
-            if addr == 0x2001 {
-                // println!("TODO: PPU MASK");
-                ppu.lock().unwrap().mask = value;
-                return;
-            }
-            if addr == 0x2002 {
-                // println!("TODO: PPU STATUS");
-                ppu.lock().unwrap().status = value;
-                return;
-            }
-            if addr == 0x2003 {
-                // println!("TODO: PPU OAM ADDR");
-                ppu.lock().unwrap().oam_addr = value;
-                return;
-            }
-            if addr == 0x2004 {
-                // println!("TODO: PPU OAM DATA");
-                ppu.lock().unwrap().oam_data = value;
-                return;
-            }
-            if addr == 0x2005 {
-                // println!("TODO: PPU SCROLL");
-                ppu.lock().unwrap().scroll = value;
-                return;
-            }
-            if addr == 0x2006 {
-                // println!("TODO: PPU ADDR");
-                ppu.lock().unwrap().addr = value;
-                return;
-            }
-            if addr == 0x2007 {
-                // println!("TODO: PPU DATA");
-                ppu.lock().unwrap().data = value;
-                return;
-            }
+            let mapper = system.lock().unwrap().rom.mapper.clone();
+            mapper
+                .lock()
+                .unwrap()
+                .ppu_register_write(addr as u16, value);
+            ppu.lock()
+                .unwrap()
+                .write_register(system, addr as u16, value);
+            return;
         }
 
         if address <= 0x401F {
             // println!("TODO: HARDWARE REGISTERS");
             if address == 0x4014 {
-                // println!("TODO: OAM DMA");
-                return;
-            }
-            if address == 0x4015 {
-                // println!("TODO: APU STATUS");
+                self.oam_dma(system, value);
                 return;
             }
             if address == 0x4016 {
                 // println!("TODO: JOYPAD 1");
                 return;
             }
-            if address == 0x4017 {
-                // println!("TODO: JOYPAD 2");
+            // $4017 is the APU frame counter when written, joypad 2 when read
+            if address <= 0x4017 {
+                let apu = system.lock().unwrap().apu.clone();
+                apu.lock().unwrap().write_register(address as u16, value);
                 return;
             }
             return;
         }
 
-        if address <= 0x5FFF {
-            // println!("TODO: EXPANSION ROM");
-            return;
-        }
-
-        if address <= 0x7FFF {
-            // println!("TODO: SRAM");
-            return;
-        }
-
         if address <= 0xFFFF {
-            // println!("TODO: PRG ROM");
+            // Expansion ROM, SRAM and mapper registers all live on the cartridge
+            system
+                .lock()
+                .unwrap()
+                .rom
+                .set_prg_at_address(address as u16, value);
             return;
         }
     }
@@ -609,7 +572,7 @@ impl CPU {
         return (high << 8) | low;
     }
 
-    pub fn set_mapped_word(&self, system: &mut Arc<Mutex<System>>, address: usize, value: u16) {
+    pub fn set_mapped_word(&mut self, system: &mut Arc<Mutex<System>>, address: usize, value: u16) {
         let low = (value & 0x00FF) as u8;
         let high = (value & 0xFF00) as u8;
         self.set_mapped_byte(&mut system.clone(), address, low);
@@ -675,6 +638,11 @@ impl CPU {
         self.irq_requested = true;
     }
 
+    // IRQ is level triggered: it fires while the line is held and I is clear
+    pub fn set_irq_line(&mut self, level: bool) {
+        self.irq_line = level;
+    }
+
     pub fn request_nmi_interrupt(&mut self) {
         self.nmi_requested = true;
     }
@@ -716,3 +684,123 @@ impl Status {
         self.negative = (value & 0x80) != 0;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn system() -> Arc<Mutex<System>> {
+        let mut file = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, 0x00];
+        file.resize(16 + 0x6000, 0);
+        let rom = Cartridge::from_bytes(&file).unwrap();
+        Arc::new(Mutex::new(System::new(rom)))
+    }
+
+    #[test]
+    fn apu_registers_are_on_the_bus() {
+        let mut system = system();
+        let mut cpu = CPU::new();
+        cpu.set_mapped_byte(&mut system, 0x4015, 0x01);
+        cpu.set_mapped_byte(&mut system, 0x4003, 0x08);
+        assert_eq!(cpu.get_mapped_byte(&mut system, 0x4015), 0x01);
+
+        // $4017 writes go to the frame counter
+        cpu.set_mapped_byte(&mut system, 0x4017, 0x40);
+        let apu = system.lock().unwrap().apu.clone();
+        for _ in 0..30000 {
+            apu.lock().unwrap().tick(0.0);
+        }
+        assert!(!apu.lock().unwrap().irq_pending());
+    }
+
+    #[test]
+    fn oam_dma_copies_from_oam_addr() {
+        let mut system = system();
+        let mut cpu = CPU::new();
+        for low in 0..0x100 {
+            cpu.set_mapped_byte(&mut system, 0x0200 + low, low as u8);
+        }
+
+        cpu.set_mapped_byte(&mut system, 0x2003, 0x04);
+        cpu.set_mapped_byte(&mut system, 0x4014, 0x02);
+        assert!(cpu.oam_dma);
+
+        let oam = system.lock().unwrap().oam.clone();
+        let oam = oam.lock().unwrap();
+        assert_eq!(oam[0x04], 0x00);
+        assert_eq!(oam[0xFF], 0xFB);
+        assert_eq!(oam[0x03], 0xFF);
+        let ppu = system.lock().unwrap().ppu.clone();
+        assert_eq!(ppu.lock().unwrap().oam_addr, 0x04);
+    }
+
+    #[test]
+    fn irq_line_is_a_level() {
+        let mut system = system();
+        let mut cpu = CPU::new();
+        cpu.reset_requested = false;
+        cpu.pc = 0x0000;
+        for address in 0..4 {
+            cpu.set_mapped_byte(&mut system, address, 0xEA); // NOP
+        }
+
+        // Raised and acknowledged while I is set: nothing happens once I is cleared
+        cpu.set_irq_line(true);
+        cpu.tick(&mut system);
+        assert_eq!(cpu.pc, 0x0001);
+        cpu.set_irq_line(false);
+        cpu.status.interrupt_disable = false;
+        cpu.tick(&mut system);
+        assert_eq!(cpu.pc, 0x0002);
+
+        // Still held: taken through the IRQ vector
+        cpu.set_irq_line(true);
+        cpu.tick(&mut system);
+        assert_eq!(cpu.pc, 0x0000);
+        assert!(cpu.status.interrupt_disable);
+    }
+
+    #[test]
+    fn status_read_cancels_nmi_at_instruction_boundary() {
+        let mut system = system();
+        let mut cpu = CPU::new();
+        cpu.reset_requested = false;
+        let ppu = system.lock().unwrap().ppu.clone();
+        // LDA $2002, NOP, NOP at $0100; the NMI vector reads $0000
+        for (i, byte) in [0xAD, 0x02, 0x20, 0xEA, 0xEA].iter().enumerate() {
+            cpu.set_mapped_byte(&mut system, 0x0100 + i, *byte);
+        }
+        cpu.set_mapped_byte(&mut system, 0x2000, 0x80);
+
+        // Like run_processor: a PPU dot, then the CPU's next instruction
+        let mut run = |cpu: &mut CPU, pc: u16| {
+            {
+                let mut ppu = ppu.lock().unwrap();
+                ppu.scanline = 241;
+                ppu.status = 0;
+                let mut dot = 1;
+                ppu.tick(&mut system, &mut dot);
+            }
+            cpu.pc = pc;
+            cpu.tick(&mut system);
+            cpu.tick(&mut system);
+            cpu.pc
+        };
+
+        // Reading $2002 on the dot after vblank starts returns the flag and drops the NMI
+        assert_eq!(run(&mut cpu, 0x0100), 0x0104);
+        assert_eq!(cpu.a & 0x80, 0x80);
+
+        // Any other instruction lets it through
+        assert_eq!(run(&mut cpu, 0x0103), 0x0000);
+    }
+
+    #[test]
+    fn oam_dma_stall_depends_on_alignment() {
+        let mut cpu = CPU::new();
+        // STA $4014 ending on an even cycle
+        assert_eq!(cpu.oam_dma_stall(4), 513);
+        cpu.cycles = 1;
+        assert_eq!(cpu.oam_dma_stall(4), 514);
+    }
+}
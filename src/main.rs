@@ -1,11 +1,16 @@
+mod apu;
 mod cartridge;
 mod cpu;
+mod options;
 mod ppu;
+mod region;
 mod system;
 extern crate tiny_http;
 
 use rand::distributions::uniform::SampleBorrow;
+use sdl2::audio::AudioSpecDesired;
 use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
 use std::{
     sync::{Arc, Mutex},
     time::SystemTime,
@@ -13,9 +18,17 @@ use std::{
 use tiny_http::{Response, Server};
 
 use crate::ppu::PPU;
-use crate::{cpu::CPU, ppu::Screen};
-use cartridge::{get_rom, Cartridge};
-use sdl2::pixels::Color;
+use crate::{
+    apu::{APU, SAMPLE_RATE},
+    cpu::CPU,
+    ppu::{FrameBuffer, Palette, BUILT_IN_PALETTES},
+};
+use cartridge::{Battery, Cartridge, LoadOptions};
+use options::Options;
+use region::Region;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::surface::Surface;
+use std::path::Path;
 use system::System;
 
 fn main() {
@@ -29,17 +42,101 @@ fn main() {
         .build()
         .unwrap();
     let mut canvas = window.into_canvas().build().unwrap();
+    let texture_creator = canvas.texture_creator();
+    let mut texture = texture_creator
+        .create_texture_streaming(
+            PixelFormatEnum::RGBA32,
+            FrameBuffer::WIDTH as u32,
+            FrameBuffer::HEIGHT as u32,
+        )
+        .unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
+    let audio_subsystem = sdl_context.audio().unwrap();
+    let audio = audio_subsystem
+        .open_queue::<f32, _>(
+            None,
+            &AudioSpecDesired {
+                freq: Some(SAMPLE_RATE as i32),
+                channels: Some(1),
+                samples: None,
+            },
+        )
+        .unwrap();
+    audio.resume();
 
     // Emulator
-    let mut rom: Cartridge = get_rom();
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let load_options = LoadOptions {
+        use_database: options.use_database,
+        database: options.database.clone(),
+        patch: options.patch.clone(),
+        archive_entry: options.archive_entry.clone(),
+        fds_bios: options.fds_bios.clone(),
+        region: options.region,
+    };
+    let mut rom: Cartridge = match Cartridge::from_path_with(&options.rom_path, &load_options) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("Could not load {}: {}", options.rom_path.display(), e);
+            std::process::exit(1);
+        }
+    };
+    let save_path = Battery::save_path(&options.rom_path, options.saves_dir.as_deref());
+    if let Err(e) = rom.attach_battery(save_path.clone()) {
+        eprintln!("Could not load {}: {}", save_path.display(), e);
+    }
+    if let Some(player) = &rom.nsf_player {
+        let player = player.lock().unwrap();
+        let nsf = player.nsf();
+        println!("{} - {} ({})", nsf.title, nsf.artist, nsf.copyright);
+        announce_track(nsf, player.track());
+    }
+    // F9 cycles through the built-in palettes and the one asked for
+    let mut palettes: Vec<String> = BUILT_IN_PALETTES
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+    let mut palette_choice = 0;
+    if let Some(spec) = &options.palette {
+        palette_choice = palettes
+            .iter()
+            .position(|name| name == spec)
+            .unwrap_or_else(|| {
+                palettes.push(spec.clone());
+                palettes.len() - 1
+            });
+    }
+    let mut palette = match Palette::from_spec(&palettes[palette_choice]) {
+        Ok(palette) => palette,
+        Err(e) => {
+            eprintln!("Could not load palette {}: {}", palettes[palette_choice], e);
+            std::process::exit(1);
+        }
+    };
+
+    // --region has already been written into the header
+    let region = Region::from_timing(rom.header.cpu_ppu_timing);
+
     let system = Arc::new(Mutex::new(System::new(rom.clone())));
+    {
+        let ppu = system.lock().unwrap().ppu.clone();
+        ppu.lock().unwrap().sprite_limit = options.sprite_limit;
+        ppu.lock().unwrap().region = region;
+        let apu = system.lock().unwrap().apu.clone();
+        apu.lock().unwrap().region = region;
+    }
 
     let mut last_cpu_cycle: u128 = get_time();
     let mut last_ppu_cycle: u128 = get_time();
-    let mut last_apu_cycle: u128 = get_time();
     let mut num_ppu_cycles: u64 = 0;
     let mut last_draw_time: u128 = get_time();
+    let mut last_save_time: u128 = get_time();
 
     // Create a non-blocking tiny_http server.
     let server = Arc::new(Server::http("0.0.0.0:8080").unwrap());
@@ -67,6 +164,59 @@ fn main() {
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. } => break 'running,
+                // Disk System: first disk side, next disk side, or eject
+                Event::KeyDown {
+                    keycode: Some(Keycode::F4),
+                    ..
+                } if rom.disk_sides() > 0 => {
+                    rom.insert_disk(0);
+                    println!("Disk: side 1/{}", rom.disk_sides());
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    ..
+                } => rom.flip_disk(),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F6),
+                    ..
+                } => rom.eject_disk(),
+                // NSF: previous or next track
+                Event::KeyDown {
+                    keycode: Some(Keycode::F7),
+                    ..
+                } => change_track(&rom, -1),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F8),
+                    ..
+                } => change_track(&rom, 1),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    ..
+                } => {
+                    palette_choice = (palette_choice + 1) % palettes.len();
+                    match Palette::from_spec(&palettes[palette_choice]) {
+                        Ok(next) => {
+                            palette = next;
+                            println!("Palette: {}", palettes[palette_choice]);
+                        }
+                        Err(e) => eprintln!("Could not load palette: {}", e),
+                    }
+                }
+                // Screenshot of the last frame next to the ROM
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    ..
+                } => {
+                    let ppu = system.lock().unwrap().ppu.clone();
+                    let mut pixels = ppu.lock().unwrap().framebuffer.argb(&palette).to_vec();
+                    let path = options
+                        .rom_path
+                        .with_extension(format!("{}.bmp", get_time() / 1_000_000_000));
+                    match save_screenshot(&mut pixels, &path) {
+                        Ok(()) => println!("Screenshot: {}", path.display()),
+                        Err(e) => eprintln!("Could not write {}: {}", path.display(), e),
+                    }
+                }
                 Event::KeyDown { .. } => {
                     system
                         .lock()
@@ -80,48 +230,93 @@ fn main() {
             }
         }
 
-        (
-            last_cpu_cycle,
-            last_ppu_cycle,
-            last_apu_cycle,
-            num_ppu_cycles,
-        ) = run_processor(
+        (last_cpu_cycle, last_ppu_cycle, num_ppu_cycles) = run_processor(
             last_cpu_cycle,
             last_ppu_cycle,
-            last_apu_cycle,
             num_ppu_cycles,
+            region,
             &mut system.clone(),
         );
 
-        // Render at 60 FPS
-        if get_time() - last_draw_time > (1_000_000_000u128 / 60) {
-            canvas.clear();
+        // Render at the region's frame rate
+        if get_time() - last_draw_time > region.frame_period() {
             let ppu = system.lock().unwrap().ppu.clone();
-            let screen: Screen = ppu.lock().unwrap().get_screen(rom.clone());
-
-            // Draw the screen
-            for y in 0..240 {
-                for x in 0..256 {
-                    let color_nes = screen.get_pixel(x, y);
-                    let color = Color::RGB(color_nes.r, color_nes.g, color_nes.b);
-                    canvas.set_draw_color(color);
-                    canvas
-                        .fill_rect(sdl2::rect::Rect::new(
-                            x as i32 * SCALE as i32,
-                            y as i32 * SCALE as i32,
-                            SCALE as u32,
-                            SCALE as u32,
-                        ))
-                        .unwrap();
-                }
+            {
+                let mut ppu = ppu.lock().unwrap();
+                let pixels = ppu.framebuffer.rgba(&palette);
+                texture.update(None, pixels, FrameBuffer::PITCH).unwrap();
             }
 
+            // The texture is stretched to the SCALE-sized window
+            canvas.clear();
+            canvas.copy(&texture, None, None).unwrap();
             canvas.present();
             last_draw_time = get_time();
+
+            // Drop samples rather than fall further behind if emulation outpaces the sound card
+            let apu = system.lock().unwrap().apu.clone();
+            let samples = apu.lock().unwrap().take_samples();
+            let queued = audio.size() as usize / std::mem::size_of::<f32>();
+            if queued < SAMPLE_RATE as usize / 5 {
+                if let Err(e) = audio.queue_audio(&samples) {
+                    eprintln!("Could not queue audio: {}", e);
+                }
+            }
+        }
+
+        // Flush battery RAM every few seconds so a crash loses little progress
+        if get_time() - last_save_time > 5_000_000_000u128 {
+            save_battery(&system);
+            last_save_time = get_time();
         }
 
         // ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 60)); // sloppy FPS limit
     }
+
+    save_battery(&system);
+}
+
+fn save_battery(system: &Arc<Mutex<System>>) {
+    if let Err(e) = system.lock().unwrap().rom.save_battery() {
+        eprintln!("Could not write save: {}", e);
+    }
+}
+
+fn save_screenshot(pixels: &mut [u8], path: &Path) -> Result<(), String> {
+    let surface = Surface::from_data(
+        pixels,
+        FrameBuffer::WIDTH as u32,
+        FrameBuffer::HEIGHT as u32,
+        FrameBuffer::PITCH as u32,
+        PixelFormatEnum::ARGB32,
+    )?;
+    surface.save_bmp(path)
+}
+
+fn change_track(rom: &Cartridge, step: isize) {
+    if let Some((track, count)) = rom.nsf_track() {
+        let track = (track as isize + step).rem_euclid(count as isize) as usize;
+        rom.select_track(track);
+        announce_track(
+            rom.nsf_player.as_ref().unwrap().lock().unwrap().nsf(),
+            track,
+        );
+    }
+}
+
+fn announce_track(nsf: &cartridge::Nsf, track: usize) {
+    let title = nsf.track_title(track).unwrap_or("");
+    match nsf.track_duration(track) {
+        Some(duration) => println!(
+            "Track {}/{}: {} ({}:{:02})",
+            track + 1,
+            nsf.song_count,
+            title,
+            duration.as_secs() / 60,
+            duration.as_secs() % 60
+        ),
+        None => println!("Track {}/{}: {}", track + 1, nsf.song_count, title),
+    }
 }
 
 fn get_time() -> u128 {
@@ -134,23 +329,38 @@ fn get_time() -> u128 {
 fn run_processor(
     mut last_cpu_cycle: u128,
     mut last_ppu_cycle: u128,
-    mut last_apu_cycle: u128,
     mut num_ppu_cycles: u64,
+    region: Region,
     system: &mut Arc<Mutex<System>>,
-) -> (u128, u128, u128, u64) {
+) -> (u128, u128, u64) {
     // println!("Running processor");
-    const CPU_CYCLES: u128 = 559; // 1.79 MHz
-    const PPU_CYCLES: u128 = 186; // 5.37 MHz
-    const APU_CYCLES: u128 = 559; // 1.79 MHz
+    let cpu_cycles = region.cpu_period(); // 1.79 MHz NTSC, 1.66 MHz PAL
+    let ppu_cycles = region.ppu_period(); // 3 dots per CPU cycle, 3.2 on PAL
 
-    // CPU runs at 1.79 MHz
+    // CPU runs at 1.79 MHz (NTSC)
     let check_cpu_time = get_time();
-    if check_cpu_time - last_cpu_cycle.borrow() >= CPU_CYCLES {
+    if check_cpu_time - last_cpu_cycle.borrow() >= cpu_cycles {
         // println!("Running CPU");
         let cpu = system.lock().unwrap().cpu.clone();
         let cycles_ran = cpu.lock().unwrap().tick(&mut system.clone());
+
+        // The APU and mappers with CPU cycle counters (IRQ timers, expansion audio) run in
+        // lockstep
+        let mapper = system.lock().unwrap().rom.mapper.clone();
+        let apu = system.lock().unwrap().apu.clone();
+        for _ in 0..cycles_ran {
+            let expansion = {
+                let mut mapper = mapper.lock().unwrap();
+                mapper.cpu_clock();
+                mapper.audio_output()
+            };
+            run_apu(&apu, system, expansion);
+        }
+        let irq = mapper.lock().unwrap().irq_pending() || apu.lock().unwrap().irq_pending();
+        cpu.lock().unwrap().set_irq_line(irq);
+
         last_cpu_cycle = get_time()
-            + (CPU_CYCLES * cycles_ran as u128)
+            + (cpu_cycles * cycles_ran as u128)
             + (if cpu.lock().unwrap().is_jammed() {
                 0xFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF
             } else {
@@ -158,9 +368,9 @@ fn run_processor(
             });
     }
 
-    // PPU runs at 5.37 MHz
+    // PPU runs at 5.37 MHz (NTSC)
     let check_ppu_time = get_time();
-    if check_ppu_time - last_ppu_cycle >= PPU_CYCLES {
+    if check_ppu_time - last_ppu_cycle >= ppu_cycles {
         let ppu = system.lock().unwrap().ppu.clone();
         num_ppu_cycles = ppu
             .lock()
@@ -169,17 +379,15 @@ fn run_processor(
         last_ppu_cycle = get_time();
     }
 
-    // APU runs at 1.79 MHz
-    let check_apu_time = get_time();
-    if check_apu_time - last_apu_cycle >= APU_CYCLES {
-        // APU.tick();
-        last_apu_cycle = get_time();
-    }
+    return (last_cpu_cycle, last_ppu_cycle, num_ppu_cycles);
+}
 
-    return (
-        last_cpu_cycle,
-        last_ppu_cycle,
-        last_apu_cycle,
-        num_ppu_cycles,
-    );
+// One APU cycle, feeding the DMC from the cartridge when it needs a byte
+fn run_apu(apu: &Arc<Mutex<APU>>, system: &Arc<Mutex<System>>, expansion: f32) {
+    let mut apu = apu.lock().unwrap();
+    apu.tick(expansion);
+    if let Some(address) = apu.dmc_fetch_address() {
+        let value = system.lock().unwrap().rom.get_prg_from_address(address);
+        apu.dmc_fill(value);
+    }
 }
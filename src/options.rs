@@ -0,0 +1,162 @@
+/**
+* Command line options
+*
+* nust [options] [rom]
+*   --saves-dir <dir>   Keep battery saves in <dir> instead of next to the ROM
+*   --no-db             Trust the ROM header even if the game database knows better
+*   --db <file>         Extra game database entries, in the format of cartridge/database.txt
+*   --entry <name>      ROM to load from a zip file (default: the first .nes/.unf/.fds/.nsf)
+*   --fds-bios <file>   FDS BIOS (disksys.rom), needed for .fds disk images
+*   --patch <file>      Apply an IPS, UPS or BPS patch (default: <rom>.ips/.ups/.bps if present)
+*   --no-sprite-limit   Draw every sprite on a line instead of the hardware's 8, to reduce flicker
+*   --palette <palette> Built-in palette (default, rgb, ntsc), "ntsc:hue=<degrees>,saturation=<x>,
+*                       contrast=<x>,brightness=<x>,gamma=<x>" or a .pal file
+*   --region <region>   ntsc, pal or dendy (default: from the NES 2.0 header or game database)
+*/
+use std::path::PathBuf;
+
+use crate::region::Region;
+
+pub struct Options {
+    pub rom_path: PathBuf,
+    pub saves_dir: Option<PathBuf>,
+    pub use_database: bool,
+    pub database: Option<PathBuf>,
+    pub patch: Option<PathBuf>,
+    pub archive_entry: Option<String>,
+    pub fds_bios: Option<PathBuf>,
+    pub sprite_limit: bool,
+    pub palette: Option<String>,
+    pub region: Option<Region>,
+}
+
+impl Options {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+        let mut options = Options {
+            rom_path: PathBuf::from("nestest.nes"),
+            saves_dir: None,
+            use_database: true,
+            database: None,
+            patch: None,
+            archive_entry: None,
+            fds_bios: None,
+            sprite_limit: true,
+            palette: None,
+            region: None,
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--saves-dir" => options.saves_dir = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--no-db" => options.use_database = false,
+                "--db" => options.database = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--entry" => options.archive_entry = Some(value(&mut args, &arg)?),
+                "--fds-bios" => options.fds_bios = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--patch" => options.patch = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--no-sprite-limit" => options.sprite_limit = false,
+                "--palette" => options.palette = Some(value(&mut args, &arg)?),
+                "--region" => {
+                    let name = value(&mut args, &arg)?;
+                    options.region = Some(
+                        Region::from_name(&name)
+                            .ok_or_else(|| format!("Unknown region {}", name))?,
+                    );
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+                _ => options.rom_path = PathBuf::from(arg),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+fn value<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("{} needs a value", option))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn defaults() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(options.rom_path, PathBuf::from("nestest.nes"));
+        assert_eq!(options.saves_dir, None);
+        assert!(options.use_database);
+        assert_eq!(options.patch, None);
+    }
+
+    #[test]
+    fn disables_database() {
+        assert!(!parse(&["--no-db", "game.nes"]).unwrap().use_database);
+    }
+
+    #[test]
+    fn database_file() {
+        let options = parse(&["--db", "nescartdb.txt", "game.nes"]).unwrap();
+        assert_eq!(options.database, Some(PathBuf::from("nescartdb.txt")));
+    }
+
+    #[test]
+    fn rom_and_saves_dir() {
+        let options = parse(&["--saves-dir", "saves", "zelda.nes"]).unwrap();
+        assert_eq!(options.rom_path, PathBuf::from("zelda.nes"));
+        assert_eq!(options.saves_dir, Some(PathBuf::from("saves")));
+    }
+
+    #[test]
+    fn patch_file() {
+        let options = parse(&["game.nes", "--patch", "translation.ips"]).unwrap();
+        assert_eq!(options.patch, Some(PathBuf::from("translation.ips")));
+    }
+
+    #[test]
+    fn archive_entry() {
+        let options = parse(&["--entry", "Game (E).nes", "collection.zip"]).unwrap();
+        assert_eq!(options.rom_path, PathBuf::from("collection.zip"));
+        assert_eq!(options.archive_entry, Some("Game (E).nes".to_string()));
+    }
+
+    #[test]
+    fn fds_bios() {
+        let options = parse(&["--fds-bios", "disksys.rom", "zelda.fds"]).unwrap();
+        assert_eq!(options.fds_bios, Some(PathBuf::from("disksys.rom")));
+    }
+
+    #[test]
+    fn disables_sprite_limit() {
+        assert!(parse(&[]).unwrap().sprite_limit);
+        assert!(!parse(&["--no-sprite-limit"]).unwrap().sprite_limit);
+    }
+
+    #[test]
+    fn palette() {
+        assert_eq!(parse(&[]).unwrap().palette, None);
+        let options = parse(&["--palette", "ntsc:hue=-10", "game.nes"]).unwrap();
+        assert_eq!(options.palette, Some("ntsc:hue=-10".to_string()));
+    }
+
+    #[test]
+    fn rejects_bad_options() {
+        assert!(parse(&["--saves-dir"]).is_err());
+        assert!(parse(&["--bogus"]).is_err());
+        assert!(parse(&["--patch"]).is_err());
+    }
+
+    #[test]
+    fn region() {
+        assert_eq!(parse(&[]).unwrap().region, None);
+        assert_eq!(
+            parse(&["--region", "pal"]).unwrap().region,
+            Some(Region::Pal)
+        );
+        assert!(parse(&["--region", "secam"]).is_err());
+    }
+}
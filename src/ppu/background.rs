@@ -0,0 +1,280 @@
+/**
+* Background rendering pipeline
+*
+* Every 8 dots the PPU fetches the next tile's nametable byte, attribute byte and the two
+* pattern table planes, two dots per fetch, then bumps coarse X in v. The fetched tile is loaded
+* into the low bytes of 16-bit shift registers which shift once per dot, so the pixel being drawn
+* is always bit 15 - fine X. Scanlines fetch dots 1-256 for the visible tiles and 321-336 for the
+* first two tiles of the next line; dot 256 moves v down a row, dot 257 restores the horizontal
+* bits from t and dots 280-304 of the pre-render line restore the vertical bits.
+*/
+use std::sync::{Arc, Mutex};
+
+use super::PPU;
+use crate::system::System;
+
+#[derive(Clone, Copy, Default)]
+pub struct Background {
+    next_tile: u8,
+    next_attribute: u8,
+    next_pattern_low: u8,
+    next_pattern_high: u8,
+
+    pattern_low: u16,
+    pattern_high: u16,
+    attribute_low: u16,
+    attribute_high: u16,
+}
+
+impl Background {
+    fn load(&mut self) {
+        self.pattern_low = (self.pattern_low & 0xFF00) | self.next_pattern_low as u16;
+        self.pattern_high = (self.pattern_high & 0xFF00) | self.next_pattern_high as u16;
+        // Attributes cover the whole tile, so fill all 8 bits
+        self.attribute_low = (self.attribute_low & 0xFF00)
+            | if self.next_attribute & 0x01 != 0 {
+                0xFF
+            } else {
+                0x00
+            };
+        self.attribute_high = (self.attribute_high & 0xFF00)
+            | if self.next_attribute & 0x02 != 0 {
+                0xFF
+            } else {
+                0x00
+            };
+    }
+
+    fn shift(&mut self) {
+        self.pattern_low <<= 1;
+        self.pattern_high <<= 1;
+        self.attribute_low <<= 1;
+        self.attribute_high <<= 1;
+    }
+
+    // (palette, pixel) at fine X
+    fn pixel(&self, fine_x: u8) -> (u8, u8) {
+        let bit = 0x8000 >> fine_x;
+        let pixel =
+            ((self.pattern_high & bit != 0) as u8) << 1 | (self.pattern_low & bit != 0) as u8;
+        let palette =
+            ((self.attribute_high & bit != 0) as u8) << 1 | (self.attribute_low & bit != 0) as u8;
+        (palette, pixel)
+    }
+}
+
+impl PPU {
+    fn show_background(&self, x: usize) -> bool {
+        // Bit 1 clear hides the leftmost 8 pixels
+        self.mask & 0x08 != 0 && (x >= 8 || self.mask & 0x02 != 0)
+    }
+
+    pub fn update_background(
+        &mut self,
+        system: &mut Arc<Mutex<System>>,
+        dot: u16,
+        pre_render: bool,
+    ) {
+        match dot {
+            2..=257 | 322..=337 => {
+                self.background.shift();
+                // The tile fetched over the last 8 dots
+                if dot % 8 == 1 {
+                    self.background.load();
+                }
+            }
+            _ => {}
+        }
+
+        match dot {
+            1..=256 | 321..=336 => self.fetch_background(system, dot),
+            // Unused nametable fetches, seen by mappers that count them
+            337 | 339 => {
+                self.read_bus(system, 0x2000 | (self.vram_addr & 0x0FFF));
+            }
+            _ => {}
+        }
+
+        if dot == 256 {
+            self.increment_y();
+        }
+        if dot == 257 {
+            self.copy_horizontal();
+        }
+        if pre_render && (280..=304).contains(&dot) {
+            self.copy_vertical();
+        }
+    }
+
+    fn fetch_background(&mut self, system: &mut Arc<Mutex<System>>, dot: u16) {
+        let v = self.vram_addr;
+        match (dot - 1) % 8 {
+            0 => self.background.next_tile = self.read_bus(system, 0x2000 | (v & 0x0FFF)),
+            2 => {
+                let address = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                let mut attribute = self.read_bus(system, address);
+                // Each byte covers 4x4 tiles, two bits per 2x2 quadrant
+                if v & 0x0040 != 0 {
+                    attribute >>= 4;
+                }
+                if v & 0x0002 != 0 {
+                    attribute >>= 2;
+                }
+                self.background.next_attribute = attribute & 0x03;
+            }
+            4 => {
+                let address = self.pattern_address();
+                self.background.next_pattern_low = self.read_bus(system, address);
+            }
+            6 => {
+                let address = self.pattern_address() + 8;
+                self.background.next_pattern_high = self.read_bus(system, address);
+            }
+            7 => self.increment_x(),
+            _ => {}
+        }
+    }
+
+    fn pattern_address(&self) -> u16 {
+        let table = (self.ctrl as u16 & 0x10) << 8;
+        let fine_y = (self.vram_addr >> 12) & 0x07;
+        table | (self.background.next_tile as u16) << 4 | fine_y
+    }
+
+    // (palette, pixel) at x on the current line
+    pub fn background_pixel(&self, x: usize) -> (u8, u8) {
+        if !self.show_background(x) {
+            return (0, 0);
+        }
+        self.background.pixel(self.fine_x)
+    }
+
+    // Coarse X, wrapping into the horizontally adjacent nametable
+    fn increment_x(&mut self) {
+        if self.vram_addr & 0x001F == 31 {
+            self.vram_addr &= !0x001F;
+            self.vram_addr ^= 0x0400;
+        } else {
+            self.vram_addr += 1;
+        }
+    }
+
+    // Fine Y, overflowing into coarse Y, which wraps into the vertically adjacent nametable after
+    // row 29. Rows 30 and 31 are attribute data and wrap without switching nametables.
+    fn increment_y(&mut self) {
+        if self.vram_addr & 0x7000 != 0x7000 {
+            self.vram_addr += 0x1000;
+            return;
+        }
+        self.vram_addr &= !0x7000;
+        let mut coarse_y = (self.vram_addr & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.vram_addr ^= 0x0800;
+        } else if coarse_y == 31 {
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.vram_addr = (self.vram_addr & !0x03E0) | (coarse_y << 5);
+    }
+
+    // Coarse X and the horizontal nametable bit
+    fn copy_horizontal(&mut self) {
+        self.vram_addr = (self.vram_addr & !0x041F) | (self.temp_addr & 0x041F);
+    }
+
+    // Fine Y, coarse Y and the vertical nametable bit
+    fn copy_vertical(&mut self) {
+        self.vram_addr = (self.vram_addr & !0x7BE0) | (self.temp_addr & 0x7BE0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{run_dots, set_addr, system};
+    use super::*;
+
+    #[test]
+    fn increments_wrap_nametables() {
+        let mut ppu = PPU::new();
+        ppu.vram_addr = 0x001F;
+        ppu.increment_x();
+        assert_eq!(ppu.vram_addr, 0x0400);
+
+        ppu.vram_addr = 0x73A0; // Fine Y 7, coarse Y 29
+        ppu.increment_y();
+        assert_eq!(ppu.vram_addr, 0x0800);
+        ppu.vram_addr = 0x73E0; // Coarse Y 31
+        ppu.increment_y();
+        assert_eq!(ppu.vram_addr, 0x0000);
+        ppu.vram_addr = 0x0000;
+        ppu.increment_y();
+        assert_eq!(ppu.vram_addr, 0x1000);
+    }
+
+    #[test]
+    fn copies_from_t() {
+        let mut ppu = PPU::new();
+        ppu.temp_addr = 0x7FFF;
+        ppu.copy_horizontal();
+        assert_eq!(ppu.vram_addr, 0x041F);
+        ppu.copy_vertical();
+        assert_eq!(ppu.vram_addr, 0x7FFF);
+    }
+
+    #[test]
+    fn renders_a_scrolled_background() {
+        let mut system = system();
+        let mut ppu = PPU::new();
+
+        // Tile 1: row 0 solid colour 1, other rows colour 3
+        set_addr(&mut ppu, &mut system, 0x0010);
+        for _ in 0..8 {
+            ppu.write_register(&mut system, 0x2007, 0xFF);
+        }
+        set_addr(&mut ppu, &mut system, 0x0019);
+        for _ in 1..8 {
+            ppu.write_register(&mut system, 0x2007, 0xFF);
+        }
+        // Second tile of the top row is tile 1, with attribute palette 2 for the top left
+        set_addr(&mut ppu, &mut system, 0x2001);
+        ppu.write_register(&mut system, 0x2007, 0x01);
+        set_addr(&mut ppu, &mut system, 0x23C0);
+        ppu.write_register(&mut system, 0x2007, 0x02);
+        for (entry, colour) in [(0x00, 0x0F), (0x09, 0x16), (0x0B, 0x30)] {
+            ppu.palette[entry] = colour;
+        }
+
+        // Scroll 3 pixels right, start from the pre-render line
+        ppu.write_register(&mut system, 0x2005, 3);
+        ppu.write_register(&mut system, 0x2005, 0);
+        ppu.mask = 0x0A;
+        ppu.scanline = 261;
+        run_dots(&mut ppu, &mut system, 341 + 341 * 2);
+
+        let row = |y: usize| &ppu.framebuffer.row(y)[..16];
+        // Tile 1 starts at x = 8 - 3
+        assert_eq!(&row(0)[..6], &[0x0F, 0x0F, 0x0F, 0x0F, 0x0F, 0x16]);
+        assert_eq!(&row(0)[5..13], &[0x16; 8]);
+        assert_eq!(row(0)[13], 0x0F);
+        assert_eq!(&row(1)[5..13], &[0x30; 8]);
+    }
+
+    #[test]
+    fn hides_left_column() {
+        let mut system = system();
+        let mut ppu = PPU::new();
+        set_addr(&mut ppu, &mut system, 0x0000);
+        for _ in 0..16 {
+            ppu.write_register(&mut system, 0x2007, 0xFF);
+        }
+        ppu.palette[0x03] = 0x2A;
+        ppu.mask = 0x08;
+        ppu.scanline = 261;
+        run_dots(&mut ppu, &mut system, 341 * 2);
+
+        assert_eq!(ppu.framebuffer.pixels()[7], 0x00);
+        assert_eq!(ppu.framebuffer.pixels()[8], 0x2A);
+    }
+}
@@ -0,0 +1,104 @@
+/**
+* Frame buffer
+*
+* The PPU writes one 9-bit colour (emphasis bits above the palette RAM value) per pixel, row by
+* row from the top left, 256x240. Frontends turn a finished frame into 32-bit pixels with a
+* Palette and get plain bytes back, ready to upload to a texture in one go, hash or save:
+*   rgba: R, G, B, A for each pixel (SDL's RGBA32, OpenGL's RGBA/UNSIGNED_BYTE)
+*   argb: A, R, G, B for each pixel (SDL's ARGB32)
+*/
+use super::{Color, Palette, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+#[derive(Clone)]
+pub struct FrameBuffer {
+    pixels: Vec<u16>,
+    // Last conversion, kept so converting every frame doesn't allocate
+    bytes: Vec<u8>,
+}
+
+impl FrameBuffer {
+    pub const WIDTH: usize = SCREEN_WIDTH;
+    pub const HEIGHT: usize = SCREEN_HEIGHT;
+    // Bytes per row of converted pixels
+    pub const PITCH: usize = SCREEN_WIDTH * 4;
+
+    pub fn new() -> FrameBuffer {
+        FrameBuffer {
+            pixels: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            bytes: Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT * 4),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, x: usize, y: usize) -> u16 {
+        self.pixels[y * SCREEN_WIDTH + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, colour: u16) {
+        self.pixels[y * SCREEN_WIDTH + x] = colour;
+    }
+
+    // The whole frame, row-major
+    #[cfg(test)]
+    pub fn pixels(&self) -> &[u16] {
+        &self.pixels
+    }
+
+    #[cfg(test)]
+    pub fn row(&self, y: usize) -> &[u16] {
+        &self.pixels[y * SCREEN_WIDTH..(y + 1) * SCREEN_WIDTH]
+    }
+
+    pub fn rgba(&mut self, palette: &Palette) -> &[u8] {
+        self.convert(palette, |Color { r, g, b }| [r, g, b, 0xFF])
+    }
+
+    pub fn argb(&mut self, palette: &Palette) -> &[u8] {
+        self.convert(palette, |Color { r, g, b }| [0xFF, r, g, b])
+    }
+
+    fn convert(&mut self, palette: &Palette, pixel: fn(Color) -> [u8; 4]) -> &[u8] {
+        self.bytes.clear();
+        for &colour in &self.pixels {
+            self.bytes.extend_from_slice(&pixel(palette.colour(colour)));
+        }
+        &self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn row_major_pixels() {
+        let mut frame = FrameBuffer::new();
+        frame.set(255, 0, 0x01);
+        frame.set(0, 1, 0x02);
+        frame.set(255, 239, 0x03);
+        assert_eq!(frame.pixels().len(), 256 * 240);
+        assert_eq!(frame.pixels()[255], 0x01);
+        assert_eq!(frame.pixels()[256], 0x02);
+        assert_eq!(frame.row(239)[255], 0x03);
+        assert_eq!(frame.get(0, 1), 0x02);
+    }
+
+    #[test]
+    fn converts_to_bytes() {
+        let palette = Palette::default();
+        let mut frame = FrameBuffer::new();
+        frame.set(1, 0, 0x30);
+        let Color { r, g, b } = palette.colour(0x30);
+        let Color {
+            r: r0,
+            g: g0,
+            b: b0,
+        } = palette.colour(0x00);
+
+        let rgba = frame.rgba(&palette);
+        assert_eq!(rgba.len(), FrameBuffer::PITCH * FrameBuffer::HEIGHT);
+        assert_eq!(&rgba[..8], &[r0, g0, b0, 0xFF, r, g, b, 0xFF]);
+        let argb = frame.argb(&palette);
+        assert_eq!(&argb[4..8], &[0xFF, r, g, b]);
+    }
+}
@@ -1,25 +1,73 @@
 /**
 * NES Picture Processing Unit (PPU) module
+*
+* CPU-facing registers at $2000-$2007 (mirrored through $3FFF):
+*   $2000 PPUCTRL, $2001 PPUMASK, $2002 PPUSTATUS, $2003 OAMADDR, $2004 OAMDATA,
+*   $2005 PPUSCROLL, $2006 PPUADDR, $2007 PPUDATA
+* Scrolling and VRAM access share the internal "loopy" registers: v (current VRAM address), t
+* (temporary address, the top left of the screen), x (fine X scroll) and w (the write toggle
+* for the two-write $2005/$2006 registers). v and t are laid out as
+*   yyy NN YYYYY XXXXX: fine Y, nametable, coarse Y, coarse X
+* Each NTSC frame is 262 scanlines of 341 dots: 240 visible lines, the idle post-render line 240,
+* vertical blank on 241-260 and the pre-render line 261, which makes the same fetches as a
+* visible line to set up the next frame. PAL and Dendy frames are 312 lines (see Region).
+* The vblank flag ($2002 bit 7) is set at line 241 dot 1 (291 on Dendy) and cleared with the
+* sprite flags at dot 1 of the pre-render line. NMI fires whenever the flag and ctrl bit 7 are
+* both set after one of them wasn't, so enabling NMI during vblank raises one straight away.
+* Reading $2002 the dot before the flag is set hides it for the whole frame, and reading it
+* within two dots after cancels that frame's NMI. With rendering on, NTSC odd frames skip the
+* last dot of the pre-render line.
+* mask bit 0 turns the picture grayscale, and bits 5-7 emphasise red, green and blue by dimming
+* the other two, which gives 8 versions of the 64 colours.
 */
+mod background;
+mod framebuffer;
+mod palette;
 mod screen;
+mod sprites;
 
 use std::sync::{Arc, Mutex};
 
-use crate::{cartridge::Cartridge, system::System};
-pub use screen::{Color, Screen};
+use crate::region::Region;
+use crate::system::System;
+use background::Background;
+pub use framebuffer::FrameBuffer;
+pub use palette::{Palette, BUILT_IN as BUILT_IN_PALETTES};
+pub use screen::Color;
+use sprites::LineSprite;
 
-#[derive(Clone, Copy)]
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+#[derive(Clone)]
 pub struct PPU {
     pub ctrl: u8,     // $2000
     pub mask: u8,     // $2001
     pub status: u8,   // $2002
-    pub oam_addr: u8, // $2003
-    pub oam_data: u8, // $2004
-    pub scroll: u8,   // $2005
-    pub addr: u8,     // $2006
-    pub data: u8,     // $2007
+    pub oam_addr: u8, // $2003, $2004 goes through System::oam
+
+    pub vram_addr: u16,     // v
+    pub temp_addr: u16,     // t
+    pub fine_x: u8,         // x
+    pub write_toggle: bool, // w: second $2005/$2006 write pending
+    pub read_buffer: u8,    // $2007 reads return the previous read's byte
+    pub open_bus: u8,       // Last value on the PPU's data bus, read back from write-only registers
 
+    pub palette: [u8; 0x20],
+
+    pub region: Region,
     pub scanline: u16,
+    pub dot: u16, // Next dot to run
+    pub frame: u64,
+    nmi_line: bool,        // Vblank flag and NMI enable together
+    nmi_pending: bool,     // Rising edge of nmi_line, for the CPU to pick up
+    suppress_vblank: bool, // $2002 read right before the flag would be set
+    background: Background,
+    // Secondary OAM, drawn on the next line
+    sprites: Vec<LineSprite>,
+    // Hardware drops sprites after the eighth on a line
+    pub sprite_limit: bool,
+    pub framebuffer: FrameBuffer,
 }
 
 #[derive(Clone, Copy)]
@@ -37,85 +85,659 @@ impl PPU {
             mask: 0,
             status: 0,
             oam_addr: 0,
-            oam_data: 0,
-            scroll: 0,
-            addr: 0,
-            data: 0,
 
+            vram_addr: 0,
+            temp_addr: 0,
+            fine_x: 0,
+            write_toggle: false,
+            read_buffer: 0,
+            open_bus: 0,
+
+            palette: [0; 0x20],
+
+            region: Region::Ntsc,
             scanline: 0,
+            dot: 0,
+            frame: 0,
+            nmi_line: false,
+            nmi_pending: false,
+            suppress_vblank: false,
+            background: Background::default(),
+            sprites: Vec::with_capacity(64),
+            sprite_limit: true,
+            framebuffer: FrameBuffer::new(),
         }
     }
 
     pub fn tick(&mut self, system: &mut Arc<Mutex<System>>, cycles: &mut u64) -> u64 {
-        let vram = system.lock().unwrap().vram.clone();
-        let ram = system.lock().unwrap().ram.clone();
-        let cpu = system.lock().unwrap().cpu.clone();
+        let mapper = system.lock().unwrap().rom.mapper.clone();
         let mut cycle = *cycles;
 
+        // Let the cartridge see where the PPU is before it makes this dot's fetches
+        mapper
+            .lock()
+            .unwrap()
+            .ppu_tick(self.scanline, cycle as u16, self.rendering_enabled());
+
+        let pre_render = self.region.pre_render_line();
         match self.scanline {
-            0..=239 => self.render_scanline(cycle),
-            240 => self.post_render(),
-            241..=260 => self.vertical_blank(),
-            _ => {}
+            0..=239 => self.render_scanline(system, cycle as u16),
+            line if line == pre_render => self.render_scanline(system, cycle as u16),
+            line if line >= self.region.vblank_line() => self.vertical_blank(cycle as u16),
+            _ => self.post_render(),
         }
 
         cycle += 1;
+        // Odd frame: straight from dot 339 of the pre-render line to line 0 (NTSC only)
+        if self.scanline == pre_render
+            && cycle == 340
+            && self.frame % 2 == 1
+            && self.rendering_enabled()
+            && self.region.skips_odd_frame_dot()
+        {
+            cycle += 1;
+        }
         if cycle > 340 {
             cycle = 0;
             self.scanline += 1;
-            if self.scanline > 261 {
+            if self.scanline > pre_render {
                 self.scanline = 0;
+                self.frame += 1;
             }
         }
+        self.dot = cycle as u16;
 
         return cycle;
     }
 
-    pub fn render_scanline(&self, cycles: u64) {
-        if cycles <= 0 {
-            // Idle
-        } else if cycles <= 256 {
-            // Fetch 2 tiles per cycle, including tile and attribute
-        } else if cycles <= 320 {
-        } else if cycles <= 336 {
-        } else if cycles <= 340 {
+    pub fn rendering_enabled(&self) -> bool {
+        self.mask & 0x18 != 0
+    }
+
+    // One dot of a visible (0-239) or pre-render scanline
+    pub fn render_scanline(&mut self, system: &mut Arc<Mutex<System>>, dot: u16) {
+        let pre_render = self.scanline == self.region.pre_render_line();
+        if pre_render && dot == 1 {
+            // Vblank, sprite 0 hit and overflow
+            self.status &= !0xE0;
+            self.update_nmi();
+        }
+
+        if self.rendering_enabled() {
+            self.update_background(system, dot, pre_render);
+            self.update_sprites(system, dot, pre_render);
+        }
+
+        // Pixels come out after the dot's shift
+        if !pre_render && (1..=256).contains(&dot) {
+            self.output_pixel(dot as usize - 1);
+        }
+    }
+
+    fn output_pixel(&mut self, x: usize) {
+        let (background_palette, background_pixel) = self.background_pixel(x);
+        let sprite = self.sprite_pixel(x);
+        if let Some(sprite) = &sprite {
+            if sprite.sprite_zero && background_pixel != 0 && x != 255 {
+                self.status |= 0x40;
+            }
         }
+
+        let (palette, pixel) = match sprite {
+            Some(sprite) if background_pixel == 0 || !sprite.behind_background => {
+                (sprite.palette, sprite.pixel)
+            }
+            _ => (background_palette, background_pixel),
+        };
+
+        // Pixel 0 of every palette is the backdrop colour at $3F00, unless rendering is off and v
+        // points into palette RAM, which shows that entry instead
+        let entry = if pixel != 0 {
+            (palette << 2 | pixel) as usize
+        } else if !self.rendering_enabled() && self.vram_addr & 0x3F00 == 0x3F00 {
+            palette_index(self.vram_addr)
+        } else {
+            0
+        };
+
+        let mut colour = self.palette[entry];
+        if self.mask & 0x01 != 0 {
+            // Grayscale keeps only the brightness column
+            colour &= 0x30;
+        }
+        // Emphasis bits go above the 6-bit colour, in NTSC's red, green, blue order; PAL and
+        // Dendy PPUs have the red and green bits the other way around
+        let mut emphasis = self.mask as u16 & 0xE0;
+        if self.region != Region::Ntsc {
+            emphasis = emphasis & 0x80 | (emphasis & 0x20) << 1 | (emphasis & 0x40) >> 1;
+        }
+        self.framebuffer
+            .set(x, self.scanline as usize, emphasis << 1 | colour as u16);
     }
 
     pub fn post_render(&self) {
         // Post render
     }
 
-    pub fn vertical_blank(&self) {
-        // Vertical blank
+    pub fn vertical_blank(&mut self, dot: u16) {
+        if self.scanline == self.region.vblank_line() && dot == 1 {
+            if !self.suppress_vblank {
+                self.status |= 0x80;
+            }
+            self.suppress_vblank = false;
+            self.update_nmi();
+        }
+    }
+
+    fn update_nmi(&mut self) {
+        let nmi_line = self.status & 0x80 != 0 && self.ctrl & 0x80 != 0;
+        if nmi_line && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = nmi_line;
     }
 
-    pub fn fetch(&self, system: &mut Arc<Mutex<System>>, sprite_num: u8) -> Sprite {
+    // Whether an NMI has been raised since the last call
+    pub fn take_nmi(&mut self) -> bool {
+        let nmi = self.nmi_pending;
+        self.nmi_pending = false;
+        nmi
+    }
+
+    // CPU reads of $2000-$2007
+    pub fn read_register(&mut self, system: &mut Arc<Mutex<System>>, address: u16) -> u8 {
+        let value = match address & 0x07 {
+            2 => {
+                // The low bits aren't driven and read back whatever was last on the bus
+                let value = (self.status & 0xE0) | (self.open_bus & 0x1F);
+                if self.scanline == self.region.vblank_line() {
+                    match self.dot {
+                        1 => self.suppress_vblank = true,
+                        2..=3 => self.nmi_pending = false,
+                        _ => {}
+                    }
+                }
+                self.status &= !0x80;
+                self.update_nmi();
+                self.write_toggle = false;
+                value
+            }
+            4 => self.read_oam(system),
+            7 => self.read_data(system),
+            _ => self.open_bus,
+        };
+        self.open_bus = value;
+        value
+    }
+
+    // CPU writes to $2000-$2007
+    pub fn write_register(&mut self, system: &mut Arc<Mutex<System>>, address: u16, value: u8) {
+        self.open_bus = value;
+        match address & 0x07 {
+            0 => {
+                self.ctrl = value;
+                // Nametable select goes into t
+                self.temp_addr = (self.temp_addr & 0xF3FF) | ((value as u16 & 0x03) << 10);
+                self.update_nmi();
+            }
+            1 => self.mask = value,
+            3 => self.oam_addr = value,
+            4 => self.write_oam(system, value),
+            5 => self.write_scroll(value),
+            6 => self.write_addr(value),
+            7 => self.write_data(system, value),
+            // $2002 is read only
+            _ => {}
+        }
+    }
+
+    // PPU bus: pattern tables from the cartridge, nametables through its mirroring, palette RAM
+    pub fn read_bus(&self, system: &mut Arc<Mutex<System>>, address: u16) -> u8 {
+        let address = address & 0x3FFF;
+        if address >= 0x3F00 {
+            return self.palette[palette_index(address)];
+        }
+
+        let mapper = system.lock().unwrap().rom.mapper.clone();
+        let vram = system.lock().unwrap().vram.clone();
+        match address {
+            0x0000..=0x1FFF => mapper
+                .lock()
+                .unwrap()
+                .read_pattern(address, &vram.lock().unwrap()),
+            _ => mapper
+                .lock()
+                .unwrap()
+                .read_nametable(address, &vram.lock().unwrap()),
+        }
+    }
+
+    pub fn write_bus(&mut self, system: &mut Arc<Mutex<System>>, address: u16, value: u8) {
+        let address = address & 0x3FFF;
+        if address >= 0x3F00 {
+            // Palette entries are 6 bits
+            self.palette[palette_index(address)] = value & 0x3F;
+            return;
+        }
+
+        let mapper = system.lock().unwrap().rom.mapper.clone();
+        let vram = system.lock().unwrap().vram.clone();
+        match address {
+            0x0000..=0x1FFF => {
+                mapper
+                    .lock()
+                    .unwrap()
+                    .write_pattern(address, value, &mut vram.lock().unwrap())
+            }
+            _ => mapper
+                .lock()
+                .unwrap()
+                .write_nametable(address, value, &mut vram.lock().unwrap()),
+        }
+    }
+
+    // $2005, X scroll first then Y
+    pub fn write_scroll(&mut self, value: u8) {
+        if !self.write_toggle {
+            // Coarse X into t, fine X into x
+            self.temp_addr = (self.temp_addr & !0x001F) | (value as u16 >> 3);
+            self.fine_x = value & 0x07;
+        } else {
+            // Fine Y and coarse Y into t
+            self.temp_addr = (self.temp_addr & 0x0C1F)
+                | ((value as u16 & 0x07) << 12)
+                | ((value as u16 & 0xF8) << 2);
+        }
+        self.write_toggle = !self.write_toggle;
+    }
+
+    // $2006, high byte first. The second write copies t into v.
+    pub fn write_addr(&mut self, value: u8) {
+        if !self.write_toggle {
+            // Bit 14 of t is cleared along with the top two address bits
+            self.temp_addr = ((value as u16 & 0x3F) << 8) | (self.temp_addr & 0x00FF);
+        } else {
+            self.temp_addr = (self.temp_addr & 0xFF00) | value as u16;
+            self.vram_addr = self.temp_addr;
+        }
+        self.write_toggle = !self.write_toggle;
+    }
+
+    // $2007
+    pub fn read_data(&mut self, system: &mut Arc<Mutex<System>>) -> u8 {
+        let address = self.vram_addr & 0x3FFF;
+        let value = if address >= 0x3F00 {
+            // Palette reads skip the buffer, which gets the nametable byte "under" the palette
+            self.read_buffer = self.read_bus(system, address - 0x1000);
+            self.read_bus(system, address) | (self.open_bus & 0xC0)
+        } else {
+            let value = self.read_buffer;
+            self.read_buffer = self.read_bus(system, address);
+            value
+        };
+        self.increment_vram_addr();
+        value
+    }
+
+    pub fn write_data(&mut self, system: &mut Arc<Mutex<System>>, value: u8) {
+        self.write_bus(system, self.vram_addr, value);
+        self.increment_vram_addr();
+    }
+
+    // $2004 reads don't move OAMADDR. Attribute bytes have no bits 2-4.
+    fn read_oam(&self, system: &mut Arc<Mutex<System>>) -> u8 {
         let oam = system.lock().unwrap().oam.clone();
+        let value = oam.lock().unwrap()[self.oam_addr as usize];
+        if self.oam_addr & 0x03 == 0x02 {
+            return value & 0xE3;
+        }
+        value
+    }
 
-        // Fetch 2 tiles per cycle, including tile and attribute
-        let sprite_addr = sprite_num * 4;
-        let y_coord = sprite_addr;
-        let tile_num = sprite_addr + 1;
-        let attribute = sprite_addr + 2;
-        let x_coord = sprite_addr + 3;
+    fn write_oam(&mut self, system: &mut Arc<Mutex<System>>, value: u8) {
+        let oam = system.lock().unwrap().oam.clone();
+        oam.lock().unwrap()[self.oam_addr as usize] = value;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
 
+    // Across a row with ctrl bit 2 clear, down a column with it set
+    fn increment_vram_addr(&mut self) {
+        let step = if self.ctrl & 0x04 != 0 { 32 } else { 1 };
+        self.vram_addr = self.vram_addr.wrapping_add(step) & 0x7FFF;
+    }
+
+    pub fn fetch(&self, system: &mut Arc<Mutex<System>>, sprite_num: u8) -> Sprite {
+        let oam = system.lock().unwrap().oam.clone();
+        let oam = oam.lock().unwrap();
+
+        let sprite_addr = sprite_num as usize * 4;
         return Sprite {
-            y: oam.lock().unwrap()[y_coord as usize],
-            tile: oam.lock().unwrap()[tile_num as usize],
-            attr: oam.lock().unwrap()[attribute as usize],
-            x: oam.lock().unwrap()[x_coord as usize],
+            y: oam[sprite_addr],
+            tile: oam[sprite_addr + 1],
+            attr: oam[sprite_addr + 2],
+            x: oam[sprite_addr + 3],
         };
     }
+}
+
+// $3F00-$3FFF holds 32 entries mirrored 8 times, and the sprite palettes' first entries
+// ($3F10/$3F14/$3F18/$3F1C) are the background palettes' ($3F00/$3F04/$3F08/$3F0C)
+fn palette_index(address: u16) -> usize {
+    let index = address as usize & 0x1F;
+    if index & 0x13 == 0x10 {
+        return index & 0x0F;
+    }
+    index
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    // NROM with CHR RAM and horizontal mirroring
+    pub fn system() -> Arc<Mutex<System>> {
+        let mut file = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0x00, 0x00];
+        file.resize(16 + 0x4000, 0);
+        let rom = Cartridge::from_bytes(&file).unwrap();
+        Arc::new(Mutex::new(System::new(rom)))
+    }
 
-    pub fn get_screen(&self, rom: Cartridge) -> Screen {
-        if rom.chr_rom.len() == 0 {
-            return Screen::new();
+    pub fn set_addr(ppu: &mut PPU, system: &mut Arc<Mutex<System>>, address: u16) {
+        ppu.write_register(system, 0x2006, (address >> 8) as u8);
+        ppu.write_register(system, 0x2006, address as u8);
+    }
+
+    pub fn run_dots(ppu: &mut PPU, system: &mut Arc<Mutex<System>>, dots: usize) {
+        let mut cycle = ppu.dot as u64;
+        for _ in 0..dots {
+            cycle = ppu.tick(system, &mut cycle);
         }
+    }
+
+    #[test]
+    fn scroll_and_addr_share_loopy_registers() {
+        let mut system = system();
+        let mut ppu = PPU::new();
+        ppu.write_register(&mut system, 0x2000, 0x00);
+        ppu.read_register(&mut system, 0x2002);
+        ppu.write_register(&mut system, 0x2005, 0x7D);
+        assert_eq!(
+            (ppu.temp_addr, ppu.fine_x, ppu.write_toggle),
+            (0x000F, 5, true)
+        );
+        ppu.write_register(&mut system, 0x2005, 0x5E);
+        assert_eq!((ppu.temp_addr, ppu.write_toggle), (0x616F, false));
+        ppu.write_register(&mut system, 0x2006, 0x3D);
+        assert_eq!(ppu.temp_addr, 0x3D6F);
+        ppu.write_register(&mut system, 0x2006, 0xF0);
+        assert_eq!((ppu.temp_addr, ppu.vram_addr), (0x3DF0, 0x3DF0));
+
+        // Nametable select from $2000 lands in t
+        ppu.write_register(&mut system, 0x2000, 0x03);
+        assert_eq!(ppu.temp_addr & 0x0C00, 0x0C00);
+    }
+
+    #[test]
+    fn status_read_clears_vblank_and_toggle() {
+        let mut system = system();
+        let mut ppu = PPU::new();
+        ppu.status = 0x80;
+        ppu.write_register(&mut system, 0x2006, 0x21);
+        assert!(ppu.write_toggle);
+
+        assert_eq!(ppu.read_register(&mut system, 0x2002) & 0xE0, 0x80);
+        assert!(!ppu.write_toggle);
+        assert_eq!(ppu.read_register(&mut system, 0x2002) & 0x80, 0x00);
+    }
+
+    #[test]
+    fn data_reads_are_buffered() {
+        let mut system = system();
+        let mut ppu = PPU::new();
+        set_addr(&mut ppu, &mut system, 0x2000);
+        for value in [0x11, 0x22, 0x33] {
+            ppu.write_register(&mut system, 0x2007, value);
+        }
+        assert_eq!(ppu.vram_addr, 0x2003);
+
+        set_addr(&mut ppu, &mut system, 0x2000);
+        ppu.read_register(&mut system, 0x2007);
+        assert_eq!(ppu.read_register(&mut system, 0x2007), 0x11);
+        assert_eq!(ppu.read_register(&mut system, 0x2007), 0x22);
+
+        // Horizontal mirroring: $2400 is $2000
+        set_addr(&mut ppu, &mut system, 0x2401);
+        ppu.read_register(&mut system, 0x2007);
+        assert_eq!(ppu.read_register(&mut system, 0x2007), 0x22);
+    }
+
+    #[test]
+    fn increments_by_32_down_columns() {
+        let mut system = system();
+        let mut ppu = PPU::new();
+        ppu.write_register(&mut system, 0x2000, 0x04);
+        set_addr(&mut ppu, &mut system, 0x2000);
+        ppu.write_register(&mut system, 0x2007, 0xAA);
+        ppu.write_register(&mut system, 0x2007, 0xBB);
+        assert_eq!(ppu.vram_addr, 0x2040);
+        assert_eq!(ppu.read_bus(&mut system, 0x2020), 0xBB);
+    }
+
+    #[test]
+    fn palette_reads_skip_the_buffer() {
+        let mut system = system();
+        let mut ppu = PPU::new();
+        set_addr(&mut ppu, &mut system, 0x2F05);
+        ppu.write_register(&mut system, 0x2007, 0x5A);
+        set_addr(&mut ppu, &mut system, 0x3F05);
+        ppu.write_register(&mut system, 0x2007, 0xFF);
+
+        set_addr(&mut ppu, &mut system, 0x3F05);
+        assert_eq!(ppu.read_register(&mut system, 0x2007) & 0x3F, 0x3F);
+        // The buffer now holds the nametable byte under $3F05
+        assert_eq!(ppu.read_buffer, 0x5A);
+    }
+
+    #[test]
+    fn palette_mirrors() {
+        let mut system = system();
+        let mut ppu = PPU::new();
+        ppu.write_bus(&mut system, 0x3F10, 0x21);
+        assert_eq!(ppu.read_bus(&mut system, 0x3F00), 0x21);
+        ppu.write_bus(&mut system, 0x3F0C, 0x0F);
+        assert_eq!(ppu.read_bus(&mut system, 0x3F1C), 0x0F);
+        ppu.write_bus(&mut system, 0x3F11, 0x16);
+        assert_eq!(ppu.read_bus(&mut system, 0x3F01), 0x00);
+        assert_eq!(ppu.read_bus(&mut system, 0x3F31), 0x16);
+        assert_eq!(ppu.read_bus(&mut system, 0x3FF1), 0x16);
+    }
+
+    #[test]
+    fn vblank_raises_nmi() {
+        let mut system = system();
+        let mut ppu = PPU::new();
+        ppu.write_register(&mut system, 0x2000, 0x80);
+        ppu.scanline = 241;
+        run_dots(&mut ppu, &mut system, 1);
+        assert_eq!(ppu.status & 0x80, 0);
+        run_dots(&mut ppu, &mut system, 1);
+        assert_eq!(ppu.status & 0x80, 0x80);
+        assert!(ppu.take_nmi());
+        assert!(!ppu.take_nmi());
+
+        ppu.status |= 0x60;
+        ppu.scanline = 261;
+        ppu.dot = 0;
+        run_dots(&mut ppu, &mut system, 2);
+        assert_eq!(ppu.status & 0xE0, 0);
+    }
+
+    #[test]
+    fn enabling_nmi_during_vblank() {
+        let mut system = system();
+        let mut ppu = PPU::new();
+        ppu.scanline = 241;
+        run_dots(&mut ppu, &mut system, 2);
+        assert!(!ppu.take_nmi());
+
+        ppu.write_register(&mut system, 0x2000, 0x80);
+        assert!(ppu.take_nmi());
+        ppu.write_register(&mut system, 0x2000, 0x80);
+        assert!(!ppu.take_nmi());
+
+        // Toggling it again raises another one
+        ppu.write_register(&mut system, 0x2000, 0x00);
+        ppu.write_register(&mut system, 0x2000, 0x80);
+        assert!(ppu.take_nmi());
+
+        // But not once the flag has been read
+        ppu.read_register(&mut system, 0x2002);
+        ppu.write_register(&mut system, 0x2000, 0x00);
+        ppu.write_register(&mut system, 0x2000, 0x80);
+        assert!(!ppu.take_nmi());
+    }
+
+    #[test]
+    fn status_read_race() {
+        let mut system = system();
+        let mut ppu = PPU::new();
+        ppu.write_register(&mut system, 0x2000, 0x80);
+
+        // The dot before the flag is set
+        ppu.scanline = 241;
+        ppu.dot = 1;
+        assert_eq!(ppu.read_register(&mut system, 0x2002) & 0x80, 0);
+        run_dots(&mut ppu, &mut system, 1);
+        assert_eq!(ppu.status & 0x80, 0);
+        assert!(!ppu.take_nmi());
+
+        // Just after: the flag reads back but the NMI is lost
+        ppu.dot = 1;
+        run_dots(&mut ppu, &mut system, 1);
+        assert_eq!(ppu.read_register(&mut system, 0x2002) & 0x80, 0x80);
+        assert!(!ppu.take_nmi());
+    }
+
+    #[test]
+    fn odd_frames_skip_a_dot() {
+        let mut system = system();
+        let mut ppu = PPU::new();
+        ppu.scanline = 261;
+        ppu.dot = 339;
+        run_dots(&mut ppu, &mut system, 1);
+        assert_eq!((ppu.scanline, ppu.dot), (261, 340));
+
+        ppu.mask = 0x08;
+        ppu.frame = 1;
+        ppu.dot = 339;
+        run_dots(&mut ppu, &mut system, 1);
+        assert_eq!((ppu.scanline, ppu.dot, ppu.frame), (0, 0, 2));
+
+        // Even frames don't
+        ppu.scanline = 261;
+        ppu.dot = 339;
+        run_dots(&mut ppu, &mut system, 1);
+        assert_eq!((ppu.scanline, ppu.dot), (261, 340));
+    }
+
+    #[test]
+    fn oam_access() {
+        let mut system = system();
+        let mut ppu = PPU::new();
+        ppu.write_register(&mut system, 0x2003, 0xFF);
+        ppu.write_register(&mut system, 0x2004, 0x10);
+        ppu.write_register(&mut system, 0x2004, 0x11);
+        // Writes increment OAMADDR, wrapping round
+        assert_eq!(ppu.oam_addr, 0x01);
+        let oam = system.lock().unwrap().oam.clone();
+        assert_eq!(oam.lock().unwrap()[0x00], 0x11);
+
+        ppu.write_register(&mut system, 0x2003, 0xFF);
+        assert_eq!(ppu.read_register(&mut system, 0x2004), 0x10);
+        assert_eq!(ppu.read_register(&mut system, 0x2004), 0x10);
+        ppu.write_register(&mut system, 0x2003, 0x02);
+        ppu.write_register(&mut system, 0x2004, 0xFF);
+        ppu.write_register(&mut system, 0x2003, 0x02);
+        assert_eq!(ppu.read_register(&mut system, 0x2004), 0xE3);
+    }
+
+    #[test]
+    fn grayscale_and_emphasis() {
+        let mut system = system();
+        let mut ppu = PPU::new();
+        ppu.palette[0] = 0x16;
+        ppu.mask = 0xA1;
+        ppu.dot = 1;
+        run_dots(&mut ppu, &mut system, 1);
+        assert_eq!(ppu.framebuffer.pixels()[0], 0x150);
+    }
+
+    #[test]
+    fn pal_swaps_red_and_green_emphasis() {
+        let mut system = system();
+        let mut ppu = PPU::new();
+        ppu.region = Region::Pal;
+        ppu.palette[0] = 0x16;
+        ppu.dot = 1;
+
+        // PAL's green bit 5 is stored where NTSC keeps green, and red bit 6 where it keeps red
+        ppu.mask = 0x20;
+        run_dots(&mut ppu, &mut system, 1);
+        assert_eq!(ppu.framebuffer.pixels()[0], 0x80 | 0x16);
+
+        ppu.mask = 0xC0;
+        run_dots(&mut ppu, &mut system, 1);
+        assert_eq!(ppu.framebuffer.pixels()[1], 0x140 | 0x16);
+    }
+
+    #[test]
+    fn backdrop_follows_v_while_rendering_is_off() {
+        let mut system = system();
+        let mut ppu = PPU::new();
+        ppu.palette[0x05] = 0x2A;
+        ppu.palette[0x00] = 0x0F;
+        set_addr(&mut ppu, &mut system, 0x3F05);
+        ppu.dot = 1;
+        run_dots(&mut ppu, &mut system, 1);
+        assert_eq!(ppu.framebuffer.pixels()[0], 0x2A);
+
+        // Including the mirrored backdrop entries
+        set_addr(&mut ppu, &mut system, 0x3F10);
+        run_dots(&mut ppu, &mut system, 1);
+        assert_eq!(ppu.framebuffer.pixels()[1], 0x0F);
+    }
+
+    #[test]
+    fn pal_and_dendy_frames() {
+        let mut system = system();
+        let mut ppu = PPU::new();
+        ppu.write_register(&mut system, 0x2000, 0x80);
+        ppu.mask = 0x08;
+        ppu.frame = 1;
 
-        let mut screen = Screen::new();
-        screen.draw_entire_sprite_map(rom.clone(), 0, 0);
+        // PAL: vblank at 241 and no odd frame skip on line 311
+        ppu.region = Region::Pal;
+        ppu.scanline = 241;
+        run_dots(&mut ppu, &mut system, 2);
+        assert!(ppu.take_nmi());
+        ppu.scanline = 311;
+        ppu.dot = 339;
+        run_dots(&mut ppu, &mut system, 1);
+        assert_eq!((ppu.scanline, ppu.dot), (311, 340));
+        run_dots(&mut ppu, &mut system, 1);
+        assert_eq!((ppu.scanline, ppu.frame), (0, 2));
 
-        return screen;
+        // Dendy: vblank waits for line 291
+        ppu.region = Region::Dendy;
+        ppu.read_register(&mut system, 0x2002);
+        ppu.scanline = 241;
+        ppu.dot = 0;
+        run_dots(&mut ppu, &mut system, 341 * 50);
+        assert_eq!(ppu.status & 0x80, 0);
+        assert_eq!(ppu.scanline, 291);
+        run_dots(&mut ppu, &mut system, 2);
+        assert_eq!(ppu.status & 0x80, 0x80);
+        assert!(ppu.take_nmi());
     }
 }
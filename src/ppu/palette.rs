@@ -0,0 +1,384 @@
+/**
+* Colour palettes
+*
+* Turns the PPU's 9-bit colours (emphasis bits above a 6-bit palette value) into RGB. A palette
+* is one of:
+*   a .pal file: 64 RGB triples (192 bytes), with emphasis worked out here, or 512 (1536 bytes)
+*   a built-in one: "default", "rgb" (the RGB PPU in arcade boards and some TVs) or "ntsc"
+*   "ntsc:<setting>=<value>,...": a decoded NTSC signal, with settings hue (degrees),
+*     saturation, contrast, brightness and gamma (that of the TV being imitated, 2.2 leaves the
+*     levels alone)
+* The NTSC decoder follows the PPU's output: each colour is a square wave between two voltages,
+* high for 6 of the 12 phases of the colour subcarrier starting at a phase set by its hue, and
+* each emphasis bit attenuates the signal during its own half of the cycle.
+*/
+use std::f32::consts::PI;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::Color;
+
+pub const BUILT_IN: [&str; 3] = ["default", "rgb", "ntsc"];
+
+// How much an emphasis bit dims the other channels in a 64-colour palette
+const EMPHASIS_ATTENUATION: f32 = 0.816328;
+
+#[rustfmt::skip]
+const DEFAULT: [[u8; 3]; 64] = [
+    [0x75, 0x75, 0x75], [0x27, 0x1B, 0x8F], [0x00, 0x00, 0xAB], [0x47, 0x00, 0x9F],
+    [0x8F, 0x00, 0x77], [0xAB, 0x00, 0x13], [0xA7, 0x00, 0x00], [0x7F, 0x0B, 0x00],
+    [0x43, 0x2F, 0x00], [0x00, 0x47, 0x00], [0x00, 0x51, 0x00], [0x00, 0x3F, 0x17],
+    [0x1B, 0x3F, 0x5F], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+    [0xBC, 0xBC, 0xBC], [0x00, 0x73, 0xEF], [0x23, 0x3B, 0xEF], [0x83, 0x00, 0xF3],
+    [0xBF, 0x00, 0xBF], [0xE7, 0x00, 0x5B], [0xDB, 0x2B, 0x00], [0xCB, 0x4F, 0x0F],
+    [0x8B, 0x73, 0x00], [0x00, 0x97, 0x00], [0x00, 0xAB, 0x00], [0x00, 0x93, 0x3B],
+    [0x00, 0x83, 0x8B], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+    [0xFF, 0xFF, 0xFF], [0x3F, 0xBF, 0xFF], [0x5F, 0x97, 0xFF], [0xA7, 0x8B, 0xFD],
+    [0xF7, 0x7B, 0xFF], [0xFF, 0x77, 0xB7], [0xFF, 0x77, 0x63], [0xFF, 0x9B, 0x3B],
+    [0xF3, 0xBF, 0x3F], [0x83, 0xD3, 0x13], [0x4F, 0xDF, 0x4B], [0x58, 0xF8, 0x98],
+    [0x00, 0xEB, 0xDB], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+    [0xFF, 0xFF, 0xFF], [0xAB, 0xE7, 0xFF], [0xC7, 0xD7, 0xFF], [0xD7, 0xCB, 0xFF],
+    [0xFF, 0xC7, 0xFF], [0xFF, 0xC7, 0xDB], [0xFF, 0xBF, 0xB3], [0xFF, 0xDB, 0xAB],
+    [0xFF, 0xE7, 0xA3], [0xE3, 0xFF, 0xA3], [0xAB, 0xF3, 0xBF], [0xB3, 0xFF, 0xCF],
+    [0x9F, 0xFF, 0xF3], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+];
+
+// RGB PPU (2C03), 3 bits per channel
+#[rustfmt::skip]
+const RGB_PPU: [u16; 64] = [
+    0o333, 0o014, 0o006, 0o326, 0o403, 0o503, 0o510, 0o420,
+    0o320, 0o120, 0o031, 0o040, 0o022, 0o000, 0o000, 0o000,
+    0o555, 0o036, 0o027, 0o407, 0o507, 0o704, 0o700, 0o630,
+    0o430, 0o140, 0o040, 0o053, 0o044, 0o000, 0o000, 0o000,
+    0o777, 0o357, 0o447, 0o637, 0o707, 0o737, 0o740, 0o750,
+    0o660, 0o360, 0o070, 0o276, 0o077, 0o000, 0o000, 0o000,
+    0o777, 0o567, 0o657, 0o757, 0o747, 0o755, 0o764, 0o772,
+    0o773, 0o572, 0o473, 0o276, 0o467, 0o000, 0o000, 0o000,
+];
+
+// Signal voltages for levels 0-3, the low then high half of the wave
+const SIGNAL_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const SIGNAL_BLACK: f32 = 0.518;
+const SIGNAL_WHITE: f32 = 1.962;
+const SIGNAL_EMPHASIS: f32 = 0.746;
+// Puts the decoded hues where a TV with its tint control centred shows them
+const HUE_OFFSET: f32 = 120.0;
+
+#[derive(Debug)]
+pub enum PaletteError {
+    Io(io::Error),
+    // .pal files hold 64 or 512 RGB triples
+    BadSize(usize),
+    UnknownPalette(String),
+    BadSetting(String),
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaletteError::Io(e) => write!(f, "{}", e),
+            PaletteError::BadSize(size) => write!(
+                f,
+                "Palette files are 192 or 1536 bytes, this one is {}",
+                size
+            ),
+            PaletteError::UnknownPalette(name) => write!(
+                f,
+                "No palette called {} (built in: {})",
+                name,
+                BUILT_IN.join(", ")
+            ),
+            PaletteError::BadSetting(setting) => write!(f, "Bad NTSC setting {}", setting),
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {}
+
+impl From<io::Error> for PaletteError {
+    fn from(e: io::Error) -> PaletteError {
+        PaletteError::Io(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NtscSettings {
+    pub hue: f32,
+    pub saturation: f32,
+    pub contrast: f32,
+    pub brightness: f32,
+    pub gamma: f32,
+}
+
+impl Default for NtscSettings {
+    fn default() -> NtscSettings {
+        NtscSettings {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 2.2,
+        }
+    }
+}
+
+impl NtscSettings {
+    // "hue=10,saturation=1.2"
+    pub fn parse(settings: &str) -> Result<NtscSettings, PaletteError> {
+        let mut ntsc = NtscSettings::default();
+        for setting in settings.split(',').filter(|setting| !setting.is_empty()) {
+            let bad = || PaletteError::BadSetting(setting.to_string());
+            let (name, value) = setting.split_once('=').ok_or_else(bad)?;
+            let value: f32 = value.trim().parse().map_err(|_| bad())?;
+            match name.trim() {
+                "hue" => ntsc.hue = value,
+                "saturation" => ntsc.saturation = value,
+                "contrast" => ntsc.contrast = value,
+                "brightness" => ntsc.brightness = value,
+                "gamma" if value > 0.0 => ntsc.gamma = value,
+                _ => return Err(bad()),
+            }
+        }
+        Ok(ntsc)
+    }
+}
+
+#[derive(Clone)]
+pub struct Palette {
+    // Indexed by 9-bit colour
+    colours: Vec<Color>,
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::from_64(&DEFAULT.map(|[r, g, b]| Color::new(r, g, b)))
+    }
+}
+
+impl Palette {
+    // A built-in name, "ntsc:<settings>" or a .pal file
+    pub fn from_spec(spec: &str) -> Result<Palette, PaletteError> {
+        if let Some(palette) = Palette::built_in(spec) {
+            return Ok(palette);
+        }
+        if let Some(settings) = spec.strip_prefix("ntsc:") {
+            return Ok(Palette::ntsc(&NtscSettings::parse(settings)?));
+        }
+        if Path::new(spec).is_file() {
+            return Palette::from_file(spec);
+        }
+        Err(PaletteError::UnknownPalette(spec.to_string()))
+    }
+
+    pub fn built_in(name: &str) -> Option<Palette> {
+        match name {
+            "default" => Some(Palette::default()),
+            "rgb" => Some(Palette::from_64(&RGB_PPU.map(|rgb| {
+                let level = |shift: u16| ((rgb >> shift & 0x07) * 255 / 7) as u8;
+                Color::new(level(6), level(3), level(0))
+            }))),
+            "ntsc" => Some(Palette::ntsc(&NtscSettings::default())),
+            _ => None,
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Palette, PaletteError> {
+        Palette::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Palette, PaletteError> {
+        let colours: Vec<Color> = bytes
+            .chunks_exact(3)
+            .map(|rgb| Color::new(rgb[0], rgb[1], rgb[2]))
+            .collect();
+        match bytes.len() {
+            192 => Ok(Palette::from_64(colours.as_slice().try_into().unwrap())),
+            1536 => Ok(Palette { colours }),
+            size => Err(PaletteError::BadSize(size)),
+        }
+    }
+
+    fn from_64(colours: &[Color; 64]) -> Palette {
+        Palette {
+            colours: (0..512)
+                .map(|index| emphasize(colours[index & 0x3F], index as u16))
+                .collect(),
+        }
+    }
+
+    pub fn ntsc(settings: &NtscSettings) -> Palette {
+        Palette {
+            colours: (0..512).map(|index| decode_ntsc(index, settings)).collect(),
+        }
+    }
+
+    pub fn colour(&self, index: u16) -> Color {
+        self.colours[index as usize & 0x1FF]
+    }
+}
+
+// Each emphasis bit dims the two channels it doesn't name, so combined bits stack, except on the
+// blacks in columns $E/$F
+fn emphasize(colour: Color, index: u16) -> Color {
+    let emphasis = (index >> 6) as u8 & 0x07;
+    if emphasis == 0 || index & 0x0F >= 0x0E {
+        return colour;
+    }
+    let dim = |channel: u8, bit: u8| {
+        let dimmed_by = (emphasis & !bit).count_ones() as i32;
+        (channel as f32 * EMPHASIS_ATTENUATION.powi(dimmed_by)).round() as u8
+    };
+    Color::new(
+        dim(colour.r, 0x01),
+        dim(colour.g, 0x02),
+        dim(colour.b, 0x04),
+    )
+}
+
+// Signal level at one of the 12 phases of the colour subcarrier, 0 at black and 1 at white
+fn ntsc_signal(index: u16, phase: u16) -> f32 {
+    let hue = index & 0x0F;
+    // Columns $E/$F are black
+    let level = if hue > 13 {
+        1
+    } else {
+        (index >> 4) as usize & 0x03
+    };
+    let emphasis = index >> 6;
+    let in_phase = |hue: u16| (hue + phase) % 12 < 6;
+
+    // Column 0 is the high voltage alone, columns $D-$F the low
+    let mut signal = match hue {
+        0 => SIGNAL_HIGH[level],
+        13.. => SIGNAL_LOW[level],
+        _ if in_phase(hue) => SIGNAL_HIGH[level],
+        _ => SIGNAL_LOW[level],
+    };
+    if (emphasis & 0x01 != 0 && in_phase(0))
+        || (emphasis & 0x02 != 0 && in_phase(4))
+        || (emphasis & 0x04 != 0 && in_phase(8))
+    {
+        signal *= SIGNAL_EMPHASIS;
+    }
+    (signal - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK)
+}
+
+fn decode_ntsc(index: u16, settings: &NtscSettings) -> Color {
+    // Average for luma, demodulate I and Q against the subcarrier
+    let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+    for phase in 0..12 {
+        let signal = ntsc_signal(index, phase);
+        let angle = PI * phase as f32 / 6.0 + (HUE_OFFSET + settings.hue).to_radians();
+        y += signal;
+        i += signal * angle.cos();
+        q += signal * angle.sin();
+    }
+    let y = y / 12.0 * settings.contrast + settings.brightness;
+    let i = i / 6.0 * settings.saturation * settings.contrast;
+    let q = q / 6.0 * settings.saturation * settings.contrast;
+
+    // FCC YIQ to RGB
+    let channel = |value: f32| {
+        let value = value.clamp(0.0, 1.0).powf(2.2 / settings.gamma);
+        (value * 255.0).round() as u8
+    };
+    Color::new(
+        channel(y + 0.946882 * i + 0.623557 * q),
+        channel(y - 0.274788 * i - 0.635691 * q),
+        channel(y - 1.108545 * i + 1.709007 * q),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb(colour: Color) -> (u8, u8, u8) {
+        (colour.r, colour.g, colour.b)
+    }
+
+    #[test]
+    fn emphasis_dims_other_channels() {
+        let palette = Palette::default();
+        assert_eq!(rgb(palette.colour(0x30)), (0xFF, 0xFF, 0xFF));
+        // Red
+        assert_eq!(rgb(palette.colour(0x070)), (0xFF, 0xD0, 0xD0));
+        // Red and green dim each other and blue twice
+        assert_eq!(rgb(palette.colour(0x0F0)), (0xD0, 0xD0, 0xAA));
+        // All three dim everything
+        assert_eq!(rgb(palette.colour(0x1F0)), (0xAA, 0xAA, 0xAA));
+        assert_eq!(rgb(palette.colour(0x1CF)), (0x00, 0x00, 0x00));
+    }
+
+    #[test]
+    fn loads_pal_files() {
+        let mut bytes: Vec<u8> = (0..64).flat_map(|i| [i, i, 0xFF]).collect();
+        let palette = Palette::from_bytes(&bytes).unwrap();
+        assert_eq!(rgb(palette.colour(0x05)), (5, 5, 0xFF));
+        assert_eq!(rgb(palette.colour(0x130)), (39, 39, 0xFF));
+
+        // 512 colours are used as they are
+        bytes.resize(1536, 0x12);
+        let palette = Palette::from_bytes(&bytes).unwrap();
+        assert_eq!(rgb(palette.colour(0x130)), (0x12, 0x12, 0x12));
+
+        assert!(matches!(
+            Palette::from_bytes(&bytes[..100]),
+            Err(PaletteError::BadSize(100))
+        ));
+    }
+
+    #[test]
+    fn built_in_palettes() {
+        for name in BUILT_IN {
+            assert!(Palette::from_spec(name).is_ok());
+        }
+        let rgb_ppu = Palette::built_in("rgb").unwrap();
+        assert_eq!(rgb(rgb_ppu.colour(0x16)), (0xFF, 0x00, 0x00));
+        assert!(matches!(
+            Palette::from_spec("bogus"),
+            Err(PaletteError::UnknownPalette(_))
+        ));
+    }
+
+    #[test]
+    fn ntsc_hues_and_levels() {
+        let ntsc = Palette::ntsc(&NtscSettings::default());
+        let (r, g, b) = rgb(ntsc.colour(0x16));
+        assert!(r > g && r > b);
+        let (r, g, b) = rgb(ntsc.colour(0x1A));
+        assert!(g > r && g > b);
+        let (r, g, b) = rgb(ntsc.colour(0x12));
+        assert!(b > r && b > g);
+
+        // Grays have no chroma, $0F is black and $20 white
+        let (r, g, b) = rgb(ntsc.colour(0x10));
+        assert!(r == g && g == b);
+        assert_eq!(rgb(ntsc.colour(0x0F)), (0, 0, 0));
+        assert_eq!(rgb(ntsc.colour(0x20)), (0xFF, 0xFF, 0xFF));
+
+        // Emphasis only darkens
+        let (r, _, _) = rgb(ntsc.colour(0x10));
+        let (emphasized, _, _) = rgb(ntsc.colour(0x1D0));
+        assert!(emphasized < r);
+    }
+
+    #[test]
+    fn ntsc_settings() {
+        let settings = NtscSettings::parse("hue=-15,saturation=1.5,gamma=1.8").unwrap();
+        assert_eq!(settings.hue, -15.0);
+        assert_eq!(settings.saturation, 1.5);
+        assert_eq!(settings.contrast, 1.0);
+        assert_eq!(settings.gamma, 1.8);
+        assert!(NtscSettings::parse("tint=3").is_err());
+        assert!(NtscSettings::parse("hue").is_err());
+        assert!(NtscSettings::parse("gamma=0").is_err());
+
+        let dull = Palette::from_spec("ntsc:saturation=0").unwrap();
+        let (r, g, b) = rgb(dull.colour(0x16));
+        assert!(r == g && g == b);
+    }
+}
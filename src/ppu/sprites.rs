@@ -0,0 +1,379 @@
+/**
+* Sprite evaluation and rendering
+*
+* At dot 257 of each visible line the sprites whose rows cover that line are copied out of OAM,
+* in OAM order, into secondary OAM, and dots 257-320 fetch their pattern rows to draw on the next
+* line. That's why a sprite shows up one line below its OAM Y. Hardware only has 8 slots, so
+* any further sprites on the line drop out, which games hide with flicker; the limit can be
+* turned off at the cost of accuracy.
+* OAM attributes: bit 7 flips vertically, bit 6 horizontally, bit 5 puts the sprite behind the
+* background, and bits 0-1 select sprite palette 4-7. ctrl bit 5 makes sprites 8x16, which take
+* their pattern table from bit 0 of the tile number instead of ctrl bit 3.
+* Status flags: sprite 0 hit (bit 6) is set on the dot where an opaque pixel of sprite 0 meets an
+* opaque background pixel, except at x 255 or inside a clipped left column. Sprite overflow
+* (bit 5) comes from hardware that keeps looking through OAM after the eighth sprite, but
+* bumps the byte offset along with the sprite number, checking tile, attribute and X bytes as Y
+* coordinates. Both flags stay set until the pre-render line.
+*/
+use std::sync::{Arc, Mutex};
+
+use super::{Sprite, PPU};
+use crate::system::System;
+
+pub const SECONDARY_OAM_SLOTS: usize = 8;
+
+#[derive(Clone, Copy)]
+pub struct LineSprite {
+    sprite: Sprite,
+    sprite_zero: bool,
+    // Row within the sprite, already flipped
+    row: u16,
+    // Already flipped, bit 7 is the leftmost pixel
+    pattern_low: u8,
+    pattern_high: u8,
+}
+
+pub struct SpritePixel {
+    pub palette: u8,
+    pub pixel: u8,
+    pub behind_background: bool,
+    pub sprite_zero: bool,
+}
+
+impl PPU {
+    fn sprite_height(&self) -> u16 {
+        if self.ctrl & 0x20 != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    pub fn update_sprites(&mut self, system: &mut Arc<Mutex<System>>, dot: u16, pre_render: bool) {
+        if dot == 257 {
+            if pre_render {
+                // Nothing is drawn on line 0
+                self.sprites.clear();
+            } else {
+                self.evaluate_sprites(system);
+            }
+        }
+
+        if (257..=320).contains(&dot) {
+            let slot = (dot - 257) as usize / 8;
+            match (dot - 257) % 8 {
+                4 => self.fetch_sprite_pattern(system, slot, false),
+                6 => self.fetch_sprite_pattern(system, slot, true),
+                _ => {}
+            }
+        }
+
+        // Sprites past the eighth when the limit is off
+        if dot == 320 {
+            for slot in SECONDARY_OAM_SLOTS..self.sprites.len() {
+                self.fetch_sprite_pattern(system, slot, false);
+                self.fetch_sprite_pattern(system, slot, true);
+            }
+        }
+    }
+
+    fn evaluate_sprites(&mut self, system: &mut Arc<Mutex<System>>) {
+        let height = self.sprite_height();
+        let scanline = self.scanline;
+        let in_range = |y: u8| scanline.wrapping_sub(y as u16) < height;
+        self.sprites.clear();
+
+        let mut sprite_num = 0;
+        while sprite_num < 64 && self.sprites.len() < SECONDARY_OAM_SLOTS {
+            let sprite = self.fetch(system, sprite_num);
+            if in_range(sprite.y) {
+                self.push_line_sprite(sprite, sprite_num == 0, height);
+            }
+            sprite_num += 1;
+        }
+
+        // Overflow search, offset drifting through each sprite's bytes
+        let oam = system.lock().unwrap().oam.clone();
+        let oam = oam.lock().unwrap();
+        let mut offset = 0;
+        for overflow_num in sprite_num as usize..64 {
+            if in_range(oam[overflow_num * 4 + offset]) {
+                self.status |= 0x20;
+                break;
+            }
+            offset = (offset + 1) & 0x03;
+        }
+        drop(oam);
+
+        if !self.sprite_limit {
+            for sprite_num in sprite_num..64 {
+                let sprite = self.fetch(system, sprite_num);
+                if in_range(sprite.y) {
+                    self.push_line_sprite(sprite, false, height);
+                }
+            }
+        }
+    }
+
+    fn push_line_sprite(&mut self, sprite: Sprite, sprite_zero: bool, height: u16) {
+        let row = self.scanline.wrapping_sub(sprite.y as u16);
+        self.sprites.push(LineSprite {
+            sprite,
+            sprite_zero,
+            row: if sprite.attr & 0x80 != 0 {
+                height - 1 - row
+            } else {
+                row
+            },
+            pattern_low: 0,
+            pattern_high: 0,
+        });
+    }
+
+    fn fetch_sprite_pattern(&mut self, system: &mut Arc<Mutex<System>>, slot: usize, high: bool) {
+        let plane = if high { 8 } else { 0 };
+        let Some(line_sprite) = self.sprites.get(slot).copied() else {
+            // Empty slots still fetch tile $FF, which mappers watching the bus can see
+            let address = self.sprite_pattern_address(0xFF, 0);
+            self.read_bus(system, address + plane);
+            return;
+        };
+
+        let address = self.sprite_pattern_address(line_sprite.sprite.tile, line_sprite.row);
+        let mut pattern = self.read_bus(system, address + plane);
+        if line_sprite.sprite.attr & 0x40 != 0 {
+            pattern = pattern.reverse_bits();
+        }
+
+        let line_sprite = &mut self.sprites[slot];
+        if high {
+            line_sprite.pattern_high = pattern;
+        } else {
+            line_sprite.pattern_low = pattern;
+        }
+    }
+
+    fn sprite_pattern_address(&self, tile: u8, row: u16) -> u16 {
+        if self.ctrl & 0x20 != 0 {
+            // The bottom half is the next tile
+            let table = (tile as u16 & 0x01) << 12;
+            let tile = (tile & 0xFE) as u16 + (row >> 3);
+            return table | tile << 4 | (row & 0x07);
+        }
+        let table = (self.ctrl as u16 & 0x08) << 9;
+        table | (tile as u16) << 4 | row
+    }
+
+    // The first opaque sprite pixel in OAM order. A sprite behind the background still wins over
+    // later sprites, so it can hide them even where the background is transparent.
+    pub fn sprite_pixel(&self, x: usize) -> Option<SpritePixel> {
+        // Bit 2 clear hides the leftmost 8 pixels
+        if self.mask & 0x10 == 0 || (x < 8 && self.mask & 0x04 == 0) {
+            return None;
+        }
+
+        self.sprites.iter().find_map(|line_sprite| {
+            let column = x.wrapping_sub(line_sprite.sprite.x as usize);
+            if column >= 8 {
+                return None;
+            }
+            let bit = 0x80 >> column;
+            let pixel = ((line_sprite.pattern_high & bit != 0) as u8) << 1
+                | (line_sprite.pattern_low & bit != 0) as u8;
+            (pixel != 0).then(|| SpritePixel {
+                palette: 4 + (line_sprite.sprite.attr & 0x03),
+                pixel,
+                behind_background: line_sprite.sprite.attr & 0x20 != 0,
+                sprite_zero: line_sprite.sprite_zero,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{run_dots, set_addr, system};
+    use super::*;
+
+    // Tile 0 is solid colour 1, tile 1 has its left half in colour 1
+    fn setup() -> (PPU, Arc<Mutex<System>>) {
+        let mut system = system();
+        let mut ppu = PPU::new();
+        set_addr(&mut ppu, &mut system, 0x0000);
+        for _ in 0..8 {
+            ppu.write_register(&mut system, 0x2007, 0xFF);
+        }
+        set_addr(&mut ppu, &mut system, 0x0010);
+        for _ in 0..8 {
+            ppu.write_register(&mut system, 0x2007, 0xF0);
+        }
+        for (entry, colour) in [(0x00, 0x0F), (0x01, 0x01), (0x11, 0x21), (0x15, 0x16)] {
+            ppu.palette[entry] = colour;
+        }
+        // Everything off screen
+        system.lock().unwrap().oam.lock().unwrap().fill(0xFF);
+        (ppu, system)
+    }
+
+    fn set_sprite(system: &Arc<Mutex<System>>, sprite_num: usize, bytes: [u8; 4]) {
+        let oam = system.lock().unwrap().oam.clone();
+        oam.lock().unwrap()[sprite_num * 4..sprite_num * 4 + 4].copy_from_slice(&bytes);
+    }
+
+    // Pre-render line, line 0 (evaluating sprites at Y 0) and line 1
+    fn render_line_1(ppu: &mut PPU, system: &mut Arc<Mutex<System>>) -> Vec<u16> {
+        ppu.scanline = 261;
+        run_dots(ppu, system, 341 * 3);
+        ppu.framebuffer.row(1).to_vec()
+    }
+
+    #[test]
+    fn limits_sprites_per_line() {
+        let (mut ppu, mut system) = setup();
+        for sprite_num in 0..10 {
+            set_sprite(&system, sprite_num, [20, 1, 0, sprite_num as u8 * 8]);
+        }
+        ppu.scanline = 27;
+        ppu.evaluate_sprites(&mut system);
+        assert_eq!(ppu.sprites.len(), 8);
+        ppu.scanline = 28;
+        ppu.evaluate_sprites(&mut system);
+        assert_eq!(ppu.sprites.len(), 0);
+
+        ppu.sprite_limit = false;
+        ppu.scanline = 20;
+        ppu.evaluate_sprites(&mut system);
+        assert_eq!(ppu.sprites.len(), 10);
+    }
+
+    #[test]
+    fn draws_flipped_sprites_with_clipping() {
+        let (mut ppu, mut system) = setup();
+        set_sprite(&system, 0, [0, 1, 0x41, 16]);
+        set_sprite(&system, 1, [0, 1, 0x00, 6]);
+
+        ppu.mask = 0x14;
+        let line = render_line_1(&mut ppu, &mut system);
+        assert_eq!(
+            &line[4..12],
+            &[0x0F, 0x0F, 0x21, 0x21, 0x21, 0x21, 0x0F, 0x0F]
+        );
+        assert_eq!(
+            &line[16..24],
+            &[0x0F, 0x0F, 0x0F, 0x0F, 0x16, 0x16, 0x16, 0x16]
+        );
+
+        ppu.mask = 0x10;
+        let line = render_line_1(&mut ppu, &mut system);
+        assert_eq!(&line[4..10], &[0x0F, 0x0F, 0x0F, 0x0F, 0x21, 0x21]);
+    }
+
+    #[test]
+    fn background_priority() {
+        let (mut ppu, mut system) = setup();
+        set_sprite(&system, 0, [0, 1, 0x20, 16]);
+        set_sprite(&system, 1, [0, 1, 0x00, 32]);
+        // A background priority sprite still covers the sprites after it
+        set_sprite(&system, 2, [0, 1, 0x20, 48]);
+        set_sprite(&system, 3, [0, 1, 0x01, 48]);
+
+        ppu.mask = 0x1E;
+        let line = render_line_1(&mut ppu, &mut system);
+        assert_eq!(line[16], 0x01);
+        assert_eq!(line[32], 0x21);
+        assert_eq!(line[48], 0x01);
+    }
+
+    #[test]
+    fn pattern_addresses() {
+        let mut ppu = PPU::new();
+        ppu.ctrl = 0x08;
+        assert_eq!(ppu.sprite_pattern_address(0x05, 3), 0x1053);
+
+        // 8x16 sprites ignore ctrl bit 3
+        ppu.ctrl = 0x20;
+        assert_eq!(ppu.sprite_pattern_address(0x03, 0), 0x1020);
+        assert_eq!(ppu.sprite_pattern_address(0x03, 15), 0x1037);
+        assert_eq!(ppu.sprite_pattern_address(0x04, 9), 0x0051);
+    }
+
+    #[test]
+    fn sprite_zero_hit_timing() {
+        let (mut ppu, mut system) = setup();
+        set_sprite(&system, 0, [0, 1, 0x20, 20]);
+        ppu.mask = 0x1E;
+        ppu.scanline = 261;
+
+        // Line 1 up to x 19, then x 20
+        run_dots(&mut ppu, &mut system, 341 * 2 + 21);
+        assert_eq!(ppu.status & 0x40, 0);
+        run_dots(&mut ppu, &mut system, 1);
+        assert_eq!(ppu.status & 0x40, 0x40);
+
+        // Cleared on the pre-render line
+        ppu.scanline = 261;
+        ppu.dot = 0;
+        run_dots(&mut ppu, &mut system, 2);
+        assert_eq!(ppu.status & 0x40, 0);
+    }
+
+    #[test]
+    fn no_sprite_zero_hit_at_x_255_or_clipped() {
+        let (mut ppu, mut system) = setup();
+        // Tile 2 is only opaque in its rightmost column
+        set_addr(&mut ppu, &mut system, 0x0020);
+        for _ in 0..8 {
+            ppu.write_register(&mut system, 0x2007, 0x01);
+        }
+        set_sprite(&system, 0, [0, 2, 0, 248]);
+        ppu.mask = 0x1E;
+        render_line_1(&mut ppu, &mut system);
+        assert_eq!(ppu.status & 0x40, 0);
+
+        set_sprite(&system, 0, [0, 1, 0, 0]);
+        ppu.mask = 0x18;
+        render_line_1(&mut ppu, &mut system);
+        assert_eq!(ppu.status & 0x40, 0);
+        ppu.mask = 0x1E;
+        render_line_1(&mut ppu, &mut system);
+        assert_eq!(ppu.status & 0x40, 0x40);
+    }
+
+    #[test]
+    fn sprite_overflow() {
+        let (mut ppu, mut system) = setup();
+        for sprite_num in 0..9 {
+            set_sprite(&system, sprite_num, [10, 0, 0, 0]);
+        }
+        ppu.scanline = 10;
+        ppu.evaluate_sprites(&mut system);
+        assert_eq!(ppu.status & 0x20, 0x20);
+
+        // Still reported with the limit off
+        ppu.status = 0;
+        ppu.sprite_limit = false;
+        ppu.evaluate_sprites(&mut system);
+        assert_eq!(ppu.sprites.len(), 9);
+        assert_eq!(ppu.status & 0x20, 0x20);
+    }
+
+    #[test]
+    fn sprite_overflow_scan_bug() {
+        let (mut ppu, mut system) = setup();
+        for sprite_num in 0..8 {
+            set_sprite(&system, sprite_num, [10, 0, 0, 0]);
+        }
+        // Sprite 9's tile number is read as its Y
+        set_sprite(&system, 9, [0xFF, 10, 0xFF, 0xFF]);
+        ppu.scanline = 10;
+        ppu.evaluate_sprites(&mut system);
+        assert_eq!(ppu.status & 0x20, 0x20);
+
+        // A ninth sprite that really is on the line gets missed when the scan has drifted
+        ppu.status = 0;
+        set_sprite(&system, 9, [0xFF; 4]);
+        set_sprite(&system, 10, [10, 0xFF, 0xFF, 0xFF]);
+        ppu.evaluate_sprites(&mut system);
+        assert_eq!(ppu.status & 0x20, 0);
+    }
+}
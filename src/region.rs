@@ -0,0 +1,133 @@
+/**
+* Console regions and their timing
+*
+*           CPU clock      PPU dots per CPU cycle   Lines   Vblank from   Frame rate
+*   NTSC    1.789773 MHz   3                        262     241           60.10 Hz
+*   PAL     1.662607 MHz   3.2                      312     241           50.01 Hz
+*   Dendy   1.773448 MHz   3                        312     291           50.01 Hz
+* Dendy (a Famiclone sold in Russia) runs PAL's frame at NTSC's CPU/PPU ratio and keeps a 240
+* line picture by putting 51 idle lines after it before vblank. Only NTSC skips a dot on odd frames.
+*/
+use crate::cartridge::{TIMING_DENDY, TIMING_NTSC, TIMING_PAL};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    // NES 2.0 CPU/PPU timing, multi-region games run as NTSC
+    pub fn from_timing(timing: u8) -> Region {
+        match timing {
+            TIMING_PAL => Region::Pal,
+            TIMING_DENDY => Region::Dendy,
+            _ => Region::Ntsc,
+        }
+    }
+
+    pub fn timing(&self) -> u8 {
+        match self {
+            Region::Ntsc => TIMING_NTSC,
+            Region::Pal => TIMING_PAL,
+            Region::Dendy => TIMING_DENDY,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Region> {
+        match name.to_ascii_lowercase().as_str() {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            "dendy" => Some(Region::Dendy),
+            _ => None,
+        }
+    }
+
+    pub fn cpu_hz(&self) -> f64 {
+        match self {
+            Region::Ntsc => 1_789_773.0,
+            Region::Pal => 1_662_607.0,
+            Region::Dendy => 1_773_448.0,
+        }
+    }
+
+    pub fn ppu_dots_per_cpu_cycle(&self) -> f64 {
+        match self {
+            Region::Pal => 3.2,
+            _ => 3.0,
+        }
+    }
+
+    pub fn ppu_hz(&self) -> f64 {
+        self.cpu_hz() * self.ppu_dots_per_cpu_cycle()
+    }
+
+    pub fn scanlines(&self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            _ => 312,
+        }
+    }
+
+    pub fn pre_render_line(&self) -> u16 {
+        self.scanlines() - 1
+    }
+
+    pub fn vblank_line(&self) -> u16 {
+        match self {
+            Region::Dendy => 291,
+            _ => 241,
+        }
+    }
+
+    pub fn skips_odd_frame_dot(&self) -> bool {
+        *self == Region::Ntsc
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        // 341 dots a line, less half a dot for NTSC's odd frame skip
+        let dots =
+            self.scanlines() as f64 * 341.0 - if self.skips_odd_frame_dot() { 0.5 } else { 0.0 };
+        self.ppu_hz() / dots
+    }
+
+    // Nanoseconds per CPU cycle, PPU dot and frame
+    pub fn cpu_period(&self) -> u128 {
+        (1e9 / self.cpu_hz()).round() as u128
+    }
+
+    pub fn ppu_period(&self) -> u128 {
+        (1e9 / self.ppu_hz()).round() as u128
+    }
+
+    pub fn frame_period(&self) -> u128 {
+        (1e9 / self.frame_rate()).round() as u128
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::{TIMING_MULTI_REGION, TIMING_NTSC};
+
+    #[test]
+    fn from_header_timing() {
+        assert_eq!(Region::from_timing(TIMING_NTSC), Region::Ntsc);
+        assert_eq!(Region::from_timing(TIMING_PAL), Region::Pal);
+        assert_eq!(Region::from_timing(TIMING_MULTI_REGION), Region::Ntsc);
+        assert_eq!(Region::from_timing(TIMING_DENDY), Region::Dendy);
+        assert_eq!(Region::from_name("PAL"), Some(Region::Pal));
+        assert_eq!(Region::from_name("secam"), None);
+    }
+
+    #[test]
+    fn frame_rates() {
+        assert!((Region::Ntsc.frame_rate() - 60.0988).abs() < 0.001);
+        assert!((Region::Pal.frame_rate() - 50.0070).abs() < 0.001);
+        assert!((Region::Dendy.frame_rate() - 50.0070).abs() < 0.1);
+        assert_eq!(Region::Ntsc.cpu_period(), 559);
+        assert_eq!(Region::Ntsc.ppu_period(), 186);
+        assert_eq!(Region::Pal.ppu_period(), 188);
+    }
+}
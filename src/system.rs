@@ -1,4 +1,5 @@
-use crate::cartridge::Cartridge;
+use crate::apu::APU;
+use crate::cartridge::{Cartridge, Nametables};
 use crate::cpu::CPU;
 use crate::ppu::PPU;
 use std::sync::{Arc, Mutex};
@@ -6,22 +7,23 @@ use std::sync::{Arc, Mutex};
 pub struct System {
     pub cpu: Arc<Mutex<CPU>>,
     pub ppu: Arc<Mutex<PPU>>,
-    // pub apu: Arc<Mutex<APU>>,
+    pub apu: Arc<Mutex<APU>>,
     pub rom: Cartridge,
     pub ram: Arc<Mutex<Vec<u8>>>,
-    pub vram: Arc<Mutex<Vec<u8>>>,
+    pub vram: Arc<Mutex<Nametables>>,
     pub oam: Arc<Mutex<Vec<u8>>>,
 }
 
 impl System {
     pub fn new(rom: Cartridge) -> System {
+        let vram = Nametables::new(&rom.header);
         System {
             cpu: Arc::new(Mutex::new(CPU::new())),
             ppu: Arc::new(Mutex::new(PPU::new())),
-            // apu: Arc::new(Mutex::new(APU::new())),
+            apu: Arc::new(Mutex::new(APU::new())),
             rom,
             ram: Arc::new(Mutex::new(vec![0; 0x800])),
-            vram: Arc::new(Mutex::new(vec![0; 0x800])),
+            vram: Arc::new(Mutex::new(vram)),
             oam: Arc::new(Mutex::new(vec![0; 0x100])),
         }
     }